@@ -0,0 +1,11 @@
+-- Last event_log sequence each projector has applied. The projectors that already
+-- exist have seen every logged event.
+
+CREATE TABLE projector_checkpoints (
+    projector text PRIMARY KEY,
+    sequence bigint NOT NULL
+);
+
+INSERT INTO projector_checkpoints (projector, sequence)
+SELECT p.projector, (SELECT COALESCE(MAX(sequence), 0) FROM event_log)
+FROM (VALUES ('total_ordered'), ('total_authorized'), ('total_collected')) AS p(projector);
@@ -0,0 +1,41 @@
+-- Amounts are kept exactly, as integer minor units of the row's currency, so that
+-- sums no longer drift. Everything stored so far was in euros.
+
+ALTER TABLE total_ordered
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE total_authorized
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE total_collected
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE bank_transactions
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ALTER COLUMN ordered_amount DROP DEFAULT,
+    ALTER COLUMN ordered_amount TYPE bigint USING round(ordered_amount * 100)::bigint,
+    ALTER COLUMN ordered_amount SET DEFAULT 0,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE payment_authorizations
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE payment_collections
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE product_orders
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ALTER COLUMN collected_amount DROP DEFAULT,
+    ALTER COLUMN collected_amount TYPE bigint USING round(collected_amount * 100)::bigint,
+    ALTER COLUMN collected_amount SET DEFAULT 0,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
+
+ALTER TABLE relations
+    ALTER COLUMN amount DROP DEFAULT,
+    ALTER COLUMN amount TYPE bigint USING round(amount * 100)::bigint,
+    ADD COLUMN currency text NOT NULL DEFAULT 'EUR';
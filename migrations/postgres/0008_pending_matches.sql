@@ -0,0 +1,10 @@
+-- Events that referenced an order, payment or bank transaction not stored yet.
+-- Matching is retried for `key_column`/`key_id` once the awaited one arrives.
+
+CREATE TABLE pending_matches (
+    awaited_column text NOT NULL,
+    awaited_id text NOT NULL,
+    key_column text NOT NULL,
+    key_id text NOT NULL,
+    PRIMARY KEY (awaited_column, awaited_id, key_column, key_id)
+);
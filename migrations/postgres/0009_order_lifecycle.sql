@@ -0,0 +1,8 @@
+-- Cancellations and interruptions refer to the order they adjust, whose expected
+-- amount is kept next to the ordered one.
+
+ALTER TABLE product_orders ADD COLUMN original_order_id text;
+ALTER TABLE product_orders ADD COLUMN expected_amount bigint;
+UPDATE product_orders SET expected_amount = amount;
+
+CREATE INDEX product_orders_original_order_id_idx ON product_orders(original_order_id);
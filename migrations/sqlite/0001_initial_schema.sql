@@ -0,0 +1,63 @@
+-- IF NOT EXISTS lets databases created by the former reset_db adopt the migrations.
+
+CREATE TABLE IF NOT EXISTS total_ordered (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount double precision,
+    occurred_on text
+);
+
+CREATE TABLE IF NOT EXISTS total_authorized (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount double precision,
+    occurred_on text
+);
+
+CREATE TABLE IF NOT EXISTS total_collected (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount double precision,
+    occurred_on text
+);
+
+CREATE TABLE IF NOT EXISTS bank_transactions (
+    transaction_id text PRIMARY KEY,
+    amount double precision,
+    ordered_amount double precision default 0,
+    occurred_on text
+);
+
+CREATE TABLE IF NOT EXISTS payment_authorizations (
+    payment_id text,
+    order_id text,
+    amount double precision,
+    occurred_on text,
+    PRIMARY KEY (order_id, payment_id)
+);
+
+CREATE TABLE IF NOT EXISTS payment_collections (
+    payment_id text,
+    transaction_id text,
+    amount double precision,
+    occurred_on text,
+    PRIMARY KEY (transaction_id, payment_id)
+);
+
+CREATE TABLE IF NOT EXISTS product_orders (
+    order_id text PRIMARY KEY,
+    amount double precision,
+    collected_amount double precision default 0,
+    occurred_on text,
+    insurance_code text,
+    installment_type text,
+    event_type text
+);
+
+CREATE TABLE IF NOT EXISTS relations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    payment_id text default null,
+    order_id text default null,
+    transaction_id text default null
+);
+
+CREATE INDEX IF NOT EXISTS t_id_idx ON relations(transaction_id);
+CREATE INDEX IF NOT EXISTS p_id_idx ON relations(payment_id);
+CREATE INDEX IF NOT EXISTS o_id_idx ON relations(order_id);
@@ -0,0 +1,7 @@
+-- Ledger of the events already applied, so that redelivered ones can be skipped.
+
+CREATE TABLE processed_events (
+    event_id text PRIMARY KEY,
+    event_type text NOT NULL,
+    processed_on text NOT NULL
+);
@@ -0,0 +1,17 @@
+-- Every accepted event as it was received, the source the derived tables can be
+-- rebuilt from. Rows are only ever inserted.
+
+CREATE TABLE event_log (
+    sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id text NOT NULL UNIQUE,
+    event_type text NOT NULL,
+    order_id text,
+    payment_id text,
+    transaction_id text,
+    payload text NOT NULL,
+    received_at text NOT NULL
+);
+
+CREATE INDEX event_log_order_id_idx ON event_log(order_id);
+CREATE INDEX event_log_payment_id_idx ON event_log(payment_id);
+CREATE INDEX event_log_transaction_id_idx ON event_log(transaction_id);
@@ -0,0 +1,4 @@
+-- Projectors catch up outside the event's transaction, so a failure is kept here
+-- instead of being returned to the ingesting client.
+
+ALTER TABLE projector_checkpoints ADD COLUMN last_error text;
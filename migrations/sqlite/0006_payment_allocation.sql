@@ -0,0 +1,39 @@
+-- Orders and bank transactions are matched through the payments they share, so that
+-- an order can be paid by many payments and a transaction settle many of them. A
+-- relation now links an order, a payment authorized for it and a bank transaction
+-- that collected the payment, with the part of the collection allocated to the
+-- order: collections of a payment authorized for several orders are split in
+-- proportion to the authorized amounts. The matched amounts are recomputed from
+-- them.
+
+DELETE FROM relations;
+ALTER TABLE relations ADD COLUMN amount double precision NOT NULL DEFAULT 0;
+
+INSERT INTO relations (order_id, payment_id, transaction_id, amount)
+SELECT pa.order_id, pa.payment_id, pc.transaction_id,
+    CASE WHEN authorized.amount = 0 THEN 0
+    ELSE pc.amount * pa.amount / authorized.amount END
+FROM payment_authorizations pa
+JOIN payment_collections pc ON pc.payment_id = pa.payment_id
+JOIN (
+    SELECT payment_id, SUM(amount) AS amount
+    FROM payment_authorizations
+    GROUP BY payment_id
+) authorized ON authorized.payment_id = pa.payment_id;
+
+CREATE INDEX payment_authorizations_payment_id_idx ON payment_authorizations(payment_id);
+CREATE INDEX payment_collections_payment_id_idx ON payment_collections(payment_id);
+
+UPDATE product_orders SET collected_amount = (
+    SELECT COALESCE(SUM(r.amount), 0)
+    FROM relations r
+    JOIN bank_transactions bt ON bt.transaction_id = r.transaction_id
+    WHERE r.order_id = product_orders.order_id
+);
+
+UPDATE bank_transactions SET ordered_amount = (
+    SELECT COALESCE(SUM(r.amount), 0)
+    FROM relations r
+    JOIN product_orders po ON po.order_id = r.order_id
+    WHERE r.transaction_id = bank_transactions.transaction_id
+);
@@ -0,0 +1,119 @@
+-- Amounts are kept exactly, as integer minor units of the row's currency, so that
+-- sums no longer drift. Everything stored so far was in euros. SQLite cannot change
+-- a column's type, so the tables are copied into new ones.
+
+CREATE TABLE total_ordered_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount integer,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text
+);
+INSERT INTO total_ordered_new (id, amount, occurred_on)
+SELECT id, CAST(round(amount * 100) AS integer), occurred_on FROM total_ordered;
+DROP TABLE total_ordered;
+ALTER TABLE total_ordered_new RENAME TO total_ordered;
+
+CREATE TABLE total_authorized_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount integer,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text
+);
+INSERT INTO total_authorized_new (id, amount, occurred_on)
+SELECT id, CAST(round(amount * 100) AS integer), occurred_on FROM total_authorized;
+DROP TABLE total_authorized;
+ALTER TABLE total_authorized_new RENAME TO total_authorized;
+
+CREATE TABLE total_collected_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    amount integer,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text
+);
+INSERT INTO total_collected_new (id, amount, occurred_on)
+SELECT id, CAST(round(amount * 100) AS integer), occurred_on FROM total_collected;
+DROP TABLE total_collected;
+ALTER TABLE total_collected_new RENAME TO total_collected;
+
+CREATE TABLE bank_transactions_new (
+    transaction_id text PRIMARY KEY,
+    amount integer,
+    ordered_amount integer default 0,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text
+);
+INSERT INTO bank_transactions_new (transaction_id, amount, ordered_amount, occurred_on)
+SELECT transaction_id,
+    CAST(round(amount * 100) AS integer),
+    CAST(round(ordered_amount * 100) AS integer),
+    occurred_on
+FROM bank_transactions;
+DROP TABLE bank_transactions;
+ALTER TABLE bank_transactions_new RENAME TO bank_transactions;
+
+CREATE TABLE payment_authorizations_new (
+    payment_id text,
+    order_id text,
+    amount integer,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text,
+    PRIMARY KEY (order_id, payment_id)
+);
+INSERT INTO payment_authorizations_new (payment_id, order_id, amount, occurred_on)
+SELECT payment_id, order_id, CAST(round(amount * 100) AS integer), occurred_on
+FROM payment_authorizations;
+DROP TABLE payment_authorizations;
+ALTER TABLE payment_authorizations_new RENAME TO payment_authorizations;
+CREATE INDEX payment_authorizations_payment_id_idx ON payment_authorizations(payment_id);
+
+CREATE TABLE payment_collections_new (
+    payment_id text,
+    transaction_id text,
+    amount integer,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text,
+    PRIMARY KEY (transaction_id, payment_id)
+);
+INSERT INTO payment_collections_new (payment_id, transaction_id, amount, occurred_on)
+SELECT payment_id, transaction_id, CAST(round(amount * 100) AS integer), occurred_on
+FROM payment_collections;
+DROP TABLE payment_collections;
+ALTER TABLE payment_collections_new RENAME TO payment_collections;
+CREATE INDEX payment_collections_payment_id_idx ON payment_collections(payment_id);
+
+CREATE TABLE product_orders_new (
+    order_id text PRIMARY KEY,
+    amount integer,
+    collected_amount integer default 0,
+    currency text NOT NULL DEFAULT 'EUR',
+    occurred_on text,
+    insurance_code text,
+    installment_type text,
+    event_type text
+);
+INSERT INTO product_orders_new
+    (order_id, amount, collected_amount, occurred_on, insurance_code, installment_type, event_type)
+SELECT order_id,
+    CAST(round(amount * 100) AS integer),
+    CAST(round(collected_amount * 100) AS integer),
+    occurred_on, insurance_code, installment_type, event_type
+FROM product_orders;
+DROP TABLE product_orders;
+ALTER TABLE product_orders_new RENAME TO product_orders;
+
+CREATE TABLE relations_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    payment_id text default null,
+    order_id text default null,
+    transaction_id text default null,
+    amount integer NOT NULL,
+    currency text NOT NULL DEFAULT 'EUR'
+);
+INSERT INTO relations_new (id, payment_id, order_id, transaction_id, amount)
+SELECT id, payment_id, order_id, transaction_id, CAST(round(amount * 100) AS integer)
+FROM relations;
+DROP TABLE relations;
+ALTER TABLE relations_new RENAME TO relations;
+CREATE INDEX t_id_idx ON relations(transaction_id);
+CREATE INDEX p_id_idx ON relations(payment_id);
+CREATE INDEX o_id_idx ON relations(order_id);
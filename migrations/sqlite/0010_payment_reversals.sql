@@ -0,0 +1,19 @@
+-- Refunds and chargebacks give money of a collected payment back through a bank
+-- transaction. They are linked to the orders of the payment by relations rows of
+-- their own kind, with negative amounts, and are netted out of the collected
+-- amounts.
+
+CREATE TABLE payment_reversals (
+    kind text NOT NULL,
+    payment_id text NOT NULL,
+    transaction_id text NOT NULL,
+    amount integer NOT NULL,
+    currency text NOT NULL,
+    occurred_on text NOT NULL,
+    PRIMARY KEY (kind, transaction_id, payment_id)
+);
+
+CREATE INDEX payment_reversals_payment_id_idx ON payment_reversals(payment_id);
+
+-- the existing rows all allocate collections
+ALTER TABLE relations ADD COLUMN kind text NOT NULL DEFAULT 'collection';
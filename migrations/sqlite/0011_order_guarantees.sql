@@ -0,0 +1,10 @@
+-- The coverages an order is made of, their prices adding up to the order amount.
+-- Orders stored before this migration have none.
+
+CREATE TABLE order_guarantees (
+    order_id text NOT NULL,
+    guarantee_type text NOT NULL,
+    price integer NOT NULL,
+    currency text NOT NULL,
+    PRIMARY KEY (order_id, guarantee_type)
+);
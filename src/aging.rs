@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::events::EventType;
+use crate::money::{Currency, Money};
+use crate::reconciliation_engine::{FactsIndex, ReconciliationReport, ReconciliationStatus};
+use crate::storage::{BusinessKey, Facts, REFUND};
+
+/// How long ago an unreconciled item occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AgeBucket {
+    /// less than a day
+    Day,
+    /// from a day to less than a week
+    Week,
+    /// from a week to less than 30 days
+    Month,
+    /// 30 days or more
+    Older,
+}
+
+impl AgeBucket {
+    pub const ALL: [AgeBucket; 4] = [
+        AgeBucket::Day,
+        AgeBucket::Week,
+        AgeBucket::Month,
+        AgeBucket::Older,
+    ];
+
+    /// The bucket of an item that occurred `occurred_on`, one from the future being
+    /// less than a day old.
+    pub fn of(occurred_on: DateTime<Utc>, as_of: DateTime<Utc>) -> Self {
+        let age = as_of - occurred_on;
+        if age < Duration::days(1) {
+            AgeBucket::Day
+        } else if age < Duration::days(7) {
+            AgeBucket::Week
+        } else if age < Duration::days(30) {
+            AgeBucket::Month
+        } else {
+            AgeBucket::Older
+        }
+    }
+}
+
+impl Display for AgeBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgeBucket::Day => f.write_str("0-1 days"),
+            AgeBucket::Week => f.write_str("1-7 days"),
+            AgeBucket::Month => f.write_str("7-30 days"),
+            AgeBucket::Older => f.write_str("30+ days"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AgingKind {
+    Order,
+    Authorization,
+    Collection,
+    Refund,
+    Chargeback,
+    BankTransaction,
+}
+
+impl Display for AgingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgingKind::Order => f.write_str("order"),
+            AgingKind::Authorization => f.write_str("authorization"),
+            AgingKind::Collection => f.write_str("collection"),
+            AgingKind::Refund => f.write_str("refund"),
+            AgingKind::Chargeback => f.write_str("chargeback"),
+            AgingKind::BankTransaction => f.write_str("bank_transaction"),
+        }
+    }
+}
+
+/// An unreconciled order, authorization, collection, refund, chargeback or bank
+/// transaction, with its own amount.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgingItem {
+    pub kind: AgingKind,
+    /// the order, payment or transaction ID, `payment_id/transaction_id` for a
+    /// collection, refund or chargeback
+    pub id: String,
+    pub status: ReconciliationStatus,
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+/// Keeps the items related to an order of the given event type and insurance code.
+/// Items not related to any stored order only pass an empty filter.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AgingFilter {
+    pub event_type: Option<EventType>,
+    pub insurance_code: Option<String>,
+}
+
+impl AgingFilter {
+    fn is_empty(&self) -> bool {
+        self.event_type.is_none() && self.insurance_code.is_none()
+    }
+}
+
+/// The items of one age bucket, by kind and ID, and their amounts added up by
+/// currency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgingBucket {
+    pub bucket: AgeBucket,
+    pub items: Vec<AgingItem>,
+    pub totals: Vec<Money>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgingReport {
+    pub as_of: DateTime<Utc>,
+    /// every bucket, from the youngest
+    pub buckets: Vec<AgingBucket>,
+}
+
+impl AgingReport {
+    /// Ages what the reconciliation report did not reconcile. Payments are listed by
+    /// their authorizations; collections, refunds and chargebacks only when their
+    /// payment is not authorized for a stored order or their bank transaction has not
+    /// arrived. Fails when the totals of a bucket overflow.
+    pub fn new(
+        facts: &Facts,
+        report: &ReconciliationReport,
+        as_of: DateTime<Utc>,
+        filter: &AgingFilter,
+    ) -> Result<Self, String> {
+        let unreconciled =
+            |key: BusinessKey, id: &str| report.get(key, id).filter(|r| !r.status.is_reconciled());
+        let matching_orders = facts
+            .orders
+            .iter()
+            .filter(|o| {
+                filter
+                    .event_type
+                    .as_ref()
+                    .is_none_or(|t| o.event_type == t.to_string())
+                    && filter
+                        .insurance_code
+                        .as_ref()
+                        .is_none_or(|code| &o.insurance_code == code)
+            })
+            .map(|o| o.order_id.as_str())
+            .collect::<HashSet<_>>();
+        let index = FactsIndex::new(facts);
+        // the orders the payment is authorized for
+        let orders_of_payment = |payment_id: &str| {
+            index
+                .authorizations(BusinessKey::Payment, payment_id)
+                .iter()
+                .map(|a| a.order_id.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        // each item with the orders it relates to
+        let mut items = vec![];
+        for o in &facts.orders {
+            if let Some(r) = unreconciled(BusinessKey::Order, &o.order_id) {
+                items.push((
+                    AgingItem {
+                        kind: AgingKind::Order,
+                        id: o.order_id.clone(),
+                        status: r.status,
+                        amount: r.expected,
+                        occurred_on: o.occurred_on,
+                    },
+                    vec![o.order_id.as_str()],
+                ));
+            }
+        }
+        for a in &facts.authorizations {
+            if let Some(r) = unreconciled(BusinessKey::Payment, &a.payment_id) {
+                items.push((
+                    AgingItem {
+                        kind: AgingKind::Authorization,
+                        id: a.payment_id.clone(),
+                        status: r.status,
+                        amount: a.amount,
+                        occurred_on: a.occurred_on,
+                    },
+                    vec![a.order_id.as_str()],
+                ));
+            }
+        }
+        let collections = facts.collections.iter().map(|c| {
+            (
+                AgingKind::Collection,
+                &c.payment_id,
+                &c.transaction_id,
+                c.amount,
+                c.occurred_on,
+            )
+        });
+        let reversals = facts.reversals.iter().map(|r| {
+            let kind = if r.kind == REFUND {
+                AgingKind::Refund
+            } else {
+                AgingKind::Chargeback
+            };
+            (
+                kind,
+                &r.payment_id,
+                &r.transaction_id,
+                r.amount,
+                r.occurred_on,
+            )
+        });
+        for (kind, payment_id, transaction_id, amount, occurred_on) in collections.chain(reversals)
+        {
+            let orders = orders_of_payment(payment_id);
+            let status = if !orders.iter().any(|o| index.orders.contains_key(o)) {
+                ReconciliationStatus::Orphan
+            } else if !index.transactions.contains(transaction_id.as_str()) {
+                ReconciliationStatus::MissingBankTransaction
+            } else {
+                continue;
+            };
+            items.push((
+                AgingItem {
+                    kind,
+                    id: format!("{payment_id}/{transaction_id}"),
+                    status,
+                    amount,
+                    occurred_on,
+                },
+                orders,
+            ));
+        }
+        for t in &facts.transactions {
+            if let Some(r) = unreconciled(BusinessKey::Transaction, &t.transaction_id) {
+                let orders = index
+                    .settlements(BusinessKey::Transaction, &t.transaction_id)
+                    .iter()
+                    .flat_map(|s| orders_of_payment(s.payment_id))
+                    .collect();
+                items.push((
+                    AgingItem {
+                        kind: AgingKind::BankTransaction,
+                        id: t.transaction_id.clone(),
+                        status: r.status,
+                        amount: t.amount,
+                        occurred_on: t.occurred_on,
+                    },
+                    orders,
+                ));
+            }
+        }
+
+        let mut buckets = AgeBucket::ALL
+            .into_iter()
+            .map(|bucket| AgingBucket {
+                bucket,
+                items: vec![],
+                totals: vec![],
+            })
+            .collect::<Vec<_>>();
+        for (item, orders) in items {
+            if filter.is_empty() || orders.iter().any(|o| matching_orders.contains(o)) {
+                let bucket = AgeBucket::of(item.occurred_on, as_of);
+                buckets[bucket as usize].items.push(item);
+            }
+        }
+        for bucket in &mut buckets {
+            bucket
+                .items
+                .sort_by(|a, b| (a.kind, &a.id).cmp(&(b.kind, &b.id)));
+            let mut totals = BTreeMap::<Currency, Money>::new();
+            for item in &bucket.items {
+                let currency = item.amount.currency();
+                let total = totals.entry(currency).or_insert(Money::zero(currency));
+                *total = total.checked_add(item.amount).ok_or_else(|| {
+                    format!(
+                        "the {currency} total of the {} bucket overflows",
+                        bucket.bucket
+                    )
+                })?;
+            }
+            bucket.totals = totals.into_values().collect();
+        }
+        Ok(Self { as_of, buckets })
+    }
+
+    /// Every item, from the youngest bucket.
+    pub fn items(&self) -> impl Iterator<Item = &AgingItem> {
+        self.buckets.iter().flat_map(|bucket| &bucket.items)
+    }
+}
+
+impl Display for AgingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "as of {}", self.as_of.to_rfc3339())?;
+        for bucket in &self.buckets {
+            let totals = bucket
+                .totals
+                .iter()
+                .map(Money::to_string)
+                .collect::<Vec<_>>();
+            writeln!(
+                f,
+                "{}: {} item(s){}{}",
+                bucket.bucket,
+                bucket.items.len(),
+                if totals.is_empty() { "" } else { ", " },
+                totals.join(", ")
+            )?;
+            for item in &bucket.items {
+                writeln!(
+                    f,
+                    "  {:<16} {:<30} {:<24} {:>16} {}",
+                    item.kind.to_string(),
+                    item.id,
+                    item.status.to_string(),
+                    item.amount.to_string(),
+                    item.occurred_on.to_rfc3339()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
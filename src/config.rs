@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::events::EventType;
+
+/// Settings file read when `SPIKE_CONFIG` is not set.
+pub const DEFAULT_CONFIG_FILE: &str = "spike.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadError(String),
+    ParseError(String),
+    InvalidValue(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::ReadError(s) => f.write_fmt(format_args!("Read Error: {s}")),
+            ConfigError::ParseError(s) => f.write_fmt(format_args!("Parse Error: {s}")),
+            ConfigError::InvalidValue(s) => f.write_fmt(format_args!("Invalid Value: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub matching: MatchingConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Postgres,
+    Sqlite,
+    Memory,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: Backend,
+    /// libpq-style connection string for Postgres, file path (or `:memory:`) for SQLite
+    pub url: String,
+    pub pool_size: u32,
+    pub connect_timeout_secs: u64,
+    /// Postgres schema the tables live in, set as the connections' `search_path`
+    pub schema: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Postgres,
+            url: "host=localhost user=user password=password port=5432".to_owned(),
+            pool_size: 10,
+            connect_timeout_secs: 5,
+            schema: "public".to_owned(),
+        }
+    }
+}
+
+/// How far apart an expected and an actual amount may be and still match. Amounts
+/// are compared in minor units of their currency, a difference within either
+/// tolerance is a near-match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tolerance {
+    /// in major units, e.g. euros
+    pub absolute: f64,
+    /// percent of the expected amount
+    pub percentage: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchingConfig {
+    pub default: Tolerance,
+    pub event_type: HashMap<EventType, Tolerance>,
+    /// takes precedence over `event_type`
+    pub insurance_code: HashMap<String, Tolerance>,
+    /// rate table amounts in different currencies are converted with; without
+    /// one they never match. A relative path read from a settings file is relative
+    /// to the file's directory.
+    pub exchange_rates: Option<PathBuf>,
+}
+
+impl MatchingConfig {
+    /// The tolerance for an order: its insurance code's, else its event type's, else
+    /// the default one.
+    pub fn tolerance(&self, event_type: &str, insurance_code: &str) -> Tolerance {
+        self.insurance_code
+            .get(insurance_code)
+            .or_else(|| {
+                self.event_type
+                    .iter()
+                    .find(|(t, _)| t.to_string() == event_type)
+                    .map(|(_, tolerance)| tolerance)
+            })
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl Config {
+    /// Reads the file named by `SPIKE_CONFIG` (or `spike.toml` when present), then
+    /// applies the `SPIKE_DATABASE_*` environment variables on top.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("SPIKE_CONFIG") {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::ReadError(format!("{}: {e}", path.display())))?;
+        let mut config = Self::from_toml(&content)?;
+        if let (Some(dir), Some(rates)) = (path.parent(), &mut config.matching.exchange_rates) {
+            *rates = dir.join(&rates);
+        }
+        Ok(config)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        let config: Self =
+            toml::from_str(content).map_err(|e| ConfigError::ParseError(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides settings with the variables `var` resolves, so tests can pass a map
+    /// instead of touching the process environment.
+    pub fn apply_env<F>(&mut self, var: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let db = &mut self.database;
+        if let Some(backend) = var("SPIKE_DATABASE_BACKEND") {
+            db.backend = toml::Value::String(backend.clone())
+                .try_into()
+                .map_err(|_| invalid("SPIKE_DATABASE_BACKEND", &backend))?;
+        }
+        if let Some(url) = var("SPIKE_DATABASE_URL") {
+            db.url = url;
+        }
+        if let Some(size) = var("SPIKE_DATABASE_POOL_SIZE") {
+            db.pool_size = size
+                .parse()
+                .map_err(|_| invalid("SPIKE_DATABASE_POOL_SIZE", &size))?;
+        }
+        if let Some(timeout) = var("SPIKE_DATABASE_CONNECT_TIMEOUT_SECS") {
+            db.connect_timeout_secs = timeout
+                .parse()
+                .map_err(|_| invalid("SPIKE_DATABASE_CONNECT_TIMEOUT_SECS", &timeout))?;
+        }
+        if let Some(schema) = var("SPIKE_DATABASE_SCHEMA") {
+            db.schema = schema;
+        }
+        self.validate()
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let db = &self.database;
+        if db.pool_size == 0 {
+            return Err(invalid("database.pool_size", "0"));
+        }
+        let valid_schema = db
+            .schema
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && db
+                .schema
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_schema {
+            return Err(invalid("database.schema", &db.schema));
+        }
+
+        let matching = &self.matching;
+        let tolerances = std::iter::once(("default".to_owned(), &matching.default))
+            .chain(
+                matching
+                    .event_type
+                    .iter()
+                    .map(|(t, tolerance)| (format!("event_type.{t}"), tolerance)),
+            )
+            .chain(
+                matching
+                    .insurance_code
+                    .iter()
+                    .map(|(code, tolerance)| (format!("insurance_code.{code}"), tolerance)),
+            );
+        for (name, tolerance) in tolerances {
+            for (field, value) in [
+                ("absolute", tolerance.absolute),
+                ("percentage", tolerance.percentage),
+            ] {
+                if !(value.is_finite() && value >= 0.0) {
+                    return Err(invalid(
+                        &format!("matching.{name}.{field}"),
+                        &value.to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(name: &str, value: &str) -> ConfigError {
+    ConfigError::InvalidValue(format!("{name} = `{value}`"))
+}
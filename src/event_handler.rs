@@ -1,39 +1,77 @@
 use std::fmt::Display;
 
+use chrono::{DateTime, Utc};
+
+use crate::aging::{AgingFilter, AgingReport};
+use crate::config::{Config, MatchingConfig};
 use crate::events::*;
+use crate::export::{DateRange, OrderExport};
+use crate::installments::PaidInstallment;
+use crate::money::ExchangeRates;
 use crate::projectors::total_authorized_projector::TotalAuthorizedProjector;
 use crate::projectors::total_collected_projector::TotalCollectedProjector;
 use crate::projectors::total_ordered_projector::TotalOrderedProjector;
 use crate::projectors::Projector;
-use crate::reconciliation_engine::ReconciliationEngine;
+use crate::reconciliation_engine::{
+    GuaranteeCollection, OrderCollection, ReconciliationEngine, ReconciliationReport,
+};
+use crate::storage::{EventLogQuery, LoggedEvent, PendingMatch, StorageError, Store};
 
 #[derive(Debug)]
 pub enum EventError {
     UnknownEvent(String),
+    DecodingError(String),
     ProjectionError(String),
     ReconcilationEngineError(String),
+    StorageError(String),
 }
 
 impl Display for EventError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EventError::UnknownEvent(s) => f.write_fmt(format_args!("Unknown Error: {s}")),
+            EventError::DecodingError(s) => f.write_fmt(format_args!("Decoding Error: {s}")),
             EventError::ProjectionError(s) => f.write_fmt(format_args!("Projection Error: {s}")),
             EventError::ReconcilationEngineError(s) => {
                 f.write_fmt(format_args!("Reconciliation Engine Error: {s}"))
             }
+            EventError::StorageError(s) => f.write_fmt(format_args!("Storage Error: {s}")),
         }
     }
 }
 
+/// What [`EventHandler::accept`] did with an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acceptance {
+    Applied,
+    /// the event ID is already in the processed-events ledger, nothing was changed
+    Duplicate,
+}
+
+/// How many log sequences a projector catching up applies per committed session.
+pub const REPLAY_BATCH: i64 = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProjectorLag {
+    pub projector: &'static str,
+    /// last event_log sequence the projector applied
+    pub checkpoint: i64,
+    /// log sequences between the checkpoint and the head of the log
+    pub lag: i64,
+    /// why the projector stopped, cleared once it moves on
+    pub last_error: Option<String>,
+}
+
 pub struct EventHandler {
+    store: Box<dyn Store>,
     projectors: Vec<Box<dyn Projector>>,
     reconciliation_engine: ReconciliationEngine,
 }
 
 impl EventHandler {
-    pub fn new() -> Self {
+    pub fn new(store: Box<dyn Store>) -> Self {
         Self {
+            store,
             projectors: vec![
                 Box::new(TotalOrderedProjector::new()),
                 Box::new(TotalAuthorizedProjector::new()),
@@ -42,19 +80,300 @@ impl EventHandler {
             reconciliation_engine: ReconciliationEngine::new(),
         }
     }
-    pub fn accept(&self, event: Event) -> Result<(), EventError> {
-        self.projectors
+
+    pub fn from_config(config: &Config) -> Result<Self, EventError> {
+        let store = crate::storage::open(&config.database).map_err(storage_error)?;
+        let exchange_rates = match &config.matching.exchange_rates {
+            Some(path) => {
+                ExchangeRates::from_file(path).map_err(EventError::ReconcilationEngineError)?
+            }
+            None => ExchangeRates::default(),
+        };
+        Ok(Self::new(store)
+            .with_matching(config.matching.clone())
+            .with_exchange_rates(exchange_rates))
+    }
+
+    /// Sets the tolerances amounts are reconciled with.
+    pub fn with_matching(mut self, matching: MatchingConfig) -> Self {
+        self.reconciliation_engine = self.reconciliation_engine.with_matching(matching);
+        self
+    }
+
+    /// Converts amounts in different currencies at these rates, instead of refusing
+    /// to match them.
+    pub fn with_exchange_rates(mut self, exchange_rates: ExchangeRates) -> Self {
+        self.reconciliation_engine = self
+            .reconciliation_engine
+            .with_exchange_rates(exchange_rates);
+        self
+    }
+
+    /// Adds a projector, which catches up with the whole event log on the next
+    /// [`EventHandler::catch_up`].
+    pub fn with_projector(mut self, projector: Box<dyn Projector>) -> Self {
+        self.projectors.push(projector);
+        self
+    }
+
+    /// Logs and reconciles the event in a single storage session: when any step fails
+    /// nothing of the event is kept. Events whose ID has already been applied are
+    /// skipped, so redelivering them is harmless.
+    ///
+    /// The projections are left behind: the caller catches them up with
+    /// [`EventHandler::catch_up`], e.g. once a batch of events is accepted, so that a
+    /// failing projector never fails ingestion.
+    pub fn accept(&self, event: Event) -> Result<Acceptance, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+
+        let event_id = event.event_id().to_owned();
+        let event_type = event.event_type();
+        if session.is_processed(&event_id).map_err(storage_error)? {
+            return Ok(Acceptance::Duplicate);
+        }
+
+        let received_at = chrono::Utc::now();
+        session
+            .append_event(&event, &received_at)
+            .map_err(storage_error)?;
+
+        self.reconciliation_engine
+            .reconcile(session.as_mut(), event)
+            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))?;
+
+        session
+            .mark_processed(&event_id, event_type, &received_at)
+            .and_then(|_| session.commit())
+            .map_err(storage_error)?;
+        Ok(Acceptance::Applied)
+    }
+
+    /// The collection status of every order, by `order_id`.
+    pub fn order_collections(&self) -> Result<Vec<OrderCollection>, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        Ok(session
+            .order_balances()
+            .map_err(storage_error)?
+            .into_iter()
+            .map(OrderCollection::from)
+            .collect())
+    }
+
+    /// The collected amount of every order allocated across its guarantees, by
+    /// `order_id` and `guarantee_type`. Orders without guarantees are left out.
+    pub fn guarantee_collections(&self) -> Result<Vec<GuaranteeCollection>, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        let guarantees = session.guarantees().map_err(storage_error)?;
+        Ok(session
+            .order_balances()
+            .map_err(storage_error)?
             .iter()
-            .map(|p| {
-                p.project(event.clone())
-                    .map_err(|e| EventError::ProjectionError(e))
+            .flat_map(|balance| {
+                let of_order = guarantees
+                    .iter()
+                    .filter(|g| g.order_id == balance.order_id)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                GuaranteeCollection::allocate(balance, &of_order)
             })
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect())
+    }
 
+    /// The expected installments of every order, with the payments applied to them.
+    pub fn installments(&self) -> Result<Vec<PaidInstallment>, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
         self.reconciliation_engine
-            .reconcile(event.clone())
-            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))?;
+            .installments(session.as_mut())
+            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))
+    }
+
+    /// The installments due by `as_of` that are not paid in full, by `order_id` and
+    /// due date.
+    pub fn overdue_installments(
+        &self,
+        as_of: DateTime<Utc>,
+    ) -> Result<Vec<PaidInstallment>, EventError> {
+        Ok(self
+            .installments()?
+            .into_iter()
+            .filter(|installment| installment.is_overdue(as_of))
+            .collect())
+    }
+
+    /// The unreconciled items the filter keeps, by age as of the given date.
+    pub fn aging_report(
+        &self,
+        as_of: DateTime<Utc>,
+        filter: &AgingFilter,
+    ) -> Result<AgingReport, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        self.reconciliation_engine
+            .aging_report(session.as_mut(), as_of, filter)
+            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))
+    }
+
+    /// The reconciliation state of every order that occurred in the range, by
+    /// `order_id`.
+    pub fn order_exports(&self, range: &DateRange) -> Result<Vec<OrderExport>, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        self.reconciliation_engine
+            .order_exports(session.as_mut(), range)
+            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))
+    }
+
+    pub fn reconciliation_report(&self) -> Result<ReconciliationReport, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        self.reconciliation_engine
+            .report(session.as_mut())
+            .map_err(|err| EventError::ReconcilationEngineError(err.to_string()))
+    }
+
+    /// The matches still waiting for an order, authorization or bank transaction to
+    /// arrive.
+    pub fn pending_matches(&self) -> Result<Vec<PendingMatch>, EventError> {
+        self.store
+            .session()
+            .and_then(|mut session| session.pending_matches())
+            .map_err(storage_error)
+    }
 
-        Ok(())
+    /// Reads back the accepted events, as they were received.
+    pub fn read_log(&self, query: EventLogQuery) -> Result<Vec<LoggedEvent>, EventError> {
+        self.store
+            .session()
+            .and_then(|mut session| session.read_events(query))
+            .map_err(storage_error)
     }
+
+    /// Applies the logged events the named projectors (all of them when `names` is
+    /// empty) have not seen yet and returns how many each one applied. Every projector
+    /// is tried even if another fails; the first error is returned, and each one is
+    /// kept with the projector's checkpoint until it moves on.
+    pub fn catch_up(&self, names: &[&str]) -> Result<Vec<(&'static str, usize)>, EventError> {
+        let mut first_error = None;
+        let mut applied = vec![];
+        for projector in self.select_projectors(names)? {
+            match self.catch_up_projector(projector) {
+                Ok(count) => applied.push((projector.name(), count)),
+                Err(err) => {
+                    let _ = self.store.session().and_then(|mut session| {
+                        session.save_projector_error(projector.name(), &err.to_string())?;
+                        session.commit()
+                    });
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(applied),
+        }
+    }
+
+    /// Projects the events after the projector's checkpoint, committing every
+    /// [`REPLAY_BATCH`] sequences.
+    fn catch_up_projector(&self, projector: &dyn Projector) -> Result<usize, EventError> {
+        let mut applied = 0;
+        loop {
+            let mut session = self.store.session().map_err(storage_error)?;
+            let head = session.log_head().map_err(storage_error)?;
+            let from = session
+                .checkpoint(projector.name())
+                .map_err(storage_error)?
+                .unwrap_or_default()
+                + 1;
+            if from > head {
+                return Ok(applied);
+            }
+            let to = (from + REPLAY_BATCH).min(head + 1);
+            for logged in session
+                .read_events(EventLogQuery::Range { from, to })
+                .map_err(storage_error)?
+            {
+                projector
+                    .project(session.as_mut(), logged.event)
+                    .map_err(|err| {
+                        EventError::ProjectionError(format!(
+                            "{} at sequence {}: {err}",
+                            projector.name(),
+                            logged.sequence
+                        ))
+                    })?;
+                applied += 1;
+            }
+            session
+                .save_checkpoint(projector.name(), to - 1)
+                .and_then(|_| session.commit())
+                .map_err(storage_error)?;
+        }
+    }
+
+    /// Recomputes the named projections (all of them when `names` is empty) from the
+    /// event log and returns how many events each one replayed. Unless `resume` is
+    /// set the projections are emptied first; with `resume` an interrupted rebuild
+    /// carries on from the projectors' checkpoints.
+    pub fn rebuild(
+        &self,
+        names: &[&str],
+        resume: bool,
+    ) -> Result<Vec<(&'static str, usize)>, EventError> {
+        if !resume {
+            let mut session = self.store.session().map_err(storage_error)?;
+            for projector in self.select_projectors(names)? {
+                projector
+                    .reset(session.as_mut())
+                    .map_err(EventError::ProjectionError)?;
+                session
+                    .save_checkpoint(projector.name(), 0)
+                    .map_err(storage_error)?;
+            }
+            session.commit().map_err(storage_error)?;
+        }
+        self.catch_up(names)
+    }
+
+    /// How far behind the event log each projector is.
+    pub fn lag(&self) -> Result<Vec<ProjectorLag>, EventError> {
+        let mut session = self.store.session().map_err(storage_error)?;
+        let head = session.log_head().map_err(storage_error)?;
+        self.projectors
+            .iter()
+            .map(|projector| {
+                let checkpoint = session
+                    .checkpoint(projector.name())
+                    .map_err(storage_error)?
+                    .unwrap_or_default();
+                Ok(ProjectorLag {
+                    projector: projector.name(),
+                    checkpoint,
+                    lag: head - checkpoint,
+                    last_error: session
+                        .projector_error(projector.name())
+                        .map_err(storage_error)?,
+                })
+            })
+            .collect()
+    }
+
+    fn select_projectors(&self, names: &[&str]) -> Result<Vec<&dyn Projector>, EventError> {
+        if names.is_empty() {
+            return Ok(self.projectors.iter().map(|p| p.as_ref()).collect());
+        }
+        names
+            .iter()
+            .map(|name| {
+                self.projectors
+                    .iter()
+                    .find(|p| p.name() == *name)
+                    .map(|p| p.as_ref())
+                    .ok_or_else(|| {
+                        EventError::ProjectionError(format!("unknown projector `{name}`"))
+                    })
+            })
+            .collect()
+    }
+}
+
+fn storage_error(err: StorageError) -> EventError {
+    EventError::StorageError(err.to_string())
 }
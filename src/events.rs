@@ -1,15 +1,141 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
-#[derive(Clone)]
+use crate::event_handler::EventError;
+use crate::money::{Currency, Decimal, Money};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
     BankTransactionIssued(BankTransactionIssuedPayload),
     PaymentAuthorized(PaymentAuthorizedPayload),
     PaymentCollected(PaymentCollectedPayload),
     ProductOrdered(ProductOrderedPayload),
+    PaymentRefunded(PaymentRefundedPayload),
+    ChargebackReceived(ChargebackReceivedPayload),
+}
+
+impl Event {
+    /// Values of the `type` tag understood by [`Event::from_json`].
+    pub const TYPES: [&'static str; 6] = [
+        "bank_transaction_issued",
+        "payment_authorized",
+        "payment_collected",
+        "product_ordered",
+        "payment_refunded",
+        "chargeback_received",
+    ];
+
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Event::BankTransactionIssued(_) => Self::TYPES[0],
+            Event::PaymentAuthorized(_) => Self::TYPES[1],
+            Event::PaymentCollected(_) => Self::TYPES[2],
+            Event::ProductOrdered(_) => Self::TYPES[3],
+            Event::PaymentRefunded(_) => Self::TYPES[4],
+            Event::ChargebackReceived(_) => Self::TYPES[5],
+        }
+    }
+
+    /// Identifier assigned upstream, the same on every redelivery of the event.
+    pub fn event_id(&self) -> &str {
+        match self {
+            Event::BankTransactionIssued(p) => &p.event_id,
+            Event::PaymentAuthorized(p) => &p.event_id,
+            Event::PaymentCollected(p) => &p.event_id,
+            Event::ProductOrdered(p) => &p.event_id,
+            Event::PaymentRefunded(p) => &p.event_id,
+            Event::ChargebackReceived(p) => &p.event_id,
+        }
+    }
+
+    fn event_id_mut(&mut self) -> &mut String {
+        match self {
+            Event::BankTransactionIssued(p) => &mut p.event_id,
+            Event::PaymentAuthorized(p) => &mut p.event_id,
+            Event::PaymentCollected(p) => &mut p.event_id,
+            Event::ProductOrdered(p) => &mut p.event_id,
+            Event::PaymentRefunded(p) => &mut p.event_id,
+            Event::ChargebackReceived(p) => &mut p.event_id,
+        }
+    }
+
+    /// The order the event refers to, if any.
+    pub fn order_id(&self) -> Option<&str> {
+        match self {
+            Event::PaymentAuthorized(p) => Some(&p.order_id),
+            Event::ProductOrdered(p) => Some(&p.order_id),
+            _ => None,
+        }
+    }
+
+    pub fn payment_id(&self) -> Option<&str> {
+        match self {
+            Event::PaymentAuthorized(p) => Some(&p.payment_id),
+            Event::PaymentCollected(p) => Some(&p.payment_id),
+            Event::PaymentRefunded(p) => Some(&p.payment_id),
+            Event::ChargebackReceived(p) => Some(&p.payment_id),
+            _ => None,
+        }
+    }
+
+    pub fn transaction_id(&self) -> Option<&str> {
+        match self {
+            Event::BankTransactionIssued(p) => Some(&p.transaction_id),
+            Event::PaymentCollected(p) => Some(&p.transaction_id),
+            Event::PaymentRefunded(p) => Some(&p.transaction_id),
+            Event::ChargebackReceived(p) => Some(&p.transaction_id),
+            _ => None,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, EventError> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| EventError::DecodingError(e.to_string()))?;
+        Self::from_value(value)
+    }
+
+    /// Decodes the event, deriving a missing `event_id` from its type and a hash of
+    /// the whole decoded payload, so that only a redelivery of the same event gets
+    /// the same ID.
+    pub fn from_value(mut value: serde_json::Value) -> Result<Self, EventError> {
+        let event_type = match value.get("type").and_then(|t| t.as_str()) {
+            Some(t) if Self::TYPES.contains(&t) => t.to_owned(),
+            Some(t) => return Err(EventError::UnknownEvent(t.to_owned())),
+            None => return Err(EventError::UnknownEvent("missing `type` field".to_owned())),
+        };
+        let mut derive_id = false;
+        if let Some(object) = value.as_object_mut() {
+            if !object.contains_key("event_id") {
+                derive_id = true;
+                object.insert("event_id".to_owned(), "".into());
+            }
+            object.insert("type".to_owned(), event_type.into());
+        }
+        let mut event: Self =
+            serde_json::from_value(value).map_err(|e| EventError::DecodingError(e.to_string()))?;
+        if derive_id {
+            // re-encoded, the payload no longer depends on how upstream wrote it
+            let digest = Sha256::digest(event.to_json()?);
+            let hex = digest
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+            *event.event_id_mut() = format!("{}:{hex}", event.event_type());
+        }
+        Ok(event)
+    }
+
+    pub fn to_json(&self) -> Result<String, EventError> {
+        serde_json::to_string(self).map_err(|e| EventError::DecodingError(e.to_string()))
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EventType {
+    #[default]
     Issuance,
     Cancellation,
     Interruption,
@@ -25,13 +151,41 @@ impl std::fmt::Display for EventType {
     }
 }
 
-#[derive(Clone)]
+impl std::str::FromStr for EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            EventType::Issuance,
+            EventType::Cancellation,
+            EventType::Interruption,
+        ]
+        .into_iter()
+        .find(|event_type| event_type.to_string() == s)
+        .ok_or_else(|| format!("unknown event type `{s}`"))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum InstallmentType {
+    #[default]
     Yearly,
     BiYearly,
     Monthly,
 }
 
+impl InstallmentType {
+    /// Months between two installments: bi-yearly installments are due twice a year.
+    pub fn months(&self) -> u32 {
+        match self {
+            InstallmentType::Yearly => 12,
+            InstallmentType::BiYearly => 6,
+            InstallmentType::Monthly => 1,
+        }
+    }
+}
+
 impl std::fmt::Display for InstallmentType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -42,42 +196,210 @@ impl std::fmt::Display for InstallmentType {
     }
 }
 
-#[derive(Clone)]
+impl std::str::FromStr for InstallmentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            InstallmentType::Yearly,
+            InstallmentType::BiYearly,
+            InstallmentType::Monthly,
+        ]
+        .into_iter()
+        .find(|installment_type| installment_type.to_string() == s)
+        .ok_or_else(|| format!("unknown installment type `{s}`"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct BankTransactionIssuedPayload {
+    pub event_id: String,
     pub transaction_id: String,
-    pub amount: f64,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
+    #[serde(with = "timestamp")]
     pub occurred_on: DateTime<Utc>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PaymentAuthorizedPayload {
+    pub event_id: String,
     pub order_id: String,
     pub payment_id: String,
-    pub amount: f64,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
+    #[serde(with = "timestamp")]
     pub occurred_on: DateTime<Utc>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PaymentCollectedPayload {
+    pub event_id: String,
+    pub payment_id: String,
+    pub transaction_id: String,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
+    #[serde(with = "timestamp")]
+    pub occurred_on: DateTime<Utc>,
+}
+
+/// Money of a collected payment given back to the customer, paid out by a bank
+/// transaction, usually a negative one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PaymentRefundedPayload {
+    pub event_id: String,
+    pub payment_id: String,
+    pub transaction_id: String,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
+    #[serde(with = "timestamp")]
+    pub occurred_on: DateTime<Utc>,
+}
+
+/// A collected payment disputed by the payer and taken back, debited by a bank
+/// transaction.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChargebackReceivedPayload {
+    pub event_id: String,
     pub payment_id: String,
     pub transaction_id: String,
-    pub amount: f64,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
+    #[serde(with = "timestamp")]
     pub occurred_on: DateTime<Utc>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ProductOrderedJson")]
 pub struct ProductOrderedPayload {
+    pub event_id: String,
     pub order_id: String,
-    pub amount: f64,
+    #[serde(flatten, with = "crate::money::amount")]
+    pub amount: Money,
     pub event_type: EventType,
     pub installment_type: InstallmentType,
     pub guarantees: Vec<Guarantee>,
+    #[serde(with = "timestamp")]
     pub occurred_on: DateTime<Utc>,
     pub insurance_code: String,
+    /// The order a cancellation credits or an interruption cuts short. Without it a
+    /// cancellation or interruption is an order of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_order_id: Option<String>,
 }
 
-#[derive(Clone)]
+/// The price is in the currency of the order.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Guarantee {
+    #[serde(rename = "type")]
     pub guarantee_type: String,
-    pub price: f64,
+    pub price: Money,
+}
+
+/// A `product_ordered` payload as written upstream: the guarantee prices take the
+/// currency of the order. Older payloads leave out the event and installment types,
+/// read as a yearly issuance, and the insurance code.
+#[derive(Deserialize)]
+struct ProductOrderedJson {
+    event_id: String,
+    order_id: String,
+    amount: Decimal,
+    #[serde(default)]
+    currency: Currency,
+    #[serde(default)]
+    event_type: EventType,
+    #[serde(default)]
+    installment_type: InstallmentType,
+    guarantees: Vec<GuaranteeJson>,
+    #[serde(with = "timestamp")]
+    occurred_on: DateTime<Utc>,
+    #[serde(default)]
+    insurance_code: String,
+    #[serde(default)]
+    original_order_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GuaranteeJson {
+    #[serde(rename = "type")]
+    guarantee_type: String,
+    price: Decimal,
+}
+
+impl TryFrom<ProductOrderedJson> for ProductOrderedPayload {
+    type Error = String;
+
+    fn try_from(json: ProductOrderedJson) -> Result<Self, Self::Error> {
+        if json.event_type == EventType::Issuance && json.original_order_id.is_some() {
+            return Err("only cancellations and interruptions refer to an original order".into());
+        }
+        let amount = json.amount.to_money(json.currency)?;
+        let guarantees = json
+            .guarantees
+            .into_iter()
+            .map(|g| {
+                Ok(Guarantee {
+                    guarantee_type: g.guarantee_type,
+                    price: g.price.to_money(json.currency)?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        validate_guarantees(&guarantees)?;
+        Ok(Self {
+            event_id: json.event_id,
+            order_id: json.order_id,
+            amount,
+            event_type: json.event_type,
+            installment_type: json.installment_type,
+            guarantees,
+            occurred_on: json.occurred_on,
+            insurance_code: json.insurance_code,
+            original_order_id: json.original_order_id,
+        })
+    }
+}
+
+/// Checks that each guarantee type is listed once, since an order has a single price
+/// per type. Prices that do not add up to the order amount are kept, reconciliation
+/// reports the order as a guarantee mismatch.
+fn validate_guarantees(guarantees: &[Guarantee]) -> Result<(), String> {
+    for (i, guarantee) in guarantees.iter().enumerate() {
+        if guarantees[..i]
+            .iter()
+            .any(|g| g.guarantee_type == guarantee.guarantee_type)
+        {
+            return Err(format!(
+                "guarantee `{}` is listed twice",
+                guarantee.guarantee_type
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parses an `occurred_on` timestamp, accepting RFC 3339, the
+/// `2023-02-20T10:34:33:239Z` style (colon before the milliseconds) used upstream
+/// and the `2023-02-20 10:34:33.239 UTC` style the tables store.
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|d| d.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S:%3fZ")
+                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f UTC"))
+                .map(|d| Utc.from_utc_datetime(&d))
+        })
+        .map_err(|_| format!("invalid timestamp `{s}`"))
+}
+
+mod timestamp {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(d: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&d.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+        let s = String::deserialize(d)?;
+        parse_timestamp(&s).map_err(serde::de::Error::custom)
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::money::{Currency, Money};
+use crate::reconciliation_engine::{CollectionStatus, FactsIndex, OrderCollection};
+use crate::storage::{BusinessKey, Facts, OrderBalance, TransactionBalance};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!(
+                "unknown export format `{s}`, expected csv or jsonl"
+            )),
+        }
+    }
+}
+
+/// The `occurred_on` dates to export, from `from` included to `to` excluded. A
+/// missing bound leaves that side open.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DateRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl DateRange {
+    pub fn contains(&self, on: DateTime<Utc>) -> bool {
+        self.from.is_none_or(|from| from <= on) && self.to.is_none_or(|to| on < to)
+    }
+}
+
+/// The reconciliation state of one order. The amounts are in its currency, the
+/// ordered ones in that of their bank transaction.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderExport {
+    pub order_id: String,
+    /// the payments authorized for the order
+    pub payment_ids: Vec<String>,
+    /// the received bank transactions that settled, refunded or charged back its
+    /// payments
+    pub transaction_ids: Vec<String>,
+    /// the amount once cancellations and interruptions are applied
+    pub expected: Money,
+    pub collected: Money,
+    /// what each of `transaction_ids` settled for stored orders, in its currency
+    pub ordered: Vec<Money>,
+    pub currency: Currency,
+    /// how `collected` compares to `expected`
+    pub status: CollectionStatus,
+    pub insurance_code: String,
+}
+
+impl OrderExport {
+    pub const CSV_HEADER: &'static str = "order_id,payment_ids,transaction_ids,expected,collected,ordered,currency,status,insurance_code";
+
+    /// The orders of `balances` that occurred in the range, by `order_id`. The bank
+    /// transactions are those the `relations` table allocates to the order.
+    pub fn from_facts(
+        facts: &Facts,
+        balances: &[OrderBalance],
+        transactions: &[TransactionBalance],
+        range: &DateRange,
+    ) -> Vec<Self> {
+        let index = FactsIndex::new(facts);
+        let ordered = transactions
+            .iter()
+            .map(|t| (t.transaction_id.as_str(), t.ordered_amount))
+            .collect::<HashMap<_, _>>();
+        balances
+            .iter()
+            .filter_map(|balance| {
+                let order = index.orders.get(balance.order_id.as_str())?;
+                if !range.contains(order.occurred_on) {
+                    return None;
+                }
+                let payment_ids = index
+                    .authorizations(BusinessKey::Order, &order.order_id)
+                    .iter()
+                    .map(|a| a.payment_id.as_str())
+                    .collect::<BTreeSet<_>>();
+                let transaction_ids = index
+                    .allocations(BusinessKey::Order, &order.order_id)
+                    .iter()
+                    .map(|a| a.transaction_id.as_str())
+                    .filter(|t| ordered.contains_key(t))
+                    .collect::<BTreeSet<_>>();
+                let collection = OrderCollection::from(balance.clone());
+                Some(Self {
+                    order_id: order.order_id.clone(),
+                    payment_ids: payment_ids.into_iter().map(str::to_owned).collect(),
+                    ordered: transaction_ids.iter().map(|t| ordered[t]).collect(),
+                    transaction_ids: transaction_ids.into_iter().map(str::to_owned).collect(),
+                    expected: collection.expected,
+                    collected: collection.collected,
+                    currency: collection.amount.currency(),
+                    status: collection.status,
+                    insurance_code: order.insurance_code.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// A CSV record, the IDs and the ordered amounts separated by `;`.
+    pub fn to_csv(&self) -> String {
+        [
+            self.order_id.clone(),
+            self.payment_ids.join(";"),
+            self.transaction_ids.join(";"),
+            self.expected.to_string(),
+            self.collected.to_string(),
+            self.ordered
+                .iter()
+                .map(Money::to_string)
+                .collect::<Vec<_>>()
+                .join(";"),
+            self.currency.to_string(),
+            self.status.to_string(),
+            self.insurance_code.clone(),
+        ]
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+/// Quotes the field when it holds a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes the orders one per line, after a header for CSV.
+pub fn write<W: Write>(
+    writer: &mut W,
+    orders: &[OrderExport],
+    format: ExportFormat,
+) -> Result<(), String> {
+    let write_line =
+        |writer: &mut W, line: &str| writeln!(writer, "{line}").map_err(|e| e.to_string());
+    match format {
+        ExportFormat::Csv => {
+            write_line(writer, OrderExport::CSV_HEADER)?;
+            for order in orders {
+                write_line(writer, &order.to_csv())?;
+            }
+        }
+        ExportFormat::JsonLines => {
+            for order in orders {
+                write_line(
+                    writer,
+                    &serde_json::to_string(order).map_err(|e| e.to_string())?,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
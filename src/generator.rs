@@ -0,0 +1,253 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::seq::{index, SliceRandom};
+use rand::Rng;
+
+use crate::events::{
+    BankTransactionIssuedPayload, Event, EventType, InstallmentType, PaymentAuthorizedPayload,
+    PaymentCollectedPayload, ProductOrderedPayload,
+};
+use crate::money::{Currency, Money};
+use crate::storage::BusinessKey;
+
+/// A discrepancy [`EventFamily::generate`] can inject into an otherwise consistent
+/// family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mismatch {
+    /// the collections of one payment are never sent
+    UncollectedPayment,
+    /// one bank transaction is never issued
+    MissingTransaction,
+    /// one bank transaction is issued for more than it settles
+    TransactionAmount,
+    /// the order is for more than its payments
+    OrderAmount,
+}
+
+impl Mismatch {
+    pub const ALL: [Mismatch; 4] = [
+        Mismatch::UncollectedPayment,
+        Mismatch::MissingTransaction,
+        Mismatch::TransactionAmount,
+        Mismatch::OrderAmount,
+    ];
+}
+
+/// How many events of each kind a family has. The collections are raised to one
+/// per payment and per bank transaction at least, and capped at one per payment and
+/// transaction pair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FamilyShape {
+    pub authorizations: usize,
+    pub collections: usize,
+    pub transactions: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl FamilyShape {
+    /// A consistent shape with up to `max` events of each kind.
+    pub fn random<R: Rng>(rng: &mut R, max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            authorizations: rng.gen_range(1..=max),
+            collections: rng.gen_range(1..=max),
+            transactions: rng.gen_range(1..=max),
+            mismatches: vec![],
+        }
+    }
+}
+
+/// A mismatch that was injected, and the order, payment or bank transaction the
+/// reconciliation report must flag for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Injected {
+    pub mismatch: Mismatch,
+    pub key: BusinessKey,
+    pub id: String,
+}
+
+/// The events of one order: its authorizations, their collections and the bank
+/// transactions settling them, sorted as they would happen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventFamily {
+    pub order_id: String,
+    pub events: Vec<Event>,
+    /// the requested mismatches that had something to apply to
+    pub injected: Vec<Injected>,
+}
+
+impl EventFamily {
+    /// Generates the family with every ID prefixed by `family`, so that families
+    /// generated with different prefixes never share a key.
+    pub fn generate<R: Rng>(
+        rng: &mut R,
+        family: &str,
+        occurred_on: DateTime<Utc>,
+        shape: &FamilyShape,
+    ) -> Self {
+        let payments = shape.authorizations.max(1);
+        let transactions = shape.transactions.max(1);
+        let order_id = format!("{family}_ord");
+        let payment_id = |p: usize| format!("{family}_pay_{p}");
+        let transaction_id = |t: usize| format!("{family}_tran_{t}");
+
+        // (payment, transaction) pairs, covering each of them at least once
+        let covering = payments.max(transactions);
+        let mut pairs = (0..covering)
+            .map(|i| (i % payments, i % transactions))
+            .collect::<Vec<_>>();
+        let mut others = (0..payments)
+            .flat_map(|p| (0..transactions).map(move |t| (p, t)))
+            .filter(|pair| !pairs.contains(pair))
+            .collect::<Vec<_>>();
+        others.shuffle(rng);
+        others.truncate(shape.collections.saturating_sub(covering));
+        pairs.extend(others);
+        pairs.sort();
+
+        // each authorization is split across its collections
+        let mut collections = vec![];
+        let mut authorized = vec![];
+        for p in 0..payments {
+            let settled_by = pairs
+                .iter()
+                .filter(|(payment, _)| *payment == p)
+                .map(|(_, t)| *t)
+                .collect::<Vec<_>>();
+            let amount = rng
+                .gen_range(100..=100_000_i64)
+                .max(settled_by.len() as i64);
+            authorized.push(amount);
+            let mut cuts = index::sample(rng, amount as usize - 1, settled_by.len() - 1)
+                .into_iter()
+                .map(|cut| cut as i64 + 1)
+                .collect::<Vec<_>>();
+            cuts.sort();
+            cuts.push(amount);
+            let mut from = 0;
+            for (t, to) in settled_by.into_iter().zip(cuts) {
+                collections.push((p, t, to - from));
+                from = to;
+            }
+        }
+
+        let mut injected = vec![];
+        let mut targeted = vec![];
+        let mut mismatches = shape.mismatches.clone();
+        mismatches.sort();
+        let mut order_amount = authorized.iter().sum::<i64>();
+        let mut missing = vec![];
+        let mut surplus = vec![0; transactions];
+        for mismatch in mismatches {
+            let issued = (0..transactions)
+                .filter(|t| {
+                    !missing.contains(t)
+                        && !targeted.contains(&(BusinessKey::Transaction, *t))
+                        && collections.iter().any(|(_, tr, _)| tr == t)
+                })
+                .collect::<Vec<_>>();
+            let target = match mismatch {
+                Mismatch::UncollectedPayment => (0..payments)
+                    .filter(|p| !targeted.contains(&(BusinessKey::Payment, *p)))
+                    .collect::<Vec<_>>()
+                    .choose(rng)
+                    .map(|p| {
+                        collections.retain(|(payment, _, _)| payment != p);
+                        (BusinessKey::Payment, *p)
+                    }),
+                Mismatch::MissingTransaction => issued.choose(rng).and_then(|t| {
+                    missing.push(*t);
+                    collections
+                        .iter()
+                        .find(|(_, tr, _)| tr == t)
+                        .map(|(p, _, _)| (BusinessKey::Payment, *p))
+                }),
+                Mismatch::TransactionAmount => issued.choose(rng).map(|t| {
+                    surplus[*t] = rng.gen_range(1..=10_000);
+                    (BusinessKey::Transaction, *t)
+                }),
+                Mismatch::OrderAmount => {
+                    (!targeted.contains(&(BusinessKey::Order, 0))).then(|| {
+                        order_amount += rng.gen_range(1..=10_000);
+                        (BusinessKey::Order, 0)
+                    })
+                }
+            };
+            if let Some((key, i)) = target {
+                targeted.push((key, i));
+                injected.push(Injected {
+                    mismatch,
+                    key,
+                    id: match key {
+                        BusinessKey::Order => order_id.clone(),
+                        BusinessKey::Payment => payment_id(i),
+                        BusinessKey::Transaction => transaction_id(i),
+                    },
+                });
+            }
+        }
+
+        let currency = Currency::EUR;
+        let mut sequence = 0;
+        let mut event_id = || {
+            sequence += 1;
+            format!("{family}_evt_{sequence}")
+        };
+        let later = |rng: &mut R| occurred_on + Duration::seconds(rng.gen_range(0..86_400));
+        let mut events = vec![Event::ProductOrdered(ProductOrderedPayload {
+            event_id: event_id(),
+            order_id: order_id.clone(),
+            amount: Money::new(order_amount, currency),
+            guarantees: vec![],
+            occurred_on,
+            event_type: EventType::Issuance,
+            installment_type: InstallmentType::Yearly,
+            insurance_code: format!("PRP{}", rng.gen_range(100..1000)),
+            original_order_id: None,
+        })];
+        for (p, amount) in authorized.into_iter().enumerate() {
+            events.push(Event::PaymentAuthorized(PaymentAuthorizedPayload {
+                event_id: event_id(),
+                order_id: order_id.clone(),
+                payment_id: payment_id(p),
+                amount: Money::new(amount, currency),
+                occurred_on: later(rng),
+            }));
+        }
+        for (p, t, amount) in &collections {
+            events.push(Event::PaymentCollected(PaymentCollectedPayload {
+                event_id: event_id(),
+                payment_id: payment_id(*p),
+                transaction_id: transaction_id(*t),
+                amount: Money::new(*amount, currency),
+                occurred_on: later(rng),
+            }));
+        }
+        for t in (0..transactions).filter(|t| !missing.contains(t)) {
+            let settled = collections
+                .iter()
+                .filter(|(_, tr, _)| *tr == t)
+                .map(|(_, _, amount)| amount)
+                .sum::<i64>();
+            // a transaction left without collections is never issued
+            if settled > 0 {
+                events.push(Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                    event_id: event_id(),
+                    transaction_id: transaction_id(t),
+                    amount: Money::new(settled + surplus[t], currency),
+                    occurred_on: later(rng),
+                }));
+            }
+        }
+
+        Self {
+            order_id,
+            events,
+            injected,
+        }
+    }
+
+    /// Whether no mismatch was injected, so that everything reconciles.
+    pub fn is_consistent(&self) -> bool {
+        self.injected.is_empty()
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Display;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::event_handler::{Acceptance, EventError, EventHandler};
+use crate::events::Event;
+
+/// Where an event was read from: the file (or `<stdin>`) and the line its JSON starts on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Position {
+    pub source: String,
+    pub line: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.source, self.line))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IngestSummary {
+    pub accepted: usize,
+    /// events skipped because they had already been applied
+    pub duplicates: Vec<Position>,
+    pub rejected: Vec<(Position, EventError)>,
+    pub unknown: Vec<(Position, String)>,
+    /// why a projector stopped catching up with the ingested events, see
+    /// [`EventHandler::lag`]
+    pub projector_error: Option<EventError>,
+}
+
+impl Display for IngestSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "accepted: {}", self.accepted)?;
+        writeln!(f, "duplicates: {}", self.duplicates.len())?;
+        for position in &self.duplicates {
+            writeln!(f, "  {position}")?;
+        }
+        writeln!(f, "rejected: {}", self.rejected.len())?;
+        for (position, err) in &self.rejected {
+            writeln!(f, "  {position}: {err}")?;
+        }
+        writeln!(f, "unknown: {}", self.unknown.len())?;
+        for (position, event_type) in &self.unknown {
+            writeln!(f, "  {position}: {event_type}")?;
+        }
+        if let Some(err) = &self.projector_error {
+            writeln!(f, "projectors behind: {err}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays events from `path` through the handler, then catches the projectors up
+/// with them. Directories are read file by file in name order; `None` or `-` reads
+/// JSON Lines from stdin.
+pub fn ingest(handler: &EventHandler, path: Option<&Path>) -> Result<IngestSummary, String> {
+    let mut summary = IngestSummary::default();
+    match path {
+        None => ingest_stdin(handler, &mut summary)?,
+        Some(p) if p == Path::new("-") => ingest_stdin(handler, &mut summary)?,
+        Some(p) if p.is_dir() => {
+            for file in json_files(p)? {
+                ingest_file(handler, &file, &mut summary)?;
+            }
+        }
+        Some(p) => ingest_file(handler, p, &mut summary)?,
+    }
+    summary.projector_error = handler.catch_up(&[]).err();
+    Ok(summary)
+}
+
+fn json_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = std::fs::read_dir(dir)
+        .map_err(|e| format!("{}: {e}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|p| {
+        p.is_file()
+            && matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("json") | Some("jsonl")
+            )
+    });
+    files.sort();
+    Ok(files)
+}
+
+fn ingest_stdin(handler: &EventHandler, summary: &mut IngestSummary) -> Result<(), String> {
+    let mut input = String::new();
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|e| format!("<stdin>: {e}"))?;
+    ingest_str(handler, "<stdin>", &input, summary);
+    Ok(())
+}
+
+fn ingest_file(
+    handler: &EventHandler,
+    path: &Path,
+    summary: &mut IngestSummary,
+) -> Result<(), String> {
+    let input = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    ingest_str(handler, &path.display().to_string(), &input, summary);
+    Ok(())
+}
+
+/// Accepts a single pretty-printed document as well as JSON Lines, since both are
+/// just a stream of whitespace-separated JSON values. After a syntax error reading
+/// resumes at the next line opening a top-level value, one starting with `{`.
+fn ingest_str(handler: &EventHandler, source: &str, input: &str, summary: &mut IngestSummary) {
+    let mut base = 0;
+    while base < input.len() {
+        let mut stream =
+            serde_json::Deserializer::from_str(&input[base..]).into_iter::<serde_json::Value>();
+        loop {
+            let start = value_start(input, base + stream.byte_offset());
+            let position = Position {
+                source: source.to_owned(),
+                line: input[..start].matches('\n').count() + 1,
+            };
+            let value = match stream.next() {
+                None => return,
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    summary
+                        .rejected
+                        .push((position, EventError::DecodingError(e.to_string())));
+                    base = next_top_level_value(input, start);
+                    break;
+                }
+            };
+
+            match Event::from_value(value).and_then(|event| handler.accept(event)) {
+                Ok(Acceptance::Applied) => summary.accepted += 1,
+                Ok(Acceptance::Duplicate) => summary.duplicates.push(position),
+                Err(EventError::UnknownEvent(event_type)) => {
+                    summary.unknown.push((position, event_type))
+                }
+                Err(err) => summary.rejected.push((position, err)),
+            }
+        }
+    }
+}
+
+/// The offset of the first non-whitespace character from `offset` on.
+fn value_start(input: &str, offset: usize) -> usize {
+    input.len() - input[offset..].trim_start().len()
+}
+
+/// The start of the first line after the one holding `offset` that opens a
+/// top-level value, skipping the rest of a broken multi-line document.
+fn next_top_level_value(input: &str, offset: usize) -> usize {
+    let next_line = |offset: usize| {
+        input[offset..]
+            .find('\n')
+            .map_or(input.len(), |i| offset + i + 1)
+    };
+    let mut line = next_line(offset);
+    while line < input.len() && !input[line..].starts_with('{') {
+        line = next_line(line);
+    }
+    line
+}
@@ -0,0 +1,136 @@
+use chrono::{DateTime, Months, Utc};
+
+use crate::events::InstallmentType;
+use crate::money::Money;
+
+/// Orders run for a year from when they are placed.
+pub const TERM_MONTHS: u32 = 12;
+
+/// A part of an order's amount, due at the start of the period it covers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Installment {
+    pub due_on: DateTime<Utc>,
+    /// when the next installment is due, or the term ends
+    pub covers_until: DateTime<Utc>,
+    pub amount: Money,
+}
+
+/// Splits the amount into equal installments over the term starting `from`, the
+/// first one taking what does not divide evenly.
+pub fn schedule(
+    amount: Money,
+    installment_type: &InstallmentType,
+    from: DateTime<Utc>,
+) -> Vec<Installment> {
+    let months = installment_type.months();
+    let count = (TERM_MONTHS / months) as i64;
+    let share = amount.minor_units() / count;
+    let remainder = amount.minor_units() - share * count;
+    let after = |n: i64| from + Months::new(months * n as u32);
+    (0..count)
+        .map(|n| Installment {
+            due_on: after(n),
+            covers_until: after(n + 1),
+            amount: Money::new(
+                if n == 0 { share + remainder } else { share },
+                amount.currency(),
+            ),
+        })
+        .collect()
+}
+
+/// The installments still owed when the order is interrupted `on` the given date:
+/// those due before it, the one running prorated by the time it covered and none
+/// of the later ones.
+pub fn interrupted(installments: &[Installment], on: DateTime<Utc>) -> Vec<Installment> {
+    installments
+        .iter()
+        .filter(|installment| installment.due_on < on)
+        .map(|installment| {
+            let covered = (on - installment.due_on).num_seconds() as f64;
+            let period = (installment.covers_until - installment.due_on).num_seconds() as f64;
+            let fraction = (covered / period).clamp(0.0, 1.0);
+            Installment {
+                amount: Money::new(
+                    (installment.amount.minor_units() as f64 * fraction).round() as i64,
+                    installment.amount.currency(),
+                ),
+                ..installment.clone()
+            }
+        })
+        .collect()
+}
+
+/// Takes the credit off the latest installments first, down to nothing.
+pub fn credit(installments: &mut [Installment], credit: Money) {
+    let mut left = credit.minor_units();
+    for installment in installments.iter_mut().rev() {
+        let taken = left.min(installment.amount.minor_units()).max(0);
+        installment.amount = installment.amount - Money::new(taken, credit.currency());
+        left -= taken;
+    }
+}
+
+/// An installment with what has been paid towards it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaidInstallment {
+    pub order_id: String,
+    /// from 1, in due order
+    pub number: usize,
+    pub due_on: DateTime<Utc>,
+    pub amount: Money,
+    pub paid: Money,
+    /// the payments that paid towards it, in the order they were applied
+    pub payment_ids: Vec<String>,
+}
+
+impl PaidInstallment {
+    pub fn outstanding(&self) -> Money {
+        self.amount - self.paid
+    }
+
+    /// Whether it was due by `as_of` and is not paid in full.
+    pub fn is_overdue(&self, as_of: DateTime<Utc>) -> bool {
+        self.due_on <= as_of && self.outstanding().minor_units() > 0
+    }
+}
+
+/// Applies the payments to the installments in due order, each one paying off what
+/// is left of the earliest installments before the next. Payments are in the
+/// currency of the installments; what exceeds the schedule is not applied.
+pub fn apply_payments<'a, I>(
+    order_id: &str,
+    installments: &[Installment],
+    payments: I,
+) -> Vec<PaidInstallment>
+where
+    I: IntoIterator<Item = (&'a str, Money)>,
+{
+    let mut paid = installments
+        .iter()
+        .enumerate()
+        .map(|(i, installment)| PaidInstallment {
+            order_id: order_id.to_owned(),
+            number: i + 1,
+            due_on: installment.due_on,
+            amount: installment.amount,
+            paid: Money::zero(installment.amount.currency()),
+            payment_ids: vec![],
+        })
+        .collect::<Vec<_>>();
+    for (payment_id, amount) in payments {
+        let mut left = amount.minor_units();
+        for installment in paid.iter_mut() {
+            if left <= 0 {
+                break;
+            }
+            let taken = left.min(installment.outstanding().minor_units());
+            if taken > 0 {
+                installment.paid = installment.paid + Money::new(taken, amount.currency());
+                installment.payment_ids.push(payment_id.to_owned());
+                left -= taken;
+            }
+        }
+    }
+    paid
+}
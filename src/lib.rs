@@ -1,158 +1,142 @@
+pub mod aging;
+pub mod config;
 pub mod event_handler;
 pub mod events;
+pub mod export;
+pub mod generator;
+pub mod ingest;
+pub mod installments;
+pub mod money;
 pub mod pool;
 pub mod projectors;
 pub mod reconciliation_engine;
+pub mod storage;
 #[cfg(test)]
 mod tests {
     use postgres::types::FromSql;
 
+    use once_cell::sync::Lazy;
     use postgres::NoTls;
     use r2d2_postgres::r2d2::PooledConnection;
     use r2d2_postgres::PostgresConnectionManager;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use std::fmt::Debug;
     use std::str::FromStr;
 
+    use crate::aging::{AgeBucket, AgingFilter, AgingKind};
+    use crate::config::*;
     use crate::event_handler::*;
     use crate::events::*;
+    use crate::export::{DateRange, ExportFormat};
+    use crate::generator::{EventFamily, FamilyShape, Mismatch};
+    use crate::money::{Currency, ExchangeRates, Money};
+    use crate::projectors::Projector;
+    use crate::reconciliation_engine::{
+        guarantee_totals, CollectionStatus, GuaranteeTotal, OrderCollection, Reconciliation,
+        ReconciliationStatus,
+    };
+    use crate::storage::memory::InMemoryStore;
+    use crate::storage::postgres::{Pool, PostgresStore};
+    use crate::storage::sqlite::SqliteStore;
+    use crate::storage::{BusinessKey, EventLogQuery, PendingMatch, Session, Store, Total};
+    use sqlite::Value;
     type Client = PooledConnection<PostgresConnectionManager<NoTls>>;
 
+    static POOL: Lazy<Pool> =
+        Lazy::new(|| crate::pool::connect(&Config::load().unwrap().database).unwrap());
+
+    // tests sharing the Postgres database must not reset it under each other
+    static DB_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
     #[test]
     fn happy_path_reconciliation_engine() {
-        let mut client = crate::pool::POOL.get().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut client = POOL.get().unwrap();
         crate::pool::reset_db(&mut client);
-        let events = [
-            Event::ProductOrdered(ProductOrderedPayload {
-                amount: 100.0,
-                order_id: "ord_1".to_owned(),
-                guarantees: vec![],
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
-                event_type: EventType::Issuance,
-                installment_type: InstallmentType::Yearly,
-                insurance_code: "PRP123".to_string(),
-            }),
-            Event::PaymentAuthorized(PaymentAuthorizedPayload {
-                amount: 100.0,
-                order_id: "ord_1".to_owned(),
-                payment_id: "pay_1".to_owned(),
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:01.000Z").unwrap(),
-            }),
-            Event::PaymentCollected(PaymentCollectedPayload {
-                amount: 100.0,
-                payment_id: "pay_1".to_owned(),
-                transaction_id: "tran_1".to_owned(),
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
-            }),
-            Event::BankTransactionIssued(BankTransactionIssuedPayload {
-                amount: 100.0,
-                transaction_id: "tran_1".to_owned(),
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:01.000Z").unwrap(),
-            }),
-        ];
+        let events = happy_path_events();
 
-        let event_handler = EventHandler::new();
+        let event_handler = EventHandler::new(postgres_store());
         let handler_result = events
             .into_iter()
             .map(|e| event_handler.accept(e))
             .collect::<Result<Vec<_>, _>>();
 
         assert!(handler_result.is_ok());
-
-        client.query(r"SELECT * from relations", &[]).unwrap();
+        event_handler.catch_up(&[]).unwrap();
 
         let s = client
-            .query(r"SELECT SUM(amount) from total_ordered", &[])
+            .query(r"SELECT CAST(SUM(amount) as int8) from total_ordered", &[])
             .unwrap();
 
-        let actual_total_ordered: f64 = s.get(0).unwrap().get(0);
+        let actual_total_ordered: i64 = s.first().unwrap().get(0);
 
         assert_eq!(
-            actual_total_ordered, 100.0,
+            actual_total_ordered, 10_000,
             "expecting the sum of all ordered events to be 100"
         );
 
         let s = client
-            .query(r"SELECT SUM(amount) from total_authorized", &[])
+            .query(
+                r"SELECT CAST(SUM(amount) as int8) from total_authorized",
+                &[],
+            )
             .unwrap();
 
-        let actual_total_authorized: f64 = s.get(0).unwrap().get(0);
+        let actual_total_authorized: i64 = s.first().unwrap().get(0);
 
         assert_eq!(
-            actual_total_authorized, 100.0,
+            actual_total_authorized, 10_000,
             "expecting the sum of all authorized events to be 100"
         );
 
         let s = client
-            .query(r"SELECT SUM(amount) from total_collected", &[])
+            .query(
+                r"SELECT CAST(SUM(amount) as int8) from total_collected",
+                &[],
+            )
             .unwrap();
 
-        let actual_total_collected: f64 = s.get(0).unwrap().get(0);
+        let actual_total_collected: i64 = s.first().unwrap().get(0);
 
         assert_eq!(
-            actual_total_collected, 100.0 as f64,
+            actual_total_collected, 10_000,
             "expecting the sum of all collected events to be 100"
         );
 
         assert_query(
             &mut client,
             r"SELECT COUNT(order_id) from product_orders where  collected_amount <> amount",
-            0 as i64,
+            0_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT COUNT(transaction_id) from bank_transactions where ordered_amount <> amount",
-            0 as i64,
+            0_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT COUNT(order_id) from product_orders where collected_amount = amount",
-            1 as i64,
+            1_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT COUNT(transaction_id) from bank_transactions where ordered_amount = amount",
-            1 as i64,
+            1_i64,
         );
     }
 
     #[test]
     fn events_type_not_reconciled() {
-        let mut client = crate::pool::POOL.get().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut client = POOL.get().unwrap();
         crate::pool::reset_db(&mut client);
-        let events = [
-            Event::ProductOrdered(ProductOrderedPayload {
-                amount: 100.0,
-                order_id: "ord_1".to_owned(),
-                guarantees: vec![],
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
-                event_type: EventType::Issuance,
-                installment_type: InstallmentType::Yearly,
-                insurance_code: "PRP1".to_owned(),
-            }),
-            Event::ProductOrdered(ProductOrderedPayload {
-                amount: 200.0,
-                order_id: "ord_2".to_owned(),
-                guarantees: vec![],
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
-                event_type: EventType::Interruption,
-                installment_type: InstallmentType::BiYearly,
-                insurance_code: "PRP2".to_owned(),
-            }),
-            Event::ProductOrdered(ProductOrderedPayload {
-                amount: 300.0,
-                order_id: "ord_3".to_owned(),
-                guarantees: vec![],
-                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
-                event_type: EventType::Interruption,
-                installment_type: InstallmentType::BiYearly,
-                insurance_code: "PRP3".to_owned(),
-            }),
-        ];
+        let events = events_type_not_reconciled_events();
 
-        let event_handler = EventHandler::new();
+        let event_handler = EventHandler::new(postgres_store());
         let handler_result = events
             .into_iter()
             .map(|e| event_handler.accept(e))
@@ -162,31 +146,2437 @@ mod tests {
         assert_query(
             &mut client,
             r"SELECT COUNT(*) FROM product_orders WHERE event_type='issuance' AND collected_amount <> amount",
-            1 as i64,
+            1_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT CAST(SUM(amount) as int8) FROM product_orders WHERE event_type='issuance' AND collected_amount <> amount",
-            100 as i64,
+            10_000_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT COUNT(*) FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
-            2 as i64,
+            2_i64,
         );
         assert_query(
             &mut client,
             r"SELECT CAST(SUM(amount) as int8) FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
-            500 as i64,
+            50_000_i64,
         );
 
         assert_query(
             &mut client,
             r"SELECT insurance_code FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
             "PRP2".to_string(),
+        );
+
+        assert_not_reconciled_by_event_type(&event_handler);
+    }
+
+    #[test]
+    fn happy_path_reconciliation_engine_sqlite() {
+        let store = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+        store.migrate().unwrap();
+
+        let event_handler = EventHandler::new(Box::new(store.clone()));
+        let handler_result = happy_path_events()
+            .into_iter()
+            .map(|e| event_handler.accept(e))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(handler_result.is_ok());
+        event_handler.catch_up(&[]).unwrap();
+
+        let connection = store.connection();
+        for total in ["total_ordered", "total_authorized", "total_collected"] {
+            assert_sqlite_query(
+                &connection,
+                &format!("SELECT SUM(amount) from {total}"),
+                Value::Integer(10_000),
+            );
+        }
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(order_id) from product_orders where collected_amount <> amount",
+            Value::Integer(0),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(transaction_id) from bank_transactions where ordered_amount <> amount",
+            Value::Integer(0),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(order_id) from product_orders where collected_amount = amount",
+            Value::Integer(1),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(transaction_id) from bank_transactions where ordered_amount = amount",
+            Value::Integer(1),
+        );
+    }
+
+    #[test]
+    fn events_type_not_reconciled_sqlite() {
+        let path = std::env::temp_dir().join("spike_costacando_not_reconciled.sqlite");
+        let store = std::sync::Arc::new(SqliteStore::open(&path).unwrap());
+        store.reset_db().unwrap();
+
+        let event_handler = EventHandler::new(Box::new(store.clone()));
+        let handler_result = events_type_not_reconciled_events()
+            .into_iter()
+            .map(|e| event_handler.accept(e))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(handler_result.is_ok());
+
+        let connection = store.connection();
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(*) FROM product_orders WHERE event_type='issuance' AND collected_amount <> amount",
+            Value::Integer(1),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT CAST(SUM(amount) as int8) FROM product_orders WHERE event_type='issuance' AND collected_amount <> amount",
+            Value::Integer(10_000),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT COUNT(*) FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
+            Value::Integer(2),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT CAST(SUM(amount) as int8) FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
+            Value::Integer(50_000),
+        );
+        assert_sqlite_query(
+            &connection,
+            r"SELECT insurance_code FROM product_orders WHERE event_type='interruption' AND collected_amount <> amount",
+            Value::String("PRP2".to_owned()),
+        );
+        drop(connection);
+
+        assert_not_reconciled_by_event_type(&event_handler);
+        drop(event_handler);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn happy_path_reconciliation_engine_in_memory() {
+        let store = std::sync::Arc::new(InMemoryStore::new());
+        let event_handler = EventHandler::new(Box::new(store.clone()));
+        let handler_result = happy_path_events()
+            .into_iter()
+            .map(|e| event_handler.accept(e))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(handler_result.is_ok());
+        event_handler.catch_up(&[]).unwrap();
+
+        let state = store.state();
+        assert_eq!(state.total(Total::Ordered, Currency::EUR), eur(100.0));
+        assert_eq!(state.total(Total::Authorized, Currency::EUR), eur(100.0));
+        assert_eq!(state.total(Total::Collected, Currency::EUR), eur(100.0));
+        assert_eq!(state.unreconciled_orders().count(), 0);
+        assert_eq!(state.unreconciled_transactions().count(), 0);
+        assert_eq!(state.product_orders["ord_1"].collected_amount, eur(100.0));
+        assert_eq!(state.bank_transactions["tran_1"].ordered_amount, eur(100.0));
+    }
+
+    #[test]
+    fn in_memory_store_matches_sqlite() {
+        let mut scenarios = vec![
+            events_type_not_reconciled_events().to_vec(),
+            split_payment_events(),
+        ];
+        // every arrival order of the happy path
+        let events = happy_path_events();
+        for a in 0..4 {
+            for b in (0..4).filter(|b| *b != a) {
+                for c in (0..4).filter(|c| *c != a && *c != b) {
+                    let d = 6 - a - b - c;
+                    scenarios.push([a, b, c, d].iter().map(|i| events[*i].clone()).collect());
+                }
+            }
+        }
+
+        for scenario in scenarios {
+            let memory = std::sync::Arc::new(InMemoryStore::new());
+            let sqlite = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+            sqlite.migrate().unwrap();
+            let memory_handler = EventHandler::new(Box::new(memory.clone()));
+            let sqlite_handler = EventHandler::new(Box::new(sqlite.clone()));
+            for event in scenario {
+                assert_eq!(
+                    memory_handler.accept(event.clone()).is_ok(),
+                    sqlite_handler.accept(event).is_ok()
+                );
+            }
+
+            let state = memory.state();
+            let connection = sqlite.connection();
+            let sqlite_orders = sqlite_rows(
+                &connection,
+                "SELECT order_id, collected_amount FROM product_orders",
+            );
+            let memory_orders = state
+                .product_orders
+                .iter()
+                .map(|(id, o)| (id.clone(), Some(o.collected_amount.minor_units())))
+                .collect::<Vec<_>>();
+            assert_eq!(memory_orders, sqlite_orders);
+
+            let sqlite_transactions = sqlite_rows(
+                &connection,
+                "SELECT transaction_id, ordered_amount FROM bank_transactions",
+            );
+            let memory_transactions = state
+                .bank_transactions
+                .iter()
+                .map(|(id, t)| (id.clone(), Some(t.ordered_amount.minor_units())))
+                .collect::<Vec<_>>();
+            assert_eq!(memory_transactions, sqlite_transactions);
+        }
+    }
+
+    #[test]
+    fn arrival_order_does_not_change_the_outcome() {
+        fn outcome(
+            handler: &EventHandler,
+        ) -> (Vec<OrderCollection>, Vec<Reconciliation>, Vec<PendingMatch>) {
+            (
+                handler.order_collections().unwrap(),
+                handler
+                    .reconciliation_report()
+                    .unwrap()
+                    .iter()
+                    .cloned()
+                    .collect(),
+                handler.pending_matches().unwrap(),
+            )
+        }
+
+        // the authorization waits for its order, the collection for both ends
+        let handler = EventHandler::new(Box::new(InMemoryStore::new()));
+        let [order, authorization, collection, transaction] = happy_path_events();
+        handler.accept(authorization).unwrap();
+        handler.accept(collection).unwrap();
+        assert_eq!(
+            handler.pending_matches().unwrap(),
+            [
+                PendingMatch {
+                    awaited: (BusinessKey::Order, "ord_1".to_owned()),
+                    key: (BusinessKey::Payment, "pay_1".to_owned()),
+                },
+                PendingMatch {
+                    awaited: (BusinessKey::Transaction, "tran_1".to_owned()),
+                    key: (BusinessKey::Payment, "pay_1".to_owned()),
+                },
+            ]
+        );
+        handler.accept(transaction).unwrap();
+        handler.accept(order).unwrap();
+        assert_eq!(handler.pending_matches().unwrap(), []);
+        assert_eq!(
+            handler.order_collections().unwrap()[0].status,
+            CollectionStatus::Full
+        );
+
+        fn shuffled_like_in_order(keys: Vec<u32>) -> bool {
+            [happy_path_events().to_vec(), split_payment_events()]
+                .into_iter()
+                .all(|events| {
+                    let in_order = EventHandler::new(Box::new(InMemoryStore::new()));
+                    for event in events.clone() {
+                        in_order.accept(event).unwrap();
+                    }
+
+                    let shuffled = shuffled(events, &keys);
+                    let sqlite = SqliteStore::open(":memory:").unwrap();
+                    sqlite.migrate().unwrap();
+                    [
+                        Box::new(InMemoryStore::new()) as Box<dyn Store>,
+                        Box::new(sqlite),
+                    ]
+                    .into_iter()
+                    .all(|store| {
+                        let handler = EventHandler::new(store);
+                        for event in &shuffled {
+                            handler.accept(event.clone()).unwrap();
+                        }
+                        outcome(&handler) == outcome(&in_order)
+                    })
+                })
+        }
+        quickcheck::quickcheck(shuffled_like_in_order as fn(Vec<u32>) -> bool);
+    }
+
+    #[test]
+    fn generated_families_reconcile_in_any_arrival_order() {
+        fn consistent_families_conserve_amounts(seed: u64, keys: Vec<u32>) -> bool {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let shape = FamilyShape::random(&mut rng, 4);
+            let family = EventFamily::generate(&mut rng, "fam", test_timestamp(), &shape);
+            let store = std::sync::Arc::new(InMemoryStore::new());
+            let handler = EventHandler::new(Box::new(store.clone()));
+            for event in shuffled(family.events, &keys) {
+                handler.accept(event).unwrap();
+            }
+            handler.catch_up(&[]).unwrap();
+
+            let report = handler.reconciliation_report().unwrap();
+            let pending = handler.pending_matches().unwrap();
+            let state = store.state();
+            let ordered = state.total(Total::Ordered, Currency::EUR);
+            family.injected.is_empty()
+                && !ordered.is_zero()
+                && ordered == state.total(Total::Collected, Currency::EUR)
+                && report.unreconciled().next().is_none()
+                && pending.is_empty()
+        }
+        quickcheck::quickcheck(consistent_families_conserve_amounts as fn(u64, Vec<u32>) -> bool);
+
+        fn injected_mismatches_are_detected(seed: u64, keys: Vec<u32>) -> bool {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let shape = FamilyShape {
+                mismatches: Mismatch::ALL
+                    .into_iter()
+                    .filter(|_| rng.gen_bool(0.5))
+                    .collect(),
+                ..FamilyShape::random(&mut rng, 4)
+            };
+            let family = EventFamily::generate(&mut rng, "fam", test_timestamp(), &shape);
+            let handler = EventHandler::new(Box::new(InMemoryStore::new()));
+            for event in shuffled(family.events, &keys) {
+                handler.accept(event).unwrap();
+            }
+
+            let report = handler.reconciliation_report().unwrap();
+            family.injected.iter().all(|injected| {
+                report
+                    .get(injected.key, &injected.id)
+                    .is_some_and(|r| !r.status.is_reconciled())
+            }) && (!family.injected.is_empty() || report.unreconciled().next().is_none())
+        }
+        quickcheck::quickcheck(injected_mismatches_are_detected as fn(u64, Vec<u32>) -> bool);
+    }
+
+    #[test]
+    fn config_from_file_and_environment() {
+        let mut config = Config::from_file("spike.example.toml").unwrap();
+        assert_eq!(config, Config::default());
+
+        config = Config::from_toml(
+            r#"
+            [database]
+            url = "host=staging user=spike"
+            pool_size = 4
+            schema = "reconciliation"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.database.url, "host=staging user=spike");
+        assert_eq!(config.database.pool_size, 4);
+        assert_eq!(config.database.connect_timeout_secs, 5);
+
+        let env = std::collections::HashMap::from([
+            ("SPIKE_DATABASE_BACKEND", "sqlite"),
+            ("SPIKE_DATABASE_URL", ":memory:"),
+            ("SPIKE_DATABASE_CONNECT_TIMEOUT_SECS", "1"),
+        ]);
+        config
+            .apply_env(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.database.backend, Backend::Sqlite);
+        assert_eq!(config.database.url, ":memory:");
+        assert_eq!(config.database.connect_timeout_secs, 1);
+        assert_eq!(config.database.schema, "reconciliation");
+
+        assert!(matches!(
+            Config::from_toml("[database]\nschema = \"public; DROP TABLE x\""),
+            Err(ConfigError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            config.apply_env(|name| (name == "SPIKE_DATABASE_POOL_SIZE").then(|| "many".into())),
+            Err(ConfigError::InvalidValue(_))
+        ));
+
+        config = Config::from_toml(
+            r#"
+            [matching.default]
+            absolute = 0.5
+            [matching.event_type.cancellation]
+            percentage = 2.0
+            [matching.insurance_code.PRP1]
+            absolute = 1.0
+            "#,
+        )
+        .unwrap();
+        let matching = &config.matching;
+        assert_eq!(matching.tolerance("issuance", "PRP2").absolute, 0.5);
+        assert_eq!(matching.tolerance("cancellation", "PRP2").percentage, 2.0);
+        assert_eq!(matching.tolerance("cancellation", "PRP1").absolute, 1.0);
+        let config =
+            Config::from_toml("[matching]\nexchange_rates = \"rates.example.toml\"").unwrap();
+        let exchange_rates =
+            ExchangeRates::from_file(config.matching.exchange_rates.unwrap()).unwrap();
+        assert!(exchange_rates
+            .get("USD".parse().unwrap(), Currency::EUR)
+            .is_some());
+        // read from a file, the rate table is found next to it
+        let dir = std::env::temp_dir().join(format!("spike-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("spike.toml"),
+            "[matching]\nexchange_rates = \"rates.toml\"",
+        )
+        .unwrap();
+        let config = Config::from_file(dir.join("spike.toml")).unwrap();
+        assert_eq!(config.matching.exchange_rates, Some(dir.join("rates.toml")));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            Config::from_toml("[matching.default]\npercentage = -1.0"),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn postgres_pool_uses_configured_schema() {
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut database = Config::load().unwrap().database;
+        POOL.get()
+            .unwrap()
+            .batch_execute("CREATE SCHEMA IF NOT EXISTS spike_config_test")
+            .unwrap();
+        database.schema = "spike_config_test".to_owned();
+        database.pool_size = 2;
+
+        let pool = crate::pool::connect(&database).unwrap();
+        let mut client = pool.get().unwrap();
+        crate::pool::reset_db(&mut client);
+        let event_handler = EventHandler::new(Box::new(PostgresStore::new(pool.clone())));
+        for event in happy_path_events() {
+            event_handler.accept(event).unwrap();
+        }
+
+        assert_query(
+            &mut client,
+            r"SELECT COUNT(*) FROM spike_config_test.product_orders WHERE collected_amount = amount",
+            1_i64,
+        );
+        POOL.get()
+            .unwrap()
+            .batch_execute("DROP SCHEMA spike_config_test CASCADE")
+            .unwrap();
+    }
+
+    #[test]
+    fn migrations_are_applied_once_in_order() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let status = store.migration_status().unwrap();
+        assert!(status.iter().all(|m| m.applied_on.is_none()));
+        assert_eq!(
+            status.iter().map(|m| m.version).collect::<Vec<_>>(),
+            crate::storage::migrations::SQLITE
+                .iter()
+                .map(|m| m.version)
+                .collect::<Vec<_>>()
+        );
+
+        let applied = store.migrate().unwrap();
+        assert_eq!(applied.len(), status.len());
+        assert_eq!(store.migration_status().unwrap(), applied);
+        assert!(store.migrate().unwrap().is_empty());
+
+        // the versions known to each backend must stay in step
+        assert_eq!(
+            crate::storage::migrations::POSTGRES
+                .iter()
+                .map(|m| (m.version, m.name))
+                .collect::<Vec<_>>(),
+            crate::storage::migrations::SQLITE
+                .iter()
+                .map(|m| (m.version, m.name))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn failed_event_leaves_no_partial_state() {
+        // the projectors accept the events sent again under new IDs, the reconciliation
+        // engine then fails on the duplicate key and must take the projections down with it
+        let replayed = || {
+            let [Event::ProductOrdered(order), _, _, Event::BankTransactionIssued(transaction)] =
+                happy_path_events()
+            else {
+                unreachable!()
+            };
+            [
+                Event::ProductOrdered(ProductOrderedPayload {
+                    event_id: "evt_5".to_owned(),
+                    ..order
+                }),
+                Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                    event_id: "evt_6".to_owned(),
+                    ..transaction
+                }),
+            ]
+        };
+
+        let memory = std::sync::Arc::new(InMemoryStore::new());
+        let sqlite = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut client = POOL.get().unwrap();
+        crate::pool::reset_db(&mut client);
+
+        for store in [
+            Box::new(memory.clone()) as Box<dyn Store>,
+            Box::new(sqlite.clone()),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in happy_path_events() {
+                event_handler.accept(event).unwrap();
+            }
+            for event in replayed() {
+                assert!(matches!(
+                    event_handler.accept(event),
+                    Err(EventError::ReconcilationEngineError(_))
+                ));
+            }
+            event_handler.catch_up(&[]).unwrap();
+        }
+
+        let state = memory.state();
+        assert_eq!(state.total(Total::Ordered, Currency::EUR), eur(100.0));
+        assert_eq!(state.total(Total::Collected, Currency::EUR), eur(100.0));
+        assert_eq!(state.bank_transactions["tran_1"].ordered_amount, eur(100.0));
+        assert_eq!(state.event_log.len(), 4);
+        assert_eq!(state.processed_events.len(), 4);
+
+        let connection = sqlite.connection();
+        for total in ["total_ordered", "total_collected"] {
+            assert_sqlite_query(
+                &connection,
+                &format!("SELECT SUM(amount) from {total}"),
+                Value::Integer(10_000),
+            );
+        }
+        assert_sqlite_query(
+            &connection,
+            "SELECT ordered_amount FROM bank_transactions",
+            Value::Integer(10_000),
+        );
+
+        for total in ["total_ordered", "total_collected"] {
+            assert_query(
+                &mut client,
+                &format!("SELECT CAST(SUM(amount) as int8) from {total}"),
+                10_000_i64,
+            );
+        }
+        assert_query(
+            &mut client,
+            "SELECT CAST(ordered_amount as int8) FROM bank_transactions",
+            10_000_i64,
+        );
+    }
+
+    #[test]
+    fn redelivered_events_are_skipped() {
+        let memory = std::sync::Arc::new(InMemoryStore::new());
+        let sqlite = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+        sqlite.migrate().unwrap();
+
+        for store in [
+            Box::new(memory.clone()) as Box<dyn Store>,
+            Box::new(sqlite.clone()),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in happy_path_events() {
+                assert_eq!(event_handler.accept(event).unwrap(), Acceptance::Applied);
+            }
+            for event in happy_path_events() {
+                assert_eq!(event_handler.accept(event).unwrap(), Acceptance::Duplicate);
+            }
+            event_handler.catch_up(&[]).unwrap();
+        }
+
+        let state = memory.state();
+        assert_eq!(state.processed_events.len(), 4);
+        assert_eq!(state.total(Total::Ordered, Currency::EUR), eur(100.0));
+        assert_eq!(state.product_orders["ord_1"].collected_amount, eur(100.0));
+
+        let connection = sqlite.connection();
+        assert_sqlite_query(
+            &connection,
+            "SELECT COUNT(*) FROM processed_events",
+            Value::Integer(4),
+        );
+        assert_sqlite_query(
+            &connection,
+            "SELECT SUM(amount) from total_ordered",
+            Value::Integer(10_000),
+        );
+        assert_sqlite_query(
+            &connection,
+            "SELECT collected_amount FROM product_orders",
+            Value::Integer(10_000),
+        );
+    }
+
+    #[test]
+    fn event_log_is_read_by_range_and_business_key() {
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            let events = happy_path_events();
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+            // a redelivery is not logged again
+            event_handler.accept(events[0].clone()).unwrap();
+
+            let logged = event_handler
+                .read_log(EventLogQuery::Range { from: 1, to: 100 })
+                .unwrap();
+            assert_eq!(
+                logged.iter().map(|e| e.event.clone()).collect::<Vec<_>>(),
+                events
+            );
+            assert!(logged.windows(2).all(|w| w[0].sequence < w[1].sequence));
+
+            let range = event_handler
+                .read_log(EventLogQuery::Range {
+                    from: logged[1].sequence,
+                    to: logged[3].sequence,
+                })
+                .unwrap();
+            assert_eq!(range, logged[1..3]);
+
+            let read_key = |key, id| {
+                event_handler
+                    .read_log(EventLogQuery::Key(key, id))
+                    .unwrap()
+                    .into_iter()
+                    .map(|e| e.event.event_type())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                read_key(BusinessKey::Order, "ord_1"),
+                ["product_ordered", "payment_authorized"]
+            );
+            assert_eq!(
+                read_key(BusinessKey::Payment, "pay_1"),
+                ["payment_authorized", "payment_collected"]
+            );
+            assert_eq!(
+                read_key(BusinessKey::Transaction, "tran_1"),
+                ["payment_collected", "bank_transaction_issued"]
+            );
+            assert!(read_key(BusinessKey::Order, "ord_2").is_empty());
+        }
+    }
+
+    #[test]
+    fn projections_are_rebuilt_from_the_event_log() {
+        let store = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+        store.migrate().unwrap();
+        let event_handler = EventHandler::new(Box::new(store.clone()));
+        for event in happy_path_events() {
+            event_handler.accept(event).unwrap();
+        }
+        event_handler.catch_up(&[]).unwrap();
+        let total = |table: &str| {
+            sqlite_rows(
+                &store.connection(),
+                &format!("SELECT '{table}', SUM(amount) FROM {table}"),
+            )[0]
+            .1
+        };
+
+        store
+            .connection()
+            .execute("INSERT INTO total_ordered (amount, occurred_on) VALUES (42, 'bogus')")
+            .unwrap();
+        assert_eq!(
+            event_handler.rebuild(&["total_ordered"], false).unwrap(),
+            [("total_ordered", 4)]
+        );
+        assert_eq!(total("total_ordered"), Some(10_000));
+
+        // a rebuild that crashed after emptying the projection and replaying nothing:
+        // events accepted meanwhile are left to the replay
+        store
+            .connection()
+            .execute(
+                "DELETE FROM total_authorized;
+                UPDATE projector_checkpoints SET sequence=0 WHERE projector='total_authorized'",
+            )
+            .unwrap();
+        event_handler
+            .accept(authorized(5, "ord_2", "pay_2", 50.0))
+            .unwrap();
+        assert_eq!(total("total_authorized"), None);
+
+        assert_eq!(
+            event_handler.rebuild(&["total_authorized"], true).unwrap(),
+            [("total_authorized", 5)]
+        );
+        assert_eq!(total("total_authorized"), Some(15_000));
+        assert_eq!(total("total_ordered"), Some(10_000));
+        assert_eq!(total("total_collected"), Some(10_000));
+
+        // the other projectors catch up with the event accepted meanwhile
+        assert_eq!(
+            event_handler.rebuild(&[], true).unwrap(),
+            [
+                ("total_ordered", 1),
+                ("total_authorized", 0),
+                ("total_collected", 1)
+            ]
+        );
+        assert!(event_handler.lag().unwrap().iter().all(|l| l.lag == 0));
+        assert_eq!(total("total_authorized"), Some(15_000));
+        assert_eq!(total("total_ordered"), Some(10_000));
+        assert_eq!(total("total_collected"), Some(10_000));
+        assert_eq!(
+            event_handler
+                .rebuild(&[], true)
+                .unwrap()
+                .iter()
+                .map(|(_, replayed)| replayed)
+                .sum::<usize>(),
+            0
+        );
+        assert!(matches!(
+            event_handler.rebuild(&["total_refunded"], false),
+            Err(EventError::ProjectionError(_))
+        ));
+    }
+
+    #[test]
+    fn projectors_catch_up_without_blocking_ingestion() {
+        struct Failing;
+        impl Projector for Failing {
+            fn name(&self) -> &'static str {
+                "failing"
+            }
+            fn project(&self, _: &mut dyn Session, event: Event) -> Result<(), String> {
+                match event {
+                    Event::PaymentCollected(_) => Err("cannot project".to_owned()),
+                    _ => Ok(()),
+                }
+            }
+            fn reset(&self, _: &mut dyn Session) -> Result<(), String> {
+                Ok(())
+            }
+        }
+
+        struct Counting(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+        impl Projector for Counting {
+            fn name(&self) -> &'static str {
+                "counting"
+            }
+            fn project(&self, _: &mut dyn Session, _: Event) -> Result<(), String> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+            fn reset(&self, _: &mut dyn Session) -> Result<(), String> {
+                self.0.store(0, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let store = std::sync::Arc::new(InMemoryStore::new());
+        let event_handler =
+            EventHandler::new(Box::new(store.clone())).with_projector(Box::new(Failing));
+        for event in happy_path_events() {
+            assert_eq!(event_handler.accept(event).unwrap(), Acceptance::Applied);
+        }
+        assert!(event_handler.lag().unwrap().iter().all(|l| l.lag == 4));
+        // the failing projector does not hold the others back
+        assert!(matches!(
+            event_handler.catch_up(&[]),
+            Err(EventError::ProjectionError(_))
+        ));
+        assert_eq!(
+            store.state().total(Total::Ordered, Currency::EUR),
+            eur(100.0)
+        );
+        assert_eq!(
+            store.state().total(Total::Collected, Currency::EUR),
+            eur(100.0)
+        );
+
+        let lag = event_handler.lag().unwrap();
+        assert_eq!(
+            lag.iter()
+                .map(|l| (l.projector, l.checkpoint, l.lag))
+                .collect::<Vec<_>>(),
+            [
+                ("total_ordered", 4, 0),
+                ("total_authorized", 4, 0),
+                ("total_collected", 4, 0),
+                // the batch it failed in is rolled back
+                ("failing", 0, 4),
+            ]
+        );
+        assert!(lag[..3].iter().all(|l| l.last_error.is_none()));
+        assert!(lag[3]
+            .last_error
+            .as_deref()
+            .is_some_and(|e| e.contains("failing at sequence 3")));
+
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let event_handler = EventHandler::new(Box::new(store.clone()))
+            .with_projector(Box::new(Counting(counter.clone())));
+        assert_eq!(event_handler.lag().unwrap()[3].lag, 4);
+        assert_eq!(
+            event_handler.catch_up(&["counting"]).unwrap(),
+            [("counting", 4)]
+        );
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert_eq!(event_handler.lag().unwrap()[3].lag, 0);
+    }
+
+    #[test]
+    fn split_payments_are_allocated_across_orders_and_transactions() {
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in split_payment_events().into_iter().rev() {
+                event_handler.accept(event).unwrap();
+            }
+
+            assert_eq!(
+                event_handler
+                    .order_collections()
+                    .unwrap()
+                    .into_iter()
+                    .map(|o| (
+                        o.order_id,
+                        o.collected.to_major(),
+                        o.outstanding.to_major(),
+                        o.status
+                    ))
+                    .collect::<Vec<_>>(),
+                [
+                    ("ord_1".to_owned(), 200.0, 100.0, CollectionStatus::Partial),
+                    ("ord_2".to_owned(), 80.0, -30.0, CollectionStatus::Over),
+                    ("ord_3".to_owned(), 100.0, 0.0, CollectionStatus::Full),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn payment_shared_by_orders_is_split_pro_rata() {
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+
+        // pay_1 is authorized 2:1 for ord_1 and ord_2, and collected in two bank
+        // transactions, the odd cent going to the largest remainder
+        let events = vec![
+            ordered(1, "ord_1", 200.0),
+            ordered(2, "ord_2", 100.0),
+            authorized(3, "ord_1", "pay_1", 200.0),
+            authorized(4, "ord_2", "pay_1", 100.0),
+            collected(5, "pay_1", "tran_1", 200.0),
+            collected(6, "pay_1", "tran_2", 100.0),
+            issued(7, "tran_1", 200.0),
+            issued(8, "tran_2", 100.0),
+            collected(9, "pay_1", "tran_3", 0.01),
+        ];
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let store = std::sync::Arc::<dyn Store>::from(store);
+            let event_handler = EventHandler::new(Box::new(store.clone()));
+            for event in events.iter().rev() {
+                event_handler.accept(event.clone()).unwrap();
+            }
+
+            assert_eq!(
+                event_handler
+                    .order_collections()
+                    .unwrap()
+                    .into_iter()
+                    .map(|o| (o.order_id, o.collected.to_major(), o.status))
+                    .collect::<Vec<_>>(),
+                [
+                    ("ord_1".to_owned(), 200.0, CollectionStatus::Full),
+                    ("ord_2".to_owned(), 100.0, CollectionStatus::Full),
+                ]
+            );
+            let report = event_handler.reconciliation_report().unwrap();
+            assert_eq!(
+                statuses(&report.orders),
+                [
+                    ("ord_1", ReconciliationStatus::Matched, 200.0, 200.0),
+                    ("ord_2", ReconciliationStatus::Matched, 100.0, 100.0),
+                ]
+            );
+            assert_eq!(
+                statuses(&report.transactions),
+                [
+                    ("tran_1", ReconciliationStatus::Matched, 200.0, 200.0),
+                    ("tran_2", ReconciliationStatus::Matched, 100.0, 100.0),
+                ]
+            );
+            let mut session = store.session().unwrap();
+            assert_eq!(
+                session
+                    .facts()
+                    .unwrap()
+                    .allocations
+                    .into_iter()
+                    .filter(|a| a.transaction_id == "tran_3")
+                    .map(|a| (a.order_id, a.amount))
+                    .collect::<Vec<_>>(),
+                [
+                    ("ord_1".to_owned(), eur(0.01)),
+                    ("ord_2".to_owned(), eur(0.0))
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn cancellations_and_interruptions_adjust_the_expected_amount() {
+        let adjusting = |event: usize, order_id: &str, original: &str, event_type, amount, on| {
+            let Event::ProductOrdered(order) = ordered(event, order_id, amount) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_type,
+                occurred_on: chrono::DateTime::from_str(on).unwrap(),
+                original_order_id: Some(original.to_owned()),
+                ..order
+            })
+        };
+        let events = [
+            // monthly installments of 100 from 2023-02-20: the first is due, the
+            // second covered 16 of its 31 days
+            ordered(1, "ord_1", 1200.0),
+            adjusting(
+                2,
+                "ord_1_int",
+                "ord_1",
+                EventType::Interruption,
+                0.0,
+                "2023-04-05T10:00:00Z",
+            ),
+            authorized(3, "ord_1", "pay_1", 151.61),
+            collected(4, "pay_1", "tran_1", 151.61),
+            issued(5, "tran_1", 151.61),
+            // reversed
+            ordered(6, "ord_2", 300.0),
+            adjusting(
+                7,
+                "ord_2_can",
+                "ord_2",
+                EventType::Cancellation,
+                300.0,
+                "2023-03-01T10:00:00Z",
+            ),
+            // credited, before the order arrives
+            adjusting(
+                8,
+                "ord_3_can",
+                "ord_3",
+                EventType::Cancellation,
+                200.0,
+                "2023-03-01T10:00:00Z",
+            ),
+            ordered(9, "ord_3", 500.0),
+            authorized(10, "ord_3", "pay_3", 300.0),
+            collected(11, "pay_3", "tran_3", 300.0),
+            issued(12, "tran_3", 300.0),
+            adjusting(
+                13,
+                "ord_9_can",
+                "ord_9",
+                EventType::Cancellation,
+                50.0,
+                "2023-03-01T10:00:00Z",
+            ),
+        ];
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+
+            let report = event_handler.reconciliation_report().unwrap();
+            assert_eq!(
+                statuses(&report.orders),
+                [
+                    ("ord_1", ReconciliationStatus::Matched, 151.61, 151.61),
+                    ("ord_2", ReconciliationStatus::Matched, 0.0, 0.0),
+                    ("ord_3", ReconciliationStatus::Matched, 300.0, 300.0),
+                    ("ord_9_can", ReconciliationStatus::Orphan, 50.0, 0.0),
+                ]
+            );
+            assert_eq!(
+                event_handler
+                    .order_collections()
+                    .unwrap()
+                    .into_iter()
+                    .map(|o| (o.order_id, o.expected.to_major(), o.status))
+                    .collect::<Vec<_>>(),
+                [
+                    ("ord_1".to_owned(), 151.61, CollectionStatus::Full),
+                    ("ord_2".to_owned(), 0.0, CollectionStatus::Full),
+                    ("ord_3".to_owned(), 300.0, CollectionStatus::Full),
+                ]
+            );
+            assert_eq!(
+                event_handler.pending_matches().unwrap(),
+                [PendingMatch {
+                    awaited: (BusinessKey::Order, "ord_9".to_owned()),
+                    key: (BusinessKey::Order, "ord_9_can".to_owned()),
+                }]
+            );
+        }
+
+        let issuance = r#"{"type": "product_ordered", "event_id": "evt_1", "order_id": "ord_1",
+            "amount": 10, "event_type": "issuance", "installment_type": "yearly",
+            "guarantees": [], "occurred_on": "2023-02-20T10:00:00Z", "insurance_code": "PRP1",
+            "original_order_id": "ord_0"}"#;
+        assert!(Event::from_json(issuance)
+            .unwrap_err()
+            .to_string()
+            .contains("refer to an original order"));
+    }
+
+    #[test]
+    fn refunds_and_chargebacks_are_netted_out() {
+        let cancelled = |event: usize, order_id: &str, original: &str, amount| {
+            let Event::ProductOrdered(order) = ordered(event, order_id, amount) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_type: EventType::Cancellation,
+                original_order_id: Some(original.to_owned()),
+                ..order
+            })
+        };
+        let events = [
+            ordered(1, "ord_1", 100.0),
+            authorized(2, "ord_1", "pay_1", 100.0),
+            collected(3, "pay_1", "tran_1", 100.0),
+            issued(4, "tran_1", 100.0),
+            cancelled(5, "ord_1_can", "ord_1", 100.0),
+            // paid back by a reversal, refunded before it is issued
+            Event::PaymentRefunded(PaymentRefundedPayload {
+                event_id: "evt_6".to_owned(),
+                payment_id: "pay_1".to_owned(),
+                transaction_id: "tran_1_rev".to_owned(),
+                amount: eur(100.0),
+                occurred_on: test_timestamp(),
+            }),
+            issued(7, "tran_1_rev", -100.0),
+            ordered(8, "ord_2", 200.0),
+            authorized(9, "ord_2", "pay_2", 200.0),
+            collected(10, "pay_2", "tran_2", 200.0),
+            issued(11, "tran_2", 200.0),
+            Event::ChargebackReceived(ChargebackReceivedPayload {
+                event_id: "evt_12".to_owned(),
+                payment_id: "pay_2".to_owned(),
+                transaction_id: "tran_2_cb".to_owned(),
+                amount: eur(50.0),
+                occurred_on: test_timestamp(),
+            }),
+            issued(13, "tran_2_cb", -50.0),
+        ];
+
+        let memory = std::sync::Arc::new(InMemoryStore::new());
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+        for store in [
+            Box::new(memory.clone()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let store = std::sync::Arc::<dyn Store>::from(store);
+            let event_handler = EventHandler::new(Box::new(store.clone()));
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+            event_handler.catch_up(&[]).unwrap();
+
+            // the chargeback is linked to the order by a relations row of its own
+            assert_eq!(
+                store
+                    .session()
+                    .unwrap()
+                    .facts()
+                    .unwrap()
+                    .allocations
+                    .into_iter()
+                    .filter(|a| a.payment_id == "pay_2")
+                    .map(|a| (a.kind, a.order_id, a.transaction_id, a.amount))
+                    .collect::<Vec<_>>(),
+                [
+                    (
+                        "collection".to_owned(),
+                        "ord_2".to_owned(),
+                        "tran_2".to_owned(),
+                        eur(200.0)
+                    ),
+                    (
+                        "chargeback".to_owned(),
+                        "ord_2".to_owned(),
+                        "tran_2_cb".to_owned(),
+                        eur(-50.0)
+                    ),
+                ]
+            );
+
+            let report = event_handler.reconciliation_report().unwrap();
+            assert_eq!(
+                statuses(&report.orders),
+                [
+                    ("ord_1", ReconciliationStatus::Matched, 0.0, 0.0),
+                    ("ord_2", ReconciliationStatus::AmountMismatch, 200.0, 150.0),
+                ]
+            );
+            assert_eq!(
+                statuses(&report.payments),
+                [
+                    ("pay_1", ReconciliationStatus::Matched, 0.0, 0.0),
+                    ("pay_2", ReconciliationStatus::Matched, 150.0, 150.0),
+                ]
+            );
+            assert_eq!(
+                statuses(&report.transactions),
+                [
+                    ("tran_1", ReconciliationStatus::Matched, 100.0, 100.0),
+                    ("tran_1_rev", ReconciliationStatus::Matched, -100.0, -100.0),
+                    ("tran_2", ReconciliationStatus::Matched, 200.0, 200.0),
+                    ("tran_2_cb", ReconciliationStatus::Matched, -50.0, -50.0),
+                ]
+            );
+            assert_eq!(
+                event_handler
+                    .order_collections()
+                    .unwrap()
+                    .into_iter()
+                    .map(|o| (o.order_id, o.collected.to_major(), o.status))
+                    .collect::<Vec<_>>(),
+                [
+                    ("ord_1".to_owned(), 0.0, CollectionStatus::Full),
+                    ("ord_2".to_owned(), 150.0, CollectionStatus::Partial),
+                ]
+            );
+            assert!(event_handler.pending_matches().unwrap().is_empty());
+        }
+
+        let state = memory.state();
+        assert_eq!(state.total(Total::Authorized, Currency::EUR), eur(300.0));
+        assert_eq!(state.total(Total::Collected, Currency::EUR), eur(150.0));
+        drop(state);
+
+        let refund = r#"{"type": "payment_refunded", "event_id": "evt_1", "payment_id": "pay_1",
+            "transaction_id": "tran_1", "amount": "12.50", "currency": "EUR",
+            "occurred_on": "2023-02-20T10:00:00Z"}"#;
+        let event = Event::from_json(refund).unwrap();
+        assert_eq!(event.event_type(), "payment_refunded");
+        assert_eq!(Event::from_json(&event.to_json().unwrap()).unwrap(), event);
+    }
+
+    #[test]
+    fn reversals_are_netted_out_once() {
+        let store = std::sync::Arc::new(InMemoryStore::new());
+        let event_handler = EventHandler::new(Box::new(store.clone()));
+        for event in [
+            ordered(1, "ord_1", 100.0),
+            authorized(2, "ord_1", "pay_1", 100.0),
+            collected(3, "pay_1", "tran_1", 100.0),
+            issued(4, "tran_1", 100.0),
+            // a reversal without any refund or chargeback
+            issued(5, "tran_1_rev", -30.0),
+            // issued before the chargeback it pays back
+            issued(6, "tran_1_cb", -20.0),
+            Event::ChargebackReceived(ChargebackReceivedPayload {
+                event_id: "evt_7".to_owned(),
+                payment_id: "pay_1".to_owned(),
+                transaction_id: "tran_1_cb".to_owned(),
+                amount: eur(20.0),
+                occurred_on: test_timestamp(),
+            }),
+        ] {
+            event_handler.accept(event).unwrap();
+        }
+        event_handler.catch_up(&[]).unwrap();
+        assert_eq!(
+            store.state().total(Total::Collected, Currency::EUR),
+            eur(50.0)
+        );
+
+        event_handler.rebuild(&["total_collected"], false).unwrap();
+        assert_eq!(
+            store.state().total(Total::Collected, Currency::EUR),
+            eur(50.0)
+        );
+    }
+
+    #[test]
+    fn collected_money_is_allocated_across_guarantees() {
+        let guaranteed = |event: usize, order_id: &str, guarantees: &[(&str, f64)]| {
+            let Event::ProductOrdered(order) = ordered(
+                event,
+                order_id,
+                guarantees.iter().map(|(_, price)| price).sum(),
+            ) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                guarantees: guarantees
+                    .iter()
+                    .map(|(guarantee_type, price)| Guarantee {
+                        guarantee_type: guarantee_type.to_string(),
+                        price: eur(*price),
+                    })
+                    .collect(),
+                ..order
+            })
+        };
+        let events = [
+            guaranteed(1, "ord_1", &[("rca", 300.0), ("infortuni", 19.32)]),
+            authorized(2, "ord_1", "pay_1", 100.0),
+            collected(3, "pay_1", "tran_1", 100.0),
+            issued(4, "tran_1", 100.0),
+            guaranteed(5, "ord_2", &[("rca", 150.0), ("infortuni", 50.0)]),
+            authorized(6, "ord_2", "pay_2", 200.0),
+            collected(7, "pay_2", "tran_2", 200.0),
+            issued(8, "tran_2", 200.0),
+            ordered(9, "ord_3", 10.0),
+        ];
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+
+            let collections = event_handler.guarantee_collections().unwrap();
+            assert_eq!(
+                collections
+                    .iter()
+                    .map(|g| (
+                        g.order_id.as_str(),
+                        g.guarantee_type.as_str(),
+                        g.price.to_major(),
+                        g.collected.to_major()
+                    ))
+                    .collect::<Vec<_>>(),
+                [
+                    // 100 split 93.9496 to 6.0504, the cent left over to the larger remainder
+                    ("ord_1", "infortuni", 19.32, 6.05),
+                    ("ord_1", "rca", 300.0, 93.95),
+                    ("ord_2", "infortuni", 50.0, 50.0),
+                    ("ord_2", "rca", 150.0, 150.0),
+                ]
+            );
+            assert_eq!(
+                guarantee_totals(&collections),
+                [
+                    GuaranteeTotal {
+                        guarantee_type: "infortuni".to_owned(),
+                        price: eur(69.32),
+                        collected: eur(56.05),
+                    },
+                    GuaranteeTotal {
+                        guarantee_type: "rca".to_owned(),
+                        price: eur(450.0),
+                        collected: eur(243.95),
+                    },
+                ]
+            );
+        }
+
+        let order = |guarantees: &str| {
+            format!(
+                r#"{{"type": "product_ordered", "event_id": "evt_1", "order_id": "ord_1",
+                "amount": 100, "event_type": "issuance", "installment_type": "yearly",
+                "guarantees": {guarantees}, "occurred_on": "2023-02-20T10:00:00Z",
+                "insurance_code": "PRP1"}}"#
+            )
+        };
+        assert!(Event::from_json(&order(
+            r#"[{"type": "rca", "price": 60}, {"type": "infortuni", "price": 40}]"#
+        ))
+        .is_ok());
+        // stored as is, and reported once reconciled
+        let mispriced = Event::from_json(&order(r#"[{"type": "rca", "price": 60}]"#)).unwrap();
+        let event_handler = EventHandler::new(Box::new(InMemoryStore::new()));
+        event_handler.accept(mispriced).unwrap();
+        assert_eq!(
+            statuses(&event_handler.reconciliation_report().unwrap().orders),
+            [("ord_1", ReconciliationStatus::GuaranteeMismatch, 100.0, 0.0)]
+        );
+        assert!(Event::from_json(&order(
+            r#"[{"type": "rca", "price": 60}, {"type": "rca", "price": 40}]"#
+        ))
+        .unwrap_err()
+        .to_string()
+        .contains("`rca` is listed twice"));
+    }
+
+    #[test]
+    fn payments_are_matched_to_installments() {
+        let adjusting = |event: usize, order_id: &str, original: &str, event_type, amount| {
+            let Event::ProductOrdered(order) = ordered(event, order_id, amount) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_type,
+                occurred_on: chrono::DateTime::from_str("2023-04-05T10:00:00Z").unwrap(),
+                original_order_id: Some(original.to_owned()),
+                ..order
+            })
+        };
+        let yearly = {
+            let Event::ProductOrdered(order) = ordered(1, "ord_2", 300.0) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                installment_type: InstallmentType::Yearly,
+                ..order
+            })
+        };
+        let events = [
+            yearly,
+            // monthly installments of 100 from 2023-02-20
+            ordered(2, "ord_1", 1200.0),
+            authorized(3, "ord_1", "pay_1", 250.0),
+            collected(4, "pay_1", "tran_1", 250.0),
+            authorized(5, "ord_1", "pay_1b", 100.0),
+            collected(6, "pay_1b", "tran_1", 100.0),
+            issued(7, "tran_1", 350.0),
+            // the second installment covered 16 of its 31 days
+            ordered(8, "ord_3", 1200.0),
+            adjusting(9, "ord_3_int", "ord_3", EventType::Interruption, 0.0),
+            ordered(10, "ord_4", 1200.0),
+            adjusting(11, "ord_4_can", "ord_4", EventType::Cancellation, 1200.0),
+        ];
+        let on = |date: &str| chrono::DateTime::from_str(date).unwrap();
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+
+            let installments = event_handler.installments().unwrap();
+            let of = |order_id: &str| {
+                installments
+                    .iter()
+                    .filter(|i| i.order_id == order_id)
+                    .map(|i| {
+                        (
+                            i.due_on.date_naive().to_string(),
+                            i.amount.to_major(),
+                            i.paid.to_major(),
+                            i.payment_ids.join(","),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let paid = |due_on: &str, amount, paid, payment_ids: &str| {
+                (due_on.to_owned(), amount, paid, payment_ids.to_owned())
+            };
+            assert_eq!(of("ord_1").len(), 12);
+            assert_eq!(
+                of("ord_1")[..5],
+                [
+                    paid("2023-02-20", 100.0, 100.0, "pay_1"),
+                    paid("2023-03-20", 100.0, 100.0, "pay_1"),
+                    paid("2023-04-20", 100.0, 100.0, "pay_1,pay_1b"),
+                    paid("2023-05-20", 100.0, 50.0, "pay_1b"),
+                    paid("2023-06-20", 100.0, 0.0, ""),
+                ]
+            );
+            assert_eq!(of("ord_2"), [paid("2023-02-20", 300.0, 0.0, "")]);
+            assert_eq!(
+                of("ord_3"),
+                [
+                    paid("2023-02-20", 100.0, 0.0, ""),
+                    paid("2023-03-20", 51.61, 0.0, ""),
+                ]
+            );
+            assert!(of("ord_4").iter().all(|(_, amount, _, _)| *amount == 0.0));
+
+            let overdue = |as_of: &str| {
+                event_handler
+                    .overdue_installments(on(as_of))
+                    .unwrap()
+                    .into_iter()
+                    .map(|i| (i.order_id.clone(), i.number, i.outstanding().to_major()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                overdue("2023-05-01T00:00:00Z"),
+                [
+                    ("ord_2".to_owned(), 1, 300.0),
+                    ("ord_3".to_owned(), 1, 100.0),
+                    ("ord_3".to_owned(), 2, 51.61),
+                ]
+            );
+            assert_eq!(
+                overdue("2023-06-01T00:00:00Z")[..2],
+                [
+                    ("ord_1".to_owned(), 4, 50.0),
+                    ("ord_2".to_owned(), 1, 300.0)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn aging_report_buckets_unreconciled_items() {
+        let on = |event: Event, date: &str| {
+            let occurred_on = chrono::DateTime::from_str(date).unwrap();
+            match event {
+                Event::ProductOrdered(p) => {
+                    Event::ProductOrdered(ProductOrderedPayload { occurred_on, ..p })
+                }
+                Event::PaymentAuthorized(p) => {
+                    Event::PaymentAuthorized(PaymentAuthorizedPayload { occurred_on, ..p })
+                }
+                Event::PaymentCollected(p) => {
+                    Event::PaymentCollected(PaymentCollectedPayload { occurred_on, ..p })
+                }
+                Event::BankTransactionIssued(p) => {
+                    Event::BankTransactionIssued(BankTransactionIssuedPayload { occurred_on, ..p })
+                }
+                event => event,
+            }
+        };
+        let events = [
+            ordered(1, "ord_1", 100.0),
+            on(
+                authorized(2, "ord_1", "pay_1", 100.0),
+                "2023-03-28T10:00:00Z",
+            ),
+            on(
+                collected(3, "pay_1", "tran_1", 100.0),
+                "2023-03-31T00:00:00Z",
+            ),
+            on(
+                collected(4, "pay_8", "tran_8", 20.0),
+                "2023-03-31T05:00:00Z",
+            ),
+            on(issued(5, "tran_9", 50.0), "2023-03-15T10:00:00Z"),
+            ordered(6, "ord_2", 10.0),
+            authorized(7, "ord_2", "pay_2", 10.0),
+            collected(8, "pay_2", "tran_2", 10.0),
+            issued(9, "tran_2", 10.0),
+            // paid back by a bank transaction not received yet
+            Event::PaymentRefunded(PaymentRefundedPayload {
+                event_id: "evt_10".to_owned(),
+                payment_id: "pay_2".to_owned(),
+                transaction_id: "tran_2_rev".to_owned(),
+                amount: eur(4.0),
+                occurred_on: chrono::DateTime::from_str("2023-03-30T12:00:00Z").unwrap(),
+            }),
+            Event::ChargebackReceived(ChargebackReceivedPayload {
+                event_id: "evt_11".to_owned(),
+                payment_id: "pay_7".to_owned(),
+                transaction_id: "tran_7".to_owned(),
+                amount: eur(30.0),
+                occurred_on: chrono::DateTime::from_str("2023-03-20T10:00:00Z").unwrap(),
+            }),
+        ];
+        let event_handler = EventHandler::new(Box::new(InMemoryStore::new()));
+        for event in events {
+            event_handler.accept(event).unwrap();
+        }
+        let as_of = chrono::DateTime::from_str("2023-03-31T10:00:00Z").unwrap();
+
+        let report = event_handler
+            .aging_report(as_of, &AgingFilter::default())
+            .unwrap();
+        assert_eq!(
+            report
+                .buckets
+                .iter()
+                .map(|bucket| (
+                    bucket.bucket,
+                    bucket
+                        .items
+                        .iter()
+                        .map(|i| (i.kind, i.id.as_str(), i.status))
+                        .collect::<Vec<_>>(),
+                    bucket.totals.clone()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    AgeBucket::Day,
+                    vec![
+                        (
+                            AgingKind::Collection,
+                            "pay_1/tran_1",
+                            ReconciliationStatus::MissingBankTransaction
+                        ),
+                        (
+                            AgingKind::Collection,
+                            "pay_8/tran_8",
+                            ReconciliationStatus::Orphan
+                        ),
+                        (
+                            AgingKind::Refund,
+                            "pay_2/tran_2_rev",
+                            ReconciliationStatus::MissingBankTransaction
+                        ),
+                    ],
+                    vec![eur(124.0)]
+                ),
+                (
+                    AgeBucket::Week,
+                    vec![(
+                        AgingKind::Authorization,
+                        "pay_1",
+                        ReconciliationStatus::MissingBankTransaction
+                    )],
+                    vec![eur(100.0)]
+                ),
+                (
+                    AgeBucket::Month,
+                    vec![
+                        (
+                            AgingKind::Chargeback,
+                            "pay_7/tran_7",
+                            ReconciliationStatus::Orphan
+                        ),
+                        (
+                            AgingKind::BankTransaction,
+                            "tran_9",
+                            ReconciliationStatus::Orphan
+                        ),
+                    ],
+                    vec![eur(80.0)]
+                ),
+                (
+                    AgeBucket::Older,
+                    vec![
+                        (
+                            AgingKind::Order,
+                            "ord_1",
+                            ReconciliationStatus::MissingBankTransaction
+                        ),
+                        (
+                            AgingKind::Authorization,
+                            "pay_2",
+                            ReconciliationStatus::MissingBankTransaction
+                        ),
+                    ],
+                    vec![eur(110.0)]
+                ),
+            ]
+        );
+        assert!(report
+            .to_string()
+            .contains("30+ days: 2 item(s), 110.00 EUR"));
+
+        let filtered = |filter: AgingFilter| {
+            event_handler
+                .aging_report(as_of, &filter)
+                .unwrap()
+                .items()
+                .map(|i| i.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            filtered(AgingFilter {
+                event_type: Some(EventType::Issuance),
+                insurance_code: Some("PRP1".to_owned()),
+            }),
+            [
+                "pay_1/tran_1",
+                "pay_2/tran_2_rev",
+                "pay_1",
+                "ord_1",
+                "pay_2"
+            ]
+        );
+        assert!(filtered(AgingFilter {
+            event_type: Some(EventType::Cancellation),
+            insurance_code: None,
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn aging_report_fails_on_overflowing_totals() {
+        let event_handler = EventHandler::new(Box::new(InMemoryStore::new()));
+        for (event, transaction_id, minor_units) in [(1, "tran_1", i64::MAX), (2, "tran_2", 1)] {
+            event_handler
+                .accept(Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                    event_id: format!("evt_{event}"),
+                    transaction_id: transaction_id.to_owned(),
+                    amount: Money::new(minor_units, Currency::EUR),
+                    occurred_on: test_timestamp(),
+                }))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            event_handler.aging_report(test_timestamp(), &AgingFilter::default()),
+            Err(EventError::ReconcilationEngineError(_))
+        ));
+    }
+
+    #[test]
+    fn orders_are_exported_as_csv_and_json_lines() {
+        let later = {
+            let Event::ProductOrdered(order) = ordered(15, "ord_4", 20.0) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                occurred_on: chrono::DateTime::from_str("2023-03-01T00:00:00Z").unwrap(),
+                insurance_code: "PRP,4".to_owned(),
+                ..order
+            })
+        };
+        let events = split_payment_events().into_iter().chain([later]);
+        let export = |event_handler: &EventHandler, range, format| {
+            let orders = event_handler.order_exports(&range).unwrap();
+            let mut out = vec![];
+            crate::export::write(&mut out, &orders, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+
+            assert_eq!(
+                export(&event_handler, DateRange::default(), ExportFormat::Csv),
+                "order_id,payment_ids,transaction_ids,expected,collected,ordered,currency,status,insurance_code
+ord_1,pay_1;pay_2,tran_1;tran_2,300.00 EUR,200.00 EUR,150.00 EUR;230.00 EUR,EUR,partial,PRP1
+ord_2,pay_3,tran_2,50.00 EUR,80.00 EUR,230.00 EUR,EUR,over,PRP1
+ord_3,pay_4,tran_2,100.00 EUR,100.00 EUR,230.00 EUR,EUR,full,PRP1
+ord_4,,,20.00 EUR,0.00 EUR,,EUR,partial,\"PRP,4\"
+"
+            );
+            let range = DateRange {
+                from: Some(test_timestamp()),
+                to: Some(chrono::DateTime::from_str("2023-03-01T00:00:00Z").unwrap()),
+            };
+            let json_lines = export(&event_handler, range, ExportFormat::JsonLines);
+            assert_eq!(json_lines.lines().count(), 3);
+            assert_eq!(
+                json_lines.lines().next().unwrap(),
+                r#"{"order_id":"ord_1","payment_ids":["pay_1","pay_2"],"transaction_ids":["tran_1","tran_2"],"expected":"300.00","collected":"200.00","ordered":["150.00","230.00"],"currency":"EUR","status":"partial","insurance_code":"PRP1"}"#
+            );
+            let later = DateRange {
+                from: Some(test_timestamp() + chrono::Duration::seconds(1)),
+                to: None,
+            };
+            assert_eq!(
+                export(&event_handler, later, ExportFormat::JsonLines)
+                    .lines()
+                    .count(),
+                1
+            );
+        }
+        assert_eq!("jsonl".parse(), Ok(ExportFormat::JsonLines));
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn reconciliation_report_types_every_discrepancy() {
+        use ReconciliationStatus::*;
+        let events = [
+            // matched all the way
+            ordered(1, "ord_1", 100.0),
+            authorized(2, "ord_1", "pay_1", 100.0),
+            collected(3, "pay_1", "tran_1", 100.0),
+            issued(4, "tran_1", 100.0),
+            // nothing authorized
+            ordered(5, "ord_2", 200.0),
+            // authorized, never collected
+            ordered(6, "ord_3", 50.0),
+            authorized(7, "ord_3", "pay_3", 50.0),
+            // collected, bank transaction not received
+            ordered(8, "ord_4", 70.0),
+            authorized(9, "ord_4", "pay_4", 70.0),
+            collected(10, "pay_4", "tran_4", 70.0),
+            // collected less than authorized
+            ordered(11, "ord_5", 90.0),
+            authorized(12, "ord_5", "pay_5", 90.0),
+            collected(13, "pay_5", "tran_5", 80.0),
+            issued(14, "tran_5", 80.0),
+            // authorized for an order never received
+            authorized(15, "ord_6", "pay_6", 10.0),
+            // a bank transaction nothing refers to
+            issued(16, "tran_7", 40.0),
+            // collected without any authorization
+            collected(17, "pay_8", "tran_8", 25.0),
+            issued(18, "tran_8", 25.0),
+        ];
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        let _lock = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::pool::reset_db(&mut POOL.get().unwrap());
+
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+            postgres_store(),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+            let report = event_handler.reconciliation_report().unwrap();
+            assert_eq!(
+                statuses(&report.orders),
+                [
+                    ("ord_1", Matched, 100.0, 100.0),
+                    ("ord_2", MissingPayment, 200.0, 0.0),
+                    ("ord_3", MissingCollection, 50.0, 0.0),
+                    ("ord_4", MissingBankTransaction, 70.0, 0.0),
+                    ("ord_5", AmountMismatch, 90.0, 80.0),
+                ]
+            );
+            assert_eq!(
+                statuses(&report.payments),
+                [
+                    ("pay_1", Matched, 100.0, 100.0),
+                    ("pay_3", MissingCollection, 50.0, 0.0),
+                    ("pay_4", MissingBankTransaction, 70.0, 0.0),
+                    ("pay_5", AmountMismatch, 90.0, 80.0),
+                    ("pay_6", Orphan, 10.0, 0.0),
+                    ("pay_8", Orphan, 0.0, 25.0),
+                ]
+            );
+            assert_eq!(
+                statuses(&report.transactions),
+                [
+                    ("tran_1", Matched, 100.0, 100.0),
+                    ("tran_5", Matched, 80.0, 80.0),
+                    ("tran_7", Orphan, 40.0, 0.0),
+                    ("tran_8", MissingPayment, 25.0, 0.0),
+                ]
+            );
+            assert_eq!(
+                report.get(BusinessKey::Order, "ord_4").map(|r| r.status),
+                Some(MissingBankTransaction)
+            );
+            assert_eq!(report.unreconciled().count(), 11);
+        }
+    }
+
+    #[test]
+    fn amounts_match_within_configured_tolerances() {
+        use ReconciliationStatus::*;
+        let matching = Config::from_toml(
+            r#"
+            [matching.default]
+            absolute = 1.0
+            [matching.event_type.interruption]
+            percentage = 5.0
+            [matching.insurance_code.PRP9]
+            absolute = 0.0
+            "#,
+        )
+        .unwrap()
+        .matching;
+        let interruption = |event: usize, order_id: &str, insurance_code: &str| {
+            let Event::ProductOrdered(payload) = ordered(event, order_id, 100.0) else {
+                unreachable!()
+            };
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_type: EventType::Interruption,
+                insurance_code: insurance_code.to_owned(),
+                original_order_id: None,
+                ..payload
+            })
+        };
+        let events = [
+            // equal once rounded to the cent
+            ordered(1, "ord_1", 319.32),
+            authorized(2, "ord_1", "pay_1a", 300.0),
+            authorized(3, "ord_1", "pay_1b", 19.32),
+            collected(4, "pay_1a", "tran_1", 300.0),
+            collected(5, "pay_1b", "tran_1", 19.32),
+            issued(6, "tran_1", 319.32),
+            // within the default absolute tolerance
+            ordered(7, "ord_2", 100.0),
+            authorized(8, "ord_2", "pay_2", 100.0),
+            collected(9, "pay_2", "tran_2", 99.5),
+            issued(10, "tran_2", 100.0),
+            // beyond it
+            ordered(11, "ord_3", 100.0),
+            authorized(12, "ord_3", "pay_3", 100.0),
+            collected(13, "pay_3", "tran_3", 97.0),
+            issued(14, "tran_3", 97.0),
+            // within the interruption percentage
+            interruption(15, "ord_4", "PRP1"),
+            authorized(16, "ord_4", "pay_4", 100.0),
+            collected(17, "pay_4", "tran_4", 96.0),
+            issued(18, "tran_4", 96.0),
+            // the insurance code rule takes precedence
+            interruption(19, "ord_5", "PRP9"),
+            authorized(20, "ord_5", "pay_5", 100.0),
+            collected(21, "pay_5", "tran_5", 99.5),
+            issued(22, "tran_5", 99.5),
+        ];
+
+        let sqlite = SqliteStore::open(":memory:").unwrap();
+        sqlite.migrate().unwrap();
+        for store in [
+            Box::new(InMemoryStore::new()) as Box<dyn Store>,
+            Box::new(sqlite),
+        ] {
+            let event_handler = EventHandler::new(store).with_matching(matching.clone());
+            for event in events.clone() {
+                event_handler.accept(event).unwrap();
+            }
+            let report = event_handler.reconciliation_report().unwrap();
+            assert_eq!(
+                statuses(&report.orders),
+                [
+                    ("ord_1", Matched, 319.32, 319.32),
+                    ("ord_2", NearMatch, 100.0, 99.5),
+                    ("ord_3", AmountMismatch, 100.0, 97.0),
+                    ("ord_4", NearMatch, 100.0, 96.0),
+                    ("ord_5", AmountMismatch, 100.0, 99.5),
+                ]
+            );
+            let payments = report
+                .payments
+                .iter()
+                .map(|r| (r.id.as_str(), r.status))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                payments,
+                [
+                    ("pay_1a", Matched),
+                    ("pay_1b", Matched),
+                    ("pay_2", NearMatch),
+                    ("pay_3", AmountMismatch),
+                    ("pay_4", NearMatch),
+                    ("pay_5", AmountMismatch),
+                ]
+            );
+            assert_eq!(
+                report
+                    .get(BusinessKey::Transaction, "tran_2")
+                    .map(|r| r.status),
+                Some(NearMatch)
+            );
+            assert_eq!(report.unreconciled().count(), 4);
+
+            let collections = event_handler.order_collections().unwrap();
+            assert_eq!(collections[0].status, CollectionStatus::Full);
+            assert!(collections[0].outstanding.is_zero());
+        }
+    }
+
+    #[test]
+    fn decodes_happy_path_fixtures() {
+        let fixtures = std::fs::read_dir("events/happy_path")
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        // the product_ordered fixture is shipped with a misspelled type
+        assert!(fixtures.iter().any(|json| matches!(
+            Event::from_json(json),
+            Err(EventError::UnknownEvent(t)) if t == "product_orderedt"
+        )));
+        let mut events = fixtures
+            .iter()
+            .map(|json| json.replace(r#""product_orderedt""#, r#""product_ordered""#))
+            .map(|json| Event::from_json(&json).unwrap())
+            .collect::<Vec<_>>();
+        events.sort_by_key(|e| e.event_type());
+
+        assert_eq!(events.len(), 4);
+        assert!(events[0].event_id().starts_with("bank_transaction_issued:"));
+
+        // a missing event_id is derived from the whole payload: the same refund
+        // written differently keeps its ID, another amount gets another one
+        let refund = |amount: &str| {
+            Event::from_json(&format!(
+                r#"{{"type": "payment_refunded", "payment_id": "pay_1", "transaction_id": "tran_1",
+                "amount": {amount}, "occurred_on": "2023-02-20T10:00:00Z"}}"#
+            ))
+            .unwrap()
+            .event_id()
+            .to_owned()
+        };
+        assert_eq!(refund("10"), refund(r#""10.00""#));
+        assert_ne!(refund("10"), refund("20"));
+        match &events[3] {
+            Event::ProductOrdered(payload) => {
+                assert_eq!(payload.order_id, "prod_1");
+                assert_eq!(payload.event_type, EventType::Issuance);
+                assert_eq!(payload.insurance_code, "");
+                assert_eq!(payload.guarantees.len(), 2);
+                assert_eq!(payload.guarantees[0].guarantee_type, "rca");
+                assert_eq!(payload.guarantees[1].price, eur(19.32));
+                assert_eq!(payload.installment_type, InstallmentType::Yearly);
+                assert_eq!(
+                    payload.occurred_on,
+                    chrono::DateTime::<chrono::Utc>::from_str("2023-02-20T10:36:33.239Z").unwrap()
+                );
+            }
+            _ => panic!("expected a product_ordered event"),
+        }
+    }
+
+    #[test]
+    fn cross_currency_amounts_are_refused_or_converted() {
+        use ReconciliationStatus::*;
+        let gbp = "GBP".parse().unwrap();
+        let events = [
+            ordered(1, "ord_1", 100.0),
+            authorized(2, "ord_1", "pay_1", 100.0),
+            Event::PaymentCollected(PaymentCollectedPayload {
+                event_id: "evt_3".to_owned(),
+                payment_id: "pay_1".to_owned(),
+                transaction_id: "tran_1".to_owned(),
+                amount: Money::new(8_600, gbp),
+                occurred_on: test_timestamp(),
+            }),
+            Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                event_id: "evt_4".to_owned(),
+                transaction_id: "tran_1".to_owned(),
+                amount: Money::new(8_600, gbp),
+                occurred_on: test_timestamp(),
+            }),
+        ];
+        let exchange_rates = ExchangeRates::from_toml(
+            r#"
+            [[rates]]
+            from = "EUR"
+            to = "GBP"
+            rate = 0.86
+            "#,
+        )
+        .unwrap();
+        let rate = exchange_rates.get(gbp, Currency::EUR).unwrap();
+        assert_eq!(rate.rate, 1.0 / 0.86);
+        assert!(
+            ExchangeRates::from_toml("[[rates]]\nfrom = \"EUR\"\nto = \"GBP\"\nrate = 0").is_err()
+        );
+
+        for exchange_rates in [ExchangeRates::default(), exchange_rates] {
+            let sqlite = SqliteStore::open(":memory:").unwrap();
+            sqlite.migrate().unwrap();
+            for store in [
+                Box::new(InMemoryStore::new()) as Box<dyn Store>,
+                Box::new(sqlite),
+            ] {
+                let event_handler =
+                    EventHandler::new(store).with_exchange_rates(exchange_rates.clone());
+                for event in events.clone() {
+                    event_handler.accept(event).unwrap();
+                }
+                let report = event_handler.reconciliation_report().unwrap();
+                let order = report.get(BusinessKey::Order, "ord_1").unwrap();
+                let payment = report.get(BusinessKey::Payment, "pay_1").unwrap();
+                let transaction = report.get(BusinessKey::Transaction, "tran_1").unwrap();
+                assert_eq!((transaction.status, transaction.rates.len()), (Matched, 0));
+                if exchange_rates == ExchangeRates::default() {
+                    assert_eq!(order.status, CurrencyMismatch);
+                    assert_eq!(payment.status, CurrencyMismatch);
+                    assert_eq!(order.actual, eur(0.0));
+                } else {
+                    assert_eq!(order.status, Matched);
+                    assert_eq!(payment.status, Matched);
+                    assert_eq!(order.actual, eur(100.0));
+                    assert_eq!(order.rates, [rate]);
+                    assert_eq!(payment.rates, [rate]);
+                }
+                // the stored balance only counts collections in the order's currency
+                assert!(event_handler.order_collections().unwrap()[0]
+                    .collected
+                    .is_zero());
+            }
+        }
+    }
+
+    #[test]
+    fn money_is_exact_in_minor_units() {
+        assert_eq!(
+            "319.32".parse::<Money>().unwrap(),
+            Money::new(31_932, Currency::EUR)
+        );
+        assert_eq!(
+            "-5 JPY".parse::<Money>().unwrap(),
+            Money::new(-5, "JPY".parse().unwrap())
+        );
+        assert_eq!(Money::new(-1_005, Currency::EUR).to_string(), "-10.05 EUR");
+        assert!("1.005".parse::<Money>().is_err());
+        assert_eq!(
+            "10.100".parse::<Money>().unwrap(),
+            Money::new(1_010, Currency::EUR)
+        );
+        assert_eq!(
+            "100.0 JPY".parse::<Money>().unwrap(),
+            Money::new(100, "JPY".parse().unwrap())
+        );
+        assert!("100.5 JPY".parse::<Money>().is_err());
+        assert!("1.00 eur".parse::<Money>().is_err());
+        assert_eq!(
+            eur(300.0) + eur(19.32) - "319.32".parse().unwrap(),
+            Money::zero(Currency::EUR)
+        );
+        assert_eq!(
+            eur(1.0).checked_add(Money::new(100, "GBP".parse().unwrap())),
+            None
+        );
+        assert_eq!(Money::new(i64::MIN, Currency::EUR).checked_abs(), None);
+        assert_eq!(
+            Money::new(i64::MAX, Currency::EUR).checked_add(eur(0.01)),
+            None
+        );
+
+        // JSON numbers are read from their text like strings, not rounded as f64
+        let collected = |amount: &str| {
+            Event::from_json(&format!(
+                r#"{{"type":"payment_collected","payment_id":"pay_1","transaction_id":"tran_1","amount":{amount},"occurred_on":"2023-02-20T10:00:00.000Z"}}"#
+            ))
+        };
+        for amount in ["319.32", r#""319.32""#] {
+            match collected(amount).unwrap() {
+                Event::PaymentCollected(payload) => {
+                    assert_eq!(payload.amount, Money::new(31_932, Currency::EUR))
+                }
+                _ => panic!("expected a payment_collected event"),
+            }
+        }
+        for amount in ["319.325", r#""319.325""#, "0.1000000000000000055"] {
+            assert!(matches!(
+                collected(amount),
+                Err(EventError::DecodingError(_))
+            ));
+        }
+
+        // ten 0.10 orders add up to 1.00 exactly, as f64 they would not
+        let memory = std::sync::Arc::new(InMemoryStore::new());
+        let sqlite = std::sync::Arc::new(SqliteStore::open(":memory:").unwrap());
+        sqlite.migrate().unwrap();
+        for store in [
+            Box::new(memory.clone()) as Box<dyn Store>,
+            Box::new(sqlite.clone()),
+        ] {
+            let event_handler = EventHandler::new(store);
+            for n in 0..10 {
+                event_handler
+                    .accept(ordered(n, &format!("ord_{n}"), 0.1))
+                    .unwrap();
+            }
+            event_handler.catch_up(&[]).unwrap();
+        }
+        assert_eq!(
+            memory.state().total(Total::Ordered, Currency::EUR),
+            eur(1.0)
+        );
+        assert_sqlite_query(
+            &sqlite.connection(),
+            "SELECT SUM(amount) FROM total_ordered",
+            Value::Integer(100),
+        );
+    }
+
+    #[test]
+    fn event_json_roundtrip() {
+        let event = Event::PaymentCollected(PaymentCollectedPayload {
+            event_id: "evt_1".to_owned(),
+            amount: eur(100.0),
+            payment_id: "pay_1".to_owned(),
+            transaction_id: "tran_1".to_owned(),
+            occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+        });
+
+        let json = event.to_json().unwrap();
+        assert!(json.contains(r#""type":"payment_collected""#));
+        assert!(json.contains(r#""occurred_on":"2023-02-20T10:00:00.000Z""#));
+        assert!(json.contains(r#""amount":"100.00","currency":"EUR""#));
+        assert_eq!(Event::from_json(&json).unwrap(), event);
+
+        // beyond what an f64 holds exactly, written without an exponent
+        let Event::PaymentCollected(payload) = event else {
+            unreachable!()
+        };
+        let large = Event::PaymentCollected(PaymentCollectedPayload {
+            amount: Money::new(1_000_000_000_000_000_001, Currency::EUR),
+            ..payload
+        });
+        let json = large.to_json().unwrap();
+        assert!(json.contains(r#""amount":"10000000000000000.01""#));
+        assert_eq!(Event::from_json(&json).unwrap(), large);
+
+        let yen = r#"{"type":"product_ordered","event_id":"evt_2","order_id":"ord_1","amount":"1500","currency":"JPY","event_type":"issuance","installment_type":"yearly","guarantees":[{"type":"rca","price":1000},{"type":"infortuni","price":500}],"occurred_on":"2023-02-20T10:00:00.000Z","insurance_code":"PRP1"}"#;
+        match Event::from_json(yen).unwrap() {
+            Event::ProductOrdered(payload) => {
+                let jpy = "JPY".parse().unwrap();
+                assert_eq!(payload.amount, Money::new(1_500, jpy));
+                assert_eq!(payload.guarantees[1].price, Money::new(500, jpy));
+            }
+            _ => panic!("expected a product_ordered event"),
+        }
+
+        let rfc3339 = r#"{"type":"bank_transaction_issued","event_id":"evt_1","transaction_id":"tran_1","amount":1.5,"occurred_on":"2023-02-20T11:00:00+01:00"}"#;
+        match Event::from_json(rfc3339).unwrap() {
+            Event::BankTransactionIssued(payload) => assert_eq!(
+                payload.occurred_on,
+                chrono::DateTime::<chrono::Utc>::from_str("2023-02-20T10:00:00Z").unwrap()
+            ),
+            _ => panic!("expected a bank_transaction_issued event"),
+        }
+    }
+
+    #[test]
+    fn unknown_event_type_is_rejected() {
+        let result = Event::from_json(r#"{"type":"product_shipped","order_id":"ord_1"}"#);
+        assert!(matches!(result, Err(EventError::UnknownEvent(t)) if t == "product_shipped"));
+
+        let result = Event::from_json(r#"{"type":"product_ordered","order_id":"ord_1"}"#);
+        assert!(matches!(result, Err(EventError::DecodingError(_))));
+    }
+
+    #[test]
+    fn ingest_reports_accepted_rejected_and_unknown_events() {
+        let path = std::env::temp_dir().join("spike_costacando_ingest.jsonl");
+        std::fs::write(
+            &path,
+            [
+                r#"{"type":"bank_transaction_issued","event_id":"evt_1","transaction_id":"tran_1","amount":100.0,"occurred_on":"2023-02-20T10:00:00:000Z"}"#,
+                r#"{"type":"refund_issued","refund_id":"ref_1"}"#,
+                r#"{"type":"bank_transaction_issued","event_id":"evt_1","transaction_id":"tran_1","amount":100.0,"occurred_on":"2023-02-20T10:00:00:000Z"}"#,
+                r#"{"type": broken"#,
+                r#"{"type":"bank_transaction_issued","event_id":"evt_2","transaction_id":"tran_2","amount":50.0,"occurred_on":"2023-02-20T10:00:00:000Z"}"#,
+                r#"{"type":"bank_transaction_issued","event_id":"evt_3","transaction_id":"tran_1","amount":100.0,"occurred_on":"2023-02-20T10:00:00:000Z"}"#,
+                "{",
+                r#"    "type": "bank_transaction_issued","#,
+                r#"    "event_id": broken,"#,
+                r#"    "transaction_id": "tran_4""#,
+                "}",
+                r#"{"type":"bank_transaction_issued","event_id":"evt_5","transaction_id":"tran_5","amount":10.0,"occurred_on":"2023-02-20T10:00:00:000Z"}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let summary = crate::ingest::ingest(
+            &EventHandler::new(Box::new(InMemoryStore::new())),
+            Some(path.as_path()),
         )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(summary.accepted, 3);
+        assert!(summary.projector_error.is_none());
+        assert_eq!(
+            summary
+                .unknown
+                .iter()
+                .map(|(p, t)| (p.line, t.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "refund_issued")]
+        );
+        assert_eq!(
+            summary
+                .rejected
+                .iter()
+                .map(|(p, _)| p.line)
+                .collect::<Vec<_>>(),
+            vec![4, 6, 7]
+        );
+        assert!(matches!(
+            summary.rejected[0].1,
+            EventError::DecodingError(_)
+        ));
+        assert_eq!(
+            summary
+                .duplicates
+                .iter()
+                .map(|p| p.line)
+                .collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+
+    fn happy_path_events() -> [Event; 4] {
+        [
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_id: "evt_1".to_owned(),
+                amount: eur(100.0),
+                order_id: "ord_1".to_owned(),
+                guarantees: vec![],
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+                event_type: EventType::Issuance,
+                installment_type: InstallmentType::Yearly,
+                insurance_code: "PRP123".to_string(),
+                original_order_id: None,
+            }),
+            Event::PaymentAuthorized(PaymentAuthorizedPayload {
+                event_id: "evt_2".to_owned(),
+                amount: eur(100.0),
+                order_id: "ord_1".to_owned(),
+                payment_id: "pay_1".to_owned(),
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:01.000Z").unwrap(),
+            }),
+            Event::PaymentCollected(PaymentCollectedPayload {
+                event_id: "evt_3".to_owned(),
+                amount: eur(100.0),
+                payment_id: "pay_1".to_owned(),
+                transaction_id: "tran_1".to_owned(),
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+            }),
+            Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                event_id: "evt_4".to_owned(),
+                amount: eur(100.0),
+                transaction_id: "tran_1".to_owned(),
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:01.000Z").unwrap(),
+            }),
+        ]
+    }
+
+    fn events_type_not_reconciled_events() -> [Event; 3] {
+        [
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_id: "evt_1".to_owned(),
+                amount: eur(100.0),
+                order_id: "ord_1".to_owned(),
+                guarantees: vec![],
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+                event_type: EventType::Issuance,
+                installment_type: InstallmentType::Yearly,
+                insurance_code: "PRP1".to_owned(),
+                original_order_id: None,
+            }),
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_id: "evt_2".to_owned(),
+                amount: eur(200.0),
+                order_id: "ord_2".to_owned(),
+                guarantees: vec![],
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+                event_type: EventType::Interruption,
+                installment_type: InstallmentType::BiYearly,
+                insurance_code: "PRP2".to_owned(),
+                original_order_id: None,
+            }),
+            Event::ProductOrdered(ProductOrderedPayload {
+                event_id: "evt_3".to_owned(),
+                amount: eur(300.0),
+                order_id: "ord_3".to_owned(),
+                guarantees: vec![],
+                occurred_on: chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap(),
+                event_type: EventType::Interruption,
+                installment_type: InstallmentType::BiYearly,
+                insurance_code: "PRP3".to_owned(),
+                original_order_id: None,
+            }),
+        ]
+    }
+
+    /// The issuance and the two interruptions are unreconciled, aged 30+ days.
+    fn assert_not_reconciled_by_event_type(event_handler: &EventHandler) {
+        let as_of = chrono::DateTime::from_str("2023-04-01T00:00:00Z").unwrap();
+        let aging = |event_type, insurance_code: Option<&str>| {
+            let report = event_handler
+                .aging_report(
+                    as_of,
+                    &AgingFilter {
+                        event_type: Some(event_type),
+                        insurance_code: insurance_code.map(str::to_owned),
+                    },
+                )
+                .unwrap();
+            (
+                report
+                    .items()
+                    .map(|i| i.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                report.buckets[3].totals.clone(),
+            )
+        };
+        assert_eq!(
+            aging(EventType::Issuance, None),
+            ("ord_1".to_owned(), vec![eur(100.0)])
+        );
+        assert_eq!(
+            aging(EventType::Interruption, None),
+            ("ord_2,ord_3".to_owned(), vec![eur(500.0)])
+        );
+        assert_eq!(
+            aging(EventType::Interruption, Some("PRP2")),
+            ("ord_2".to_owned(), vec![eur(200.0)])
+        );
+    }
+
+    /// ord_1 is paid in two installments, the second one collected in two bank
+    /// transactions; ord_2 is over-collected; tran_2 settles payments of all orders.
+    fn split_payment_events() -> Vec<Event> {
+        vec![
+            ordered(1, "ord_1", 300.0),
+            ordered(2, "ord_2", 50.0),
+            ordered(3, "ord_3", 100.0),
+            authorized(4, "ord_1", "pay_1", 100.0),
+            authorized(5, "ord_1", "pay_2", 100.0),
+            authorized(6, "ord_2", "pay_3", 50.0),
+            authorized(7, "ord_3", "pay_4", 100.0),
+            collected(8, "pay_1", "tran_1", 100.0),
+            collected(9, "pay_2", "tran_1", 50.0),
+            collected(10, "pay_2", "tran_2", 50.0),
+            collected(11, "pay_3", "tran_2", 80.0),
+            collected(12, "pay_4", "tran_2", 100.0),
+            issued(13, "tran_1", 150.0),
+            issued(14, "tran_2", 230.0),
+        ]
+    }
+
+    fn statuses(reconciliations: &[Reconciliation]) -> Vec<(&str, ReconciliationStatus, f64, f64)> {
+        reconciliations
+            .iter()
+            .map(|r| {
+                (
+                    r.id.as_str(),
+                    r.status,
+                    r.expected.to_major(),
+                    r.actual.to_major(),
+                )
+            })
+            .collect()
+    }
+
+    fn eur(amount: f64) -> Money {
+        Money::from_major(amount, Currency::EUR)
+    }
+
+    /// The events sorted by the sort key at their index, missing keys sorting first.
+    fn shuffled(events: Vec<Event>, keys: &[u32]) -> Vec<Event> {
+        let mut events = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, event)| (keys.get(i).copied().unwrap_or_default(), i, event))
+            .collect::<Vec<_>>();
+        events.sort_by_key(|(key, i, _)| (*key, *i));
+        events.into_iter().map(|(_, _, event)| event).collect()
+    }
+
+    fn test_timestamp() -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_str("2023-02-20T10:00:00.000Z").unwrap()
+    }
+
+    fn ordered(event: usize, order_id: &str, amount: f64) -> Event {
+        Event::ProductOrdered(ProductOrderedPayload {
+            event_id: format!("evt_{event}"),
+            order_id: order_id.to_owned(),
+            amount: eur(amount),
+            guarantees: vec![],
+            occurred_on: test_timestamp(),
+            event_type: EventType::Issuance,
+            installment_type: InstallmentType::Monthly,
+            insurance_code: "PRP1".to_owned(),
+            original_order_id: None,
+        })
+    }
+
+    fn authorized(event: usize, order_id: &str, payment_id: &str, amount: f64) -> Event {
+        Event::PaymentAuthorized(PaymentAuthorizedPayload {
+            event_id: format!("evt_{event}"),
+            order_id: order_id.to_owned(),
+            payment_id: payment_id.to_owned(),
+            amount: eur(amount),
+            occurred_on: test_timestamp(),
+        })
+    }
+
+    fn collected(event: usize, payment_id: &str, transaction_id: &str, amount: f64) -> Event {
+        Event::PaymentCollected(PaymentCollectedPayload {
+            event_id: format!("evt_{event}"),
+            payment_id: payment_id.to_owned(),
+            transaction_id: transaction_id.to_owned(),
+            amount: eur(amount),
+            occurred_on: test_timestamp(),
+        })
+    }
+
+    fn issued(event: usize, transaction_id: &str, amount: f64) -> Event {
+        Event::BankTransactionIssued(BankTransactionIssuedPayload {
+            event_id: format!("evt_{event}"),
+            transaction_id: transaction_id.to_owned(),
+            amount: eur(amount),
+            occurred_on: test_timestamp(),
+        })
+    }
+
+    fn postgres_store() -> Box<dyn crate::storage::Store> {
+        Box::new(PostgresStore::new(POOL.clone()))
     }
 
     fn assert_query<T>(client: &mut Client, query: &str, value: T)
@@ -194,7 +2584,23 @@ mod tests {
         T: for<'a> FromSql<'a> + Eq + Debug,
     {
         let s = client.query(query, &[]).unwrap();
-        let res: T = s.get(0).unwrap().get(0);
+        let res: T = s.first().unwrap().get(0);
+        assert_eq!(res, value, "expected {query} to return {:?}", value);
+    }
+
+    fn sqlite_rows(connection: &sqlite::Connection, query: &str) -> Vec<(String, Option<i64>)> {
+        let mut statement = connection.prepare(format!("{query} ORDER BY 1")).unwrap();
+        let mut rows = vec![];
+        while statement.next().unwrap() == sqlite::State::Row {
+            rows.push((statement.read(0).unwrap(), statement.read(1).unwrap()));
+        }
+        rows
+    }
+
+    fn assert_sqlite_query(connection: &sqlite::Connection, query: &str, value: Value) {
+        let mut statement = connection.prepare(query).unwrap();
+        statement.next().unwrap();
+        let res: Value = statement.read(0).unwrap();
         assert_eq!(res, value, "expected {query} to return {:?}", value);
     }
 }
@@ -1,64 +1,225 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
 use rand::Rng;
-use spike_costacando::{
-    event_handler::EventHandler,
-    events::{
-        BankTransactionIssuedPayload, PaymentAuthorizedPayload, PaymentCollectedPayload,
-        ProductOrderedPayload,
-    },
-};
-use std::vec;
+use spike_costacando::aging::AgingFilter;
+use spike_costacando::config::Config;
+use spike_costacando::event_handler::EventHandler;
+use spike_costacando::events::parse_timestamp;
+use spike_costacando::export::{DateRange, ExportFormat};
+use spike_costacando::generator::{EventFamily, FamilyShape};
+use spike_costacando::storage::memory::InMemoryStore;
+use spike_costacando::storage::postgres::PostgresStore;
+use spike_costacando::storage::Store;
+use std::io::Write;
+use std::path::Path;
 
-fn main() -> Result<(), String> {
+const USAGE: &str = "usage:
+    spike-costacando ingest [--dry-run] [PATH|-]
+        replay JSON events from a file, a directory or stdin (JSON Lines);
+        --dry-run reconciles them in memory without touching the database
+    spike-costacando migrate
+        apply the pending schema migrations
+    spike-costacando status
+        list the schema migrations and when they were applied
+    spike-costacando rebuild [--resume] [PROJECTOR...]
+        recompute the named projections (default: all) from the event log;
+        --resume carries on an interrupted rebuild from its checkpoints
+    spike-costacando projectors
+        show how far each projector is behind the event log and why it stopped
+    spike-costacando aging [--as-of DATE] [--event-type TYPE] [--insurance-code CODE]
+        list the unreconciled items by age (default: as of now), keeping those of
+        orders of the given event type and insurance code
+    spike-costacando export [--format csv|jsonl] [--from DATE] [--to DATE] [--output PATH]
+        write the reconciliation state of the orders that occurred from DATE
+        included to DATE excluded (default: all), as CSV (default) or JSON Lines,
+        to PATH or stdout
+    spike-costacando bench
+        run the random-data benchmark against the configured Postgres database
+
+settings are read from $SPIKE_CONFIG (or ./spike.toml) and SPIKE_DATABASE_* variables";
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let config = Config::load().map_err(|e| e.to_string())?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["ingest"] => ingest(configured_handler(&config)?, None),
+        ["ingest", "--dry-run"] => ingest(dry_run_handler(), None),
+        ["ingest", "--dry-run", path] => ingest(dry_run_handler(), Some(Path::new(path))),
+        ["ingest", path] => ingest(configured_handler(&config)?, Some(Path::new(path))),
+        ["migrate"] => migrate(&config),
+        ["status"] => status(&config),
+        ["rebuild", "--resume", projectors @ ..] => rebuild(&config, projectors, true),
+        ["rebuild", projectors @ ..] => rebuild(&config, projectors, false),
+        ["projectors"] => projectors(&config),
+        ["aging", options @ ..] => aging(&config, options),
+        ["export", options @ ..] => export(&config, options),
+        ["bench"] => bench(&config),
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn configured_handler(config: &Config) -> Result<EventHandler, String> {
+    EventHandler::from_config(config).map_err(|e| e.to_string())
+}
+
+fn dry_run_handler() -> EventHandler {
+    EventHandler::new(Box::new(InMemoryStore::new()))
+}
+
+fn ingest(handler: EventHandler, path: Option<&Path>) -> Result<(), String> {
+    let summary = spike_costacando::ingest::ingest(&handler, path)?;
+    print!("{summary}");
+    if summary.rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} event(s) rejected", summary.rejected.len()))
+    }
+}
+
+fn migrate(config: &Config) -> Result<(), String> {
+    let store = spike_costacando::storage::open(&config.database).map_err(|e| e.to_string())?;
+    let applied = store.migrate().map_err(|e| e.to_string())?;
+    if applied.is_empty() {
+        println!("no pending migrations");
+    }
+    for migration in applied {
+        println!("applied {:04} {}", migration.version, migration.name);
+    }
+    Ok(())
+}
+
+fn status(config: &Config) -> Result<(), String> {
+    let store = spike_costacando::storage::open(&config.database).map_err(|e| e.to_string())?;
+    for migration in store.migration_status().map_err(|e| e.to_string())? {
+        println!(
+            "{:04} {:<30} {}",
+            migration.version,
+            migration.name,
+            migration.applied_on.as_deref().unwrap_or("pending")
+        );
+    }
+    Ok(())
+}
+
+fn rebuild(config: &Config, projectors: &[&str], resume: bool) -> Result<(), String> {
+    let handler = configured_handler(config)?;
+    for (projector, replayed) in handler
+        .rebuild(projectors, resume)
+        .map_err(|e| e.to_string())?
+    {
+        println!("{projector}: replayed {replayed} event(s)");
+    }
+    Ok(())
+}
+
+fn projectors(config: &Config) -> Result<(), String> {
+    let handler = configured_handler(config)?;
+    for lag in handler.lag().map_err(|e| e.to_string())? {
+        print!(
+            "{:<20} at {:>8}, {:>8} behind",
+            lag.projector, lag.checkpoint, lag.lag
+        );
+        match lag.last_error {
+            Some(error) => println!(": {error}"),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+fn aging(config: &Config, options: &[&str]) -> Result<(), String> {
+    let mut as_of = Utc::now();
+    let mut filter = AgingFilter::default();
+    for option in options.chunks(2) {
+        match option {
+            ["--as-of", date] => as_of = parse_timestamp(date)?,
+            ["--event-type", event_type] => filter.event_type = Some(event_type.parse()?),
+            ["--insurance-code", code] => filter.insurance_code = Some(code.to_string()),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let report = configured_handler(config)?
+        .aging_report(as_of, &filter)
+        .map_err(|e| e.to_string())?;
+    print!("{report}");
+    Ok(())
+}
+
+fn export(config: &Config, options: &[&str]) -> Result<(), String> {
+    let mut format = ExportFormat::Csv;
+    let mut range = DateRange::default();
+    let mut output = None;
+    for option in options.chunks(2) {
+        match option {
+            ["--format", f] => format = f.parse()?,
+            ["--from", date] => range.from = Some(parse_date(date)?),
+            ["--to", date] => range.to = Some(parse_date(date)?),
+            ["--output", path] => output = Some(Path::new(path)),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let orders = configured_handler(config)?
+        .order_exports(&range)
+        .map_err(|e| e.to_string())?;
+    match output {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(
+                std::fs::File::create(path).map_err(|e| format!("{}: {e}", path.display()))?,
+            );
+            spike_costacando::export::write(&mut file, &orders, format)?;
+            file.flush().map_err(|e| format!("{}: {e}", path.display()))
+        }
+        None => spike_costacando::export::write(&mut std::io::stdout().lock(), &orders, format),
+    }
+}
+
+/// A timestamp, or a `2023-02-20` date meaning its midnight UTC.
+fn parse_date(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_timestamp(s).or_else(|err| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map(|date| Utc.from_utc_datetime(&date.and_time(NaiveTime::default())))
+            .map_err(|_| err)
+    })
+}
+
+fn bench(config: &Config) -> Result<(), String> {
+    let pool = spike_costacando::pool::connect(&config.database).map_err(|e| e.to_string())?;
     println!("~40ms per evento");
     for num in [10, 100, 1000, 10000, 100000, 1000000, 10000000, 100000000] {
-        let num_of_events_to_handle: usize = num;
-        let client = &mut spike_costacando::pool::POOL.get().unwrap();
-        spike_costacando::pool::reset_db(client);
-        let handler = EventHandler::new();
-        let mut events: Vec<spike_costacando::events::Event> = vec![];
+        let store = PostgresStore::new(pool.clone());
+        store.migrate().map_err(|e| e.to_string())?;
+        let handler = EventHandler::new(Box::new(store));
         println!("Generating events...");
-        for _i in 0..num_of_events_to_handle {
-            events.append(&mut generate_random_events(num_of_events_to_handle));
+        let mut rng = rand::thread_rng();
+        let run: u32 = rng.gen();
+        let mut events = vec![];
+        for family in 0..num {
+            let shape = FamilyShape::random(&mut rng, 3);
+            events.extend(
+                EventFamily::generate(&mut rng, &format!("{run:x}_{family}"), Utc::now(), &shape)
+                    .events,
+            );
         }
+        let num_of_events_to_handle = events.len();
         println!("Generated events!\nHandling events...");
         let before = std::time::SystemTime::now();
-        events.into_iter().for_each(|e| handler.accept(e).unwrap());
+        events.into_iter().for_each(|e| {
+            handler.accept(e).unwrap();
+        });
+        handler.catch_up(&[]).map_err(|e| e.to_string())?;
         let after = std::time::SystemTime::elapsed(&before).unwrap().as_millis();
         println!("{after}ms spent to handle {num_of_events_to_handle} events");
     }
     Ok(())
 }
-
-fn generate_random_events(num_of_events_to_handle: usize) -> Vec<spike_costacando::events::Event> {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(0..num_of_events_to_handle);
-    let random_number: usize = rng.gen();
-    vec![
-        spike_costacando::events::Event::BankTransactionIssued(BankTransactionIssuedPayload {
-            transaction_id: format!("t_{random_number}"),
-            amount: 100.0,
-            occurred_on: chrono::Utc::now(),
-        }),
-        spike_costacando::events::Event::PaymentAuthorized(PaymentAuthorizedPayload {
-            order_id: format!("o_{random_number}"),
-            payment_id: format!("p_{random_number}"),
-            amount: 100.0,
-            occurred_on: chrono::Utc::now(),
-        }),
-        spike_costacando::events::Event::PaymentCollected(PaymentCollectedPayload {
-            payment_id: format!("p_{random_number}"),
-            transaction_id: format!("t_{random_number}"),
-            amount: 100.0,
-            occurred_on: chrono::Utc::now(),
-        }),
-        spike_costacando::events::Event::ProductOrdered(ProductOrderedPayload {
-            amount: 100.0,
-            occurred_on: chrono::Utc::now(),
-            order_id: format!("p_{random_number}"),
-            event_type: spike_costacando::events::EventType::Issuance,
-            installment_type: spike_costacando::events::InstallmentType::Monthly,
-            guarantees: vec![],
-            insurance_code: format!("PRP{random_number}"),
-        }),
-    ]
-}
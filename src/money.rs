@@ -0,0 +1,404 @@
+use std::fmt::Display;
+use std::ops::{Add, Neg, Sub};
+use std::str::FromStr;
+
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An ISO 4217 currency code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const EUR: Currency = Currency(*b"EUR");
+
+    pub fn code(&self) -> &str {
+        // only ASCII letters get past `from_str`
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    /// Digits after the decimal separator, i.e. the size of the minor unit.
+    pub fn decimals(&self) -> u32 {
+        match &self.0 {
+            b"BIF" | b"CLP" | b"DJF" | b"GNF" | b"ISK" | b"JPY" | b"KMF" | b"KRW" | b"PYG"
+            | b"RWF" | b"UGX" | b"VND" | b"VUV" | b"XAF" | b"XOF" | b"XPF" => 0,
+            b"BHD" | b"IQD" | b"JOD" | b"KWD" | b"LYD" | b"OMR" | b"TND" => 3,
+            _ => 2,
+        }
+    }
+
+    /// Minor units in one major unit, e.g. 100 cents in a euro.
+    pub fn minor_per_major(&self) -> i64 {
+        10_i64.pow(self.decimals())
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::EUR
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|b| b.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(format!("invalid currency code `{s}`")),
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl std::fmt::Debug for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// An exact amount, counted in minor units (cents for EUR) of its currency.
+///
+/// Amounts of different currencies never mix: adding or subtracting them panics, as
+/// does overflowing. Use [`Money::checked_add`] where the currencies are not known
+/// to agree or the amounts may be out of range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Money {
+    minor_units: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn new(minor_units: i64, currency: Currency) -> Self {
+        Self {
+            minor_units,
+            currency,
+        }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Rounds a decimal amount, e.g. `319.32`, to the currency's minor unit.
+    pub fn from_major(amount: f64, currency: Currency) -> Self {
+        Self::new(
+            (amount * currency.minor_per_major() as f64).round() as i64,
+            currency,
+        )
+    }
+
+    /// Parses a decimal amount such as `319.32` exactly, refusing more significant
+    /// decimals than the currency has: `10.100` is fine in euros, `10.105` is not.
+    pub fn parse(amount: &str, currency: Currency) -> Result<Self, String> {
+        let invalid = || format!("invalid amount `{amount}`");
+        let (negative, digits) = match amount.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, amount),
+        };
+        let (major, minor) = digits.split_once('.').unwrap_or((digits, ""));
+        let minor = minor.trim_end_matches('0');
+        let decimals = currency.decimals() as usize;
+        if major.is_empty()
+            || minor.len() > decimals
+            || !major
+                .bytes()
+                .chain(minor.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let minor_units = format!("{major}{minor:0<decimals$}")
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+        Ok(Self::new(
+            if negative { -minor_units } else { minor_units },
+            currency,
+        ))
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// The amount in major units. Exact as a decimal for amounts up to 2^53 minor
+    /// units only, see [`Money::to_decimal`].
+    pub fn to_major(&self) -> f64 {
+        self.minor_units as f64 / self.currency.minor_per_major() as f64
+    }
+
+    /// The exact amount in major units without the currency, e.g. `-10.05`, as
+    /// [`Money::parse`] reads it.
+    pub fn to_decimal(&self) -> String {
+        let decimals = self.currency.decimals() as usize;
+        let per_major = self.currency.minor_per_major();
+        let sign = if self.minor_units < 0 { "-" } else { "" };
+        let major = self.minor_units.unsigned_abs() / per_major as u64;
+        let minor = self.minor_units.unsigned_abs() % per_major as u64;
+        if decimals == 0 {
+            format!("{sign}{major}")
+        } else {
+            format!("{sign}{major}.{minor:0decimals$}")
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    /// `None` when the amount is `i64::MIN` minor units, which has no opposite.
+    pub fn checked_abs(&self) -> Option<Money> {
+        self.minor_units
+            .checked_abs()
+            .map(|minor_units| Self::new(minor_units, self.currency))
+    }
+
+    /// Converts the amount at the rate, rounding to the minor unit of `rate.to`.
+    /// The amount must be in `rate.from`.
+    pub fn convert(&self, rate: &ExchangeRate) -> Money {
+        debug_assert_eq!(self.currency, rate.from);
+        let scale = rate.to.minor_per_major() as f64 / rate.from.minor_per_major() as f64;
+        Money::new(
+            (self.minor_units as f64 * rate.rate * scale).round() as i64,
+            rate.to,
+        )
+    }
+
+    /// `None` when the currencies differ or the sum overflows.
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.minor_units
+            .checked_add(other.minor_units)
+            .map(|minor_units| Self::new(minor_units, self.currency))
+    }
+
+    /// Adds up amounts of `currency`, zero when there are none.
+    pub fn sum<I: IntoIterator<Item = Money>>(currency: Currency, amounts: I) -> Money {
+        amounts.into_iter().fold(Money::zero(currency), Add::add)
+    }
+
+    /// Splits the amount in proportion to the weights. The minor units left over by
+    /// rounding go to the largest remainders, so that the parts add up to the
+    /// amount; nothing is split when the weights add up to zero.
+    pub fn allocate(&self, weights: &[i64]) -> Vec<Money> {
+        let amount = self.minor_units as i128;
+        let total = weights.iter().map(|w| *w as i128).sum::<i128>();
+        if total == 0 {
+            return vec![Money::zero(self.currency); weights.len()];
+        }
+        let mut shares = weights
+            .iter()
+            .map(|w| {
+                let weighted = amount * *w as i128;
+                (weighted.div_euclid(total), weighted.rem_euclid(total))
+            })
+            .collect::<Vec<_>>();
+        let mut left = amount - shares.iter().map(|(share, _)| share).sum::<i128>();
+        let mut by_remainder = (0..shares.len()).collect::<Vec<_>>();
+        by_remainder.sort_by_key(|i| std::cmp::Reverse(shares[*i].1));
+        for i in by_remainder {
+            if left <= 0 {
+                break;
+            }
+            shares[i].0 += 1;
+            left -= 1;
+        }
+        shares
+            .into_iter()
+            .map(|(share, _)| Money::new(share as i64, self.currency))
+            .collect()
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.checked_add(other)
+            .unwrap_or_else(|| panic!("cannot add {other} to {self}"))
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self + -other
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Self::new(-self.minor_units, self.currency)
+    }
+}
+
+impl Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} {}", self.to_decimal(), self.currency))
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    /// Parses `319.32 GBP`, or `319.32` in euros.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(' ') {
+            Some((amount, currency)) => Money::parse(amount, currency.trim().parse()?),
+            None => Money::parse(s.trim(), Currency::default()),
+        }
+    }
+}
+
+/// Written as the exact decimal string in major units, e.g. `"319.32"`, without its
+/// currency, so that it reads back as the same amount.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_decimal())
+    }
+}
+
+/// What one unit of `from` is worth in `to`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangeRate {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: f64,
+}
+
+/// The rates amounts in different currencies are reconciled with, read from a TOML
+/// file of `[[rates]]` entries. A rate is also used the other way round.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeRates {
+    rates: Vec<ExchangeRate>,
+}
+
+impl ExchangeRates {
+    pub fn new(rates: Vec<ExchangeRate>) -> Self {
+        Self { rates }
+    }
+
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| Self::from_toml(&content))
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, String> {
+        let rates: Self = toml::from_str(content).map_err(|e| e.to_string())?;
+        match rates
+            .rates
+            .iter()
+            .find(|r| !(r.rate.is_finite() && r.rate > 0.0) || r.from == r.to)
+        {
+            Some(r) => Err(format!(
+                "invalid rate {} from {} to {}",
+                r.rate, r.from, r.to
+            )),
+            None => Ok(rates),
+        }
+    }
+
+    /// The rate from one currency to another, `None` if neither it nor its inverse
+    /// is in the table.
+    pub fn get(&self, from: Currency, to: Currency) -> Option<ExchangeRate> {
+        self.rates.iter().find_map(|r| {
+            if r.from == from && r.to == to {
+                Some(*r)
+            } else if r.from == to && r.to == from {
+                Some(ExchangeRate {
+                    from,
+                    to,
+                    rate: 1.0 / r.rate,
+                })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// An amount as read from JSON, a number or a string in major units, which only
+/// becomes [`Money`] once its currency is known. Numbers keep the text they were
+/// written as, so that both forms are parsed exactly.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Decimal {
+    Number(serde_json::Number),
+    Text(String),
+}
+
+impl Decimal {
+    pub fn to_money(&self, currency: Currency) -> Result<Money, String> {
+        match self {
+            Decimal::Number(n) => Money::parse(&n.to_string(), currency),
+            Decimal::Text(s) => Money::parse(s, currency),
+        }
+    }
+}
+
+/// Writes an amount as the `amount` and `currency` fields of the payload holding
+/// it, with `#[serde(flatten, with = "crate::money::amount")]`. The currency
+/// defaults to euros.
+pub mod amount {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Fields<'a> {
+        amount: &'a Money,
+        currency: Currency,
+    }
+
+    #[derive(Deserialize)]
+    struct RawFields {
+        amount: Decimal,
+        #[serde(default)]
+        currency: Currency,
+    }
+
+    pub fn serialize<S: Serializer>(money: &Money, s: S) -> Result<S::Ok, S::Error> {
+        Fields {
+            amount: money,
+            currency: money.currency(),
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Money, D::Error> {
+        let fields = RawFields::deserialize(d)?;
+        fields
+            .amount
+            .to_money(fields.currency)
+            .map_err(de::Error::custom)
+    }
+}
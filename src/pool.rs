@@ -1,9 +1,11 @@
-use lazy_static::lazy_static;
+use std::time::Duration;
+
 use postgres::NoTls;
-use r2d2_postgres::{
-    r2d2::{self, PooledConnection},
-    PostgresConnectionManager,
-};
+use r2d2_postgres::{r2d2, PostgresConnectionManager};
+
+use crate::config::DatabaseConfig;
+use crate::storage::postgres::Pool;
+use crate::storage::StorageError;
 
 /*
    bank transactions ->
@@ -36,95 +38,36 @@ use r2d2_postgres::{
     cerco per chiave naturale su relations e faccio i confronti necessari.
 */
 
-lazy_static! {
-    pub static ref POOL: r2d2::Pool<PostgresConnectionManager<NoTls>> = {
-        let manager = PostgresConnectionManager::new(
-            "host=localhost user=user password=password port=5432 connect_timeout=5"
-                .parse()
-                .unwrap(),
-            NoTls,
-        );
-        r2d2::Pool::new(manager).unwrap()
-    };
+/// Builds the Postgres connection pool described by `config`; every connection
+/// gets the configured schema as its `search_path`.
+pub fn connect(config: &DatabaseConfig) -> Result<Pool, StorageError> {
+    let mut pg_config: postgres::Config = config
+        .url
+        .parse()
+        .map_err(|e: postgres::Error| StorageError::ConnectionError(e.to_string()))?;
+    let timeout = Duration::from_secs(config.connect_timeout_secs);
+    pg_config
+        .connect_timeout(timeout)
+        .options(&format!("-c search_path={}", config.schema));
+
+    r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(timeout)
+        .build(PostgresConnectionManager::new(pg_config, NoTls))
+        .map_err(|e| StorageError::ConnectionError(e.to_string()))
 }
 
-type Client = PooledConnection<PostgresConnectionManager<NoTls>>;
-
-pub fn reset_db(client: &mut Client) {
-    let queries = r"
-        DROP TABLE IF EXISTS total_ordered;
-        DROP TABLE IF EXISTS total_authorized;
-        DROP TABLE IF EXISTS total_collected;
-        DROP TABLE IF EXISTS bank_transactions;
-        DROP TABLE IF EXISTS payment_authorizations;
-        DROP TABLE IF EXISTS payment_collections;
-        DROP TABLE IF EXISTS product_orders;
-        DROP TABLE IF EXISTS relations;
-        
-        CREATE TABLE total_ordered (
-            id SERIAL PRIMARY KEY,
-            amount double precision,
-            occurred_on text
-        );
-
-        CREATE TABLE total_authorized (
-            id SERIAL PRIMARY KEY,
-            amount double precision,
-            occurred_on text
-        );
-
-        CREATE TABLE total_collected (
-            id SERIAL PRIMARY KEY,
-            amount double precision,
-            occurred_on text
-        );
-        
-        CREATE TABLE bank_transactions (
-            transaction_id text PRIMARY KEY,
-            amount double precision,
-            ordered_amount double precision default 0,
-            occurred_on text
-        );
-
-        CREATE TABLE payment_authorizations (
-            payment_id text,
-            order_id text,
-            amount double precision,
-            occurred_on text,
-            PRIMARY KEY (order_id, payment_id)
-        );
-
-        CREATE TABLE payment_collections (
-            payment_id text,
-            transaction_id text,
-            amount double precision,
-            occurred_on text,
-            PRIMARY KEY (transaction_id, payment_id)
-        );
-
-        CREATE TABLE product_orders (
-            order_id text PRIMARY KEY,
-            amount double precision,
-            collected_amount double precision default 0,
-            occurred_on text,
-            insurance_code text,
-            installment_type text,
-            event_type text
-        );
-        
-        CREATE TABLE relations (
-            id BIGSERIAL PRIMARY KEY,
-            payment_id text default null,
-            order_id text default null,
-            transaction_id text default null
-        );
-
-        CREATE INDEX t_id_idx ON relations(transaction_id);
-        CREATE INDEX p_id_idx ON relations(payment_id);
-        CREATE INDEX o_id_idx ON relations(order_id);
-        ";
-
-    queries.split(";").filter(|s| !s.is_empty()).for_each(|q| {
-        client.execute(q, &[]).map(|_| ()).unwrap();
-    });
+/// Empties the connection's current schema and re-applies the migrations.
+#[cfg(test)]
+pub fn reset_db(client: &mut postgres::Client) {
+    let schema: String = client
+        .query_one("SELECT current_schema()", &[])
+        .unwrap()
+        .get(0);
+    client
+        .batch_execute(&format!(
+            "DROP SCHEMA {schema} CASCADE; CREATE SCHEMA {schema};"
+        ))
+        .unwrap();
+    crate::storage::migrations::migrate(client, crate::storage::migrations::POSTGRES).unwrap();
 }
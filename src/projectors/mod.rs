@@ -1,8 +1,15 @@
 use crate::events::Event;
+use crate::storage::Session;
 
 pub mod total_authorized_projector;
 pub mod total_collected_projector;
 pub mod total_ordered_projector;
 pub trait Projector {
-    fn project(&self, event: Event) -> Result<(), String>;
+    /// Identifies the projector in checkpoints and rebuilds.
+    fn name(&self) -> &'static str;
+
+    fn project(&self, session: &mut dyn Session, event: Event) -> Result<(), String>;
+
+    /// Empties the projection, before it is rebuilt from the event log.
+    fn reset(&self, session: &mut dyn Session) -> Result<(), String>;
 }
@@ -1,5 +1,6 @@
 use crate::events::{Event, PaymentAuthorizedPayload};
 use crate::projectors::Projector;
+use crate::storage::{Session, Total};
 
 pub struct TotalAuthorizedProjector {}
 
@@ -10,22 +11,26 @@ impl TotalAuthorizedProjector {
 }
 
 impl Projector for TotalAuthorizedProjector {
-    fn project(&self, event: Event) -> Result<(), String> {
+    fn name(&self) -> &'static str {
+        "total_authorized"
+    }
+
+    fn project(&self, session: &mut dyn Session, event: Event) -> Result<(), String> {
         match event {
             Event::PaymentAuthorized(PaymentAuthorizedPayload {
                 amount,
                 occurred_on,
                 ..
-            }) => crate::pool::POOL
-                .get()
-                .unwrap()
-                .execute(
-                    r"INSERT INTO total_authorized (amount, occurred_on) VALUES($1,$2)",
-                    &[&amount, &occurred_on.to_string()],
-                )
-                .map_err(|e| e.to_string())
-                .map(|_| ()),
+            }) => session
+                .insert_total(Total::Authorized, amount, &occurred_on)
+                .map_err(|e| e.to_string()),
             _ => Ok(()),
         }
     }
+
+    fn reset(&self, session: &mut dyn Session) -> Result<(), String> {
+        session
+            .clear_total(Total::Authorized)
+            .map_err(|e| e.to_string())
+    }
 }
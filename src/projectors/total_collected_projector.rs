@@ -1,6 +1,7 @@
 use crate::events::BankTransactionIssuedPayload;
-use crate::events::Event;
+use crate::events::{ChargebackReceivedPayload, Event, PaymentRefundedPayload};
 use crate::projectors::Projector;
+use crate::storage::{BusinessKey, EventLogQuery, Session, Total};
 
 pub struct TotalCollectedProjector {}
 
@@ -10,23 +11,87 @@ impl TotalCollectedProjector {
     }
 }
 
+/// Whether an event logged before `event_id` on the bank transaction matches
+/// `reverses`. Looks at the log rather than the relations, so a rebuild nets
+/// the same events as the live projection did.
+fn logged_before(
+    session: &mut dyn Session,
+    transaction_id: &str,
+    event_id: &str,
+    reverses: fn(&Event) -> bool,
+) -> Result<bool, String> {
+    Ok(session
+        .read_events(EventLogQuery::Key(BusinessKey::Transaction, transaction_id))
+        .map_err(|e| e.to_string())?
+        .iter()
+        .take_while(|logged| logged.event.event_id() != event_id)
+        .any(|logged| reverses(&logged.event)))
+}
+
+fn is_reversal(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::PaymentRefunded(_) | Event::ChargebackReceived(_)
+    )
+}
+
+fn is_pay_back(event: &Event) -> bool {
+    matches!(event, Event::BankTransactionIssued(p) if p.amount.minor_units() < 0)
+}
+
 impl Projector for TotalCollectedProjector {
-    fn project(&self, event: Event) -> Result<(), String> {
+    fn name(&self) -> &'static str {
+        "total_collected"
+    }
+
+    // A refund or chargeback and the negative bank transaction paying it back
+    // are linked by the transaction_id; whichever is logged first is netted out,
+    // the other is skipped.
+    fn project(&self, session: &mut dyn Session, event: Event) -> Result<(), String> {
         match event {
             Event::BankTransactionIssued(BankTransactionIssuedPayload {
+                event_id,
+                transaction_id,
+                amount,
+                occurred_on,
+            }) => {
+                if amount.minor_units() < 0
+                    && logged_before(session, &transaction_id, &event_id, is_reversal)?
+                {
+                    return Ok(());
+                }
+                session
+                    .insert_total(Total::Collected, amount, &occurred_on)
+                    .map_err(|e| e.to_string())
+            }
+            Event::PaymentRefunded(PaymentRefundedPayload {
+                event_id,
+                transaction_id,
                 amount,
                 occurred_on,
                 ..
-            }) => crate::pool::POOL
-                .get()
-                .unwrap()
-                .execute(
-                    r"INSERT INTO total_collected (amount, occurred_on) VALUES($1,$2)",
-                    &[&amount, &occurred_on.to_string()],
-                )
-                .map_err(|e| e.to_string())
-                .map(|_| ()),
+            })
+            | Event::ChargebackReceived(ChargebackReceivedPayload {
+                event_id,
+                transaction_id,
+                amount,
+                occurred_on,
+                ..
+            }) => {
+                if logged_before(session, &transaction_id, &event_id, is_pay_back)? {
+                    return Ok(());
+                }
+                session
+                    .insert_total(Total::Collected, -amount, &occurred_on)
+                    .map_err(|e| e.to_string())
+            }
             _ => Ok(()),
         }
     }
+
+    fn reset(&self, session: &mut dyn Session) -> Result<(), String> {
+        session
+            .clear_total(Total::Collected)
+            .map_err(|e| e.to_string())
+    }
 }
@@ -1,6 +1,7 @@
 use crate::events::Event;
-use crate::events::ProductOrderedPayload;
+use crate::events::{EventType, ProductOrderedPayload};
 use crate::projectors::Projector;
+use crate::storage::{Session, Total};
 
 pub struct TotalOrderedProjector {}
 
@@ -11,22 +12,37 @@ impl TotalOrderedProjector {
 }
 
 impl Projector for TotalOrderedProjector {
-    fn project(&self, event: Event) -> Result<(), String> {
+    fn name(&self) -> &'static str {
+        "total_ordered"
+    }
+
+    fn project(&self, session: &mut dyn Session, event: Event) -> Result<(), String> {
         match event {
             Event::ProductOrdered(ProductOrderedPayload {
                 amount,
                 occurred_on,
+                event_type,
+                original_order_id,
                 ..
-            }) => crate::pool::POOL
-                .get()
-                .unwrap()
-                .execute(
-                    r"INSERT INTO total_ordered (amount, occurred_on) VALUES($1,$2)",
-                    &[&amount, &occurred_on.to_string()],
-                )
-                .map(|_| ())
-                .map_err(|e| e.to_string()),
+            }) => {
+                // a cancellation of another order credits it, an interruption only
+                // changes what is expected of it
+                let amount = match (original_order_id, event_type) {
+                    (None, _) => amount,
+                    (Some(_), EventType::Cancellation) => -amount,
+                    (Some(_), _) => return Ok(()),
+                };
+                session
+                    .insert_total(Total::Ordered, amount, &occurred_on)
+                    .map_err(|e| e.to_string())
+            }
             _ => Ok(()),
         }
     }
+
+    fn reset(&self, session: &mut dyn Session) -> Result<(), String> {
+        session
+            .clear_total(Total::Ordered)
+            .map_err(|e| e.to_string())
+    }
 }
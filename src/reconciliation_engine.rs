@@ -1,289 +1,1059 @@
-type Client = PooledConnection<PostgresConnectionManager<NoTls>>;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-use postgres::{NoTls, Row, Transaction};
-use r2d2_postgres::{r2d2::PooledConnection, PostgresConnectionManager};
+use chrono::{DateTime, Months, Utc};
+use serde::Serialize;
 
-use crate::events::{
-    BankTransactionIssuedPayload, Event, PaymentAuthorizedPayload, PaymentCollectedPayload,
-    ProductOrderedPayload,
+use crate::aging::{AgingFilter, AgingReport};
+use crate::config::{MatchingConfig, Tolerance};
+use crate::events::{Event, EventType, InstallmentType};
+use crate::export::{DateRange, OrderExport};
+use crate::installments::{self, Installment, PaidInstallment};
+use crate::money::{Currency, ExchangeRate, ExchangeRates, Money};
+use crate::storage::{
+    Allocation, BusinessKey, Facts, OrderBalance, PendingMatch, Session, StorageError,
+    StoredAuthorization, StoredGuarantee, StoredOrder, StoredReversal, COLLECTION,
 };
 
-pub struct ReconciliationEngine {}
+/// An order, payment or bank transaction by its ID.
+type Key = (BusinessKey, String);
+
+pub struct ReconciliationEngine {
+    matching: MatchingConfig,
+    exchange_rates: ExchangeRates,
+}
 
 impl ReconciliationEngine {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            matching: MatchingConfig::default(),
+            exchange_rates: ExchangeRates::default(),
+        }
+    }
+
+    /// Reports amounts within the configured tolerances as near-matches.
+    pub fn with_matching(mut self, matching: MatchingConfig) -> Self {
+        self.matching = matching;
+        self
     }
 
-    pub fn reconcile(&self, event: Event) -> Result<(), postgres::Error> {
-        let mut client = crate::pool::POOL.get().unwrap();
-        match event {
+    /// Converts amounts in different currencies, instead of refusing to match them.
+    pub fn with_exchange_rates(mut self, exchange_rates: ExchangeRates) -> Self {
+        self.exchange_rates = exchange_rates;
+        self
+    }
+
+    /// Stores the event and recomputes the matched amounts of every order and bank
+    /// transaction it links through a payment. An order may be paid by many payments
+    /// and a transaction settle many of them, the amounts are summed over the links.
+    /// A payment authorized for several orders has its collections split across
+    /// them, see [`allocations`].
+    ///
+    /// Events may arrive in any order. One referring to an order, authorization or
+    /// bank transaction not stored yet is queued as a pending match, and matching is
+    /// retried for it when that key arrives, so the outcome does not depend on the
+    /// order of arrival.
+    pub fn reconcile(&self, session: &mut dyn Session, event: Event) -> Result<(), StorageError> {
+        let (arrived, mut retry, awaits) = match &event {
             Event::BankTransactionIssued(payload) => {
-                save_bank_transaction_issued(&mut client, payload.clone())?;
-                reconciliate_bank_transaction_issued(&mut client, payload.clone())?;
+                session.save_bank_transaction_issued(payload)?;
+                let transaction = (BusinessKey::Transaction, payload.transaction_id.clone());
+                (Some(transaction.clone()), vec![transaction], vec![])
             }
             Event::PaymentAuthorized(payload) => {
-                save_payment_authorized(&mut client, payload.clone())?;
-                reconciliate_payment_authorized(&mut client, payload.clone())?;
+                session.save_payment_authorized(payload)?;
+                let order = (BusinessKey::Order, payload.order_id.clone());
+                let payment = (BusinessKey::Payment, payload.payment_id.clone());
+                (
+                    Some(payment.clone()),
+                    vec![order.clone(), payment.clone()],
+                    vec![PendingMatch {
+                        awaited: order,
+                        key: payment,
+                    }],
+                )
             }
             Event::PaymentCollected(payload) => {
-                save_payment_collected(&mut client, payload.clone())?;
-                reconciliate_payment_collected(&mut client, payload)?;
+                session.save_payment_collected(payload)?;
+                settlement(&payload.payment_id, &payload.transaction_id)
+            }
+            // refunds and chargebacks link a payment and a bank transaction like a
+            // collection, and are netted out of it
+            Event::PaymentRefunded(payload) => {
+                session.save_payment_refunded(payload)?;
+                settlement(&payload.payment_id, &payload.transaction_id)
+            }
+            Event::ChargebackReceived(payload) => {
+                session.save_chargeback_received(payload)?;
+                settlement(&payload.payment_id, &payload.transaction_id)
             }
             Event::ProductOrdered(payload) => {
-                save_product_ordered(&mut client, payload.clone())?;
-                reconciliate_product_ordered(&mut client, payload)?;
+                session.save_product_ordered(payload)?;
+                let order = (BusinessKey::Order, payload.order_id.clone());
+                match &payload.original_order_id {
+                    // a cancellation or interruption adjusts the order it refers to
+                    Some(original_order_id) => {
+                        let original = (BusinessKey::Order, original_order_id.clone());
+                        (
+                            Some(order.clone()),
+                            vec![order.clone(), original.clone()],
+                            vec![PendingMatch {
+                                awaited: original,
+                                key: order,
+                            }],
+                        )
+                    }
+                    None => (Some(order.clone()), vec![order], vec![]),
+                }
             }
         };
 
-        Ok(())
+        if let Some(payment_id) = event.payment_id() {
+            let payment = session.payment_facts(payment_id)?;
+            session.save_allocations(payment_id, &allocations(&payment))?;
+        }
+
+        for pending in awaits {
+            let (key, id) = &pending.awaited;
+            if !session.is_stored(*key, id)? {
+                session.add_pending_match(&pending)?;
+            }
+        }
+        if let Some((key, id)) = arrived {
+            retry.extend(
+                session
+                    .take_pending_matches(key, &id)?
+                    .into_iter()
+                    .map(|pending| pending.key),
+            );
+        }
+
+        let mut orders = BTreeSet::new();
+        let mut transactions = BTreeSet::new();
+        for (key, id) in retry {
+            orders.extend(session.related(key, &id, BusinessKey::Order)?);
+            transactions.extend(session.related(key, &id, BusinessKey::Transaction)?);
+            match key {
+                BusinessKey::Order => {
+                    orders.insert(id);
+                }
+                BusinessKey::Transaction => {
+                    transactions.insert(id);
+                }
+                BusinessKey::Payment => {}
+            }
+        }
+        for order_id in &orders {
+            self.update_expected_amount(session, order_id)?;
+            session.update_collected_amount(order_id)?;
+        }
+        transactions
+            .iter()
+            .try_for_each(|transaction_id| session.update_ordered_amount(transaction_id))
+    }
+
+    /// Applies the cancellations and interruptions stored for the order, which is
+    /// left alone if it is not stored or adjusts another one.
+    fn update_expected_amount(
+        &self,
+        session: &mut dyn Session,
+        order_id: &str,
+    ) -> Result<(), StorageError> {
+        let lifecycle = session.order_lifecycle(order_id)?;
+        match lifecycle.iter().find(|o| o.order_id == order_id) {
+            Some(order) if order.original_order_id.is_none() => {
+                let adjustments = lifecycle.iter().filter(|o| o.order_id != order_id);
+                let expected = expected_amount(order, adjustments, &self.exchange_rates);
+                session.update_expected_amount(order_id, expected.total)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The expected installments of every stored order, with the payments applied to
+    /// them.
+    pub fn installments(
+        &self,
+        session: &mut dyn Session,
+    ) -> Result<Vec<PaidInstallment>, StorageError> {
+        Ok(paid_installments(&session.facts()?, &self.exchange_rates))
+    }
+
+    /// The unreconciled orders, authorizations, collections, refunds, chargebacks
+    /// and bank transactions the filter keeps, by how long before `as_of` they
+    /// occurred.
+    pub fn aging_report(
+        &self,
+        session: &mut dyn Session,
+        as_of: DateTime<Utc>,
+        filter: &AgingFilter,
+    ) -> Result<AgingReport, StorageError> {
+        let facts = session.facts()?;
+        let report = ReconciliationReport::from_facts(&facts, &self.matching, &self.exchange_rates);
+        AgingReport::new(&facts, &report, as_of, filter).map_err(StorageError::QueryError)
+    }
+
+    /// The reconciliation state of every stored order that occurred in the range.
+    pub fn order_exports(
+        &self,
+        session: &mut dyn Session,
+        range: &DateRange,
+    ) -> Result<Vec<OrderExport>, StorageError> {
+        let balances = session.order_balances()?;
+        let transactions = session.transaction_balances()?;
+        let facts = session.facts()?;
+        Ok(OrderExport::from_facts(
+            &facts,
+            &balances,
+            &transactions,
+            range,
+        ))
+    }
+
+    /// The status of every stored order, payment and bank transaction.
+    pub fn report(&self, session: &mut dyn Session) -> Result<ReconciliationReport, StorageError> {
+        Ok(ReconciliationReport::from_facts(
+            &session.facts()?,
+            &self.matching,
+            &self.exchange_rates,
+        ))
     }
 }
 
-fn reconciliate_bank_transaction_issued(
-    client: &mut Client,
-    payload: BankTransactionIssuedPayload,
-) -> Result<(), postgres::Error> {
-    let rows = client
-        .query(
-            r"SELECT transaction_id, order_id, payment_id 
-        FROM relations 
-        WHERE transaction_id=$1
-        AND order_id IS NOT NULL
-        AND payment_id IS NOT NULL",
-            &[&payload.transaction_id],
-        )
-        .unwrap();
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectionStatus {
+    /// the collected amount equals the expected one
+    Full,
+    /// less than the expected amount has been collected, possibly nothing yet
+    Partial,
+    Over,
+}
 
-    do_reconcile(client, rows)
+impl std::fmt::Display for CollectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectionStatus::Full => f.write_str("full"),
+            CollectionStatus::Partial => f.write_str("partial"),
+            CollectionStatus::Over => f.write_str("over"),
+        }
+    }
 }
 
-fn reconciliate_product_ordered(
-    client: &mut Client,
-    payload: ProductOrderedPayload,
-) -> Result<(), postgres::Error> {
-    let rows = client
-        .query(
-            r"SELECT transaction_id, order_id, payment_id 
-        FROM relations 
-        WHERE order_id=$1
-        AND transaction_id IS NOT NULL
-        AND payment_id IS NOT NULL",
-            &[&payload.order_id],
-        )
-        .unwrap();
-    do_reconcile(client, rows)
+/// How much of an order has been collected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderCollection {
+    pub order_id: String,
+    pub amount: Money,
+    /// the amount once cancellations and interruptions are applied
+    pub expected: Money,
+    pub collected: Money,
+    /// still to be collected, negative when the order is over-collected
+    pub outstanding: Money,
+    pub status: CollectionStatus,
 }
 
-fn reconciliate_payment_authorized(
-    client: &mut Client,
-    payload: PaymentAuthorizedPayload,
-) -> Result<(), postgres::Error> {
-    let rows = client
-        .query(
-            r"SELECT r.transaction_id, r.order_id, r.payment_id 
-        FROM relations r, product_orders po
-        WHERE r.order_id=$1
-        AND po.order_id=$1
-        AND transaction_id IS NOT NULL
-        AND payment_id=$2",
-            &[&payload.order_id, &payload.payment_id],
-        )
-        .unwrap();
-    do_reconcile(client, rows)
+impl From<OrderBalance> for OrderCollection {
+    fn from(balance: OrderBalance) -> Self {
+        let outstanding = balance.expected_amount - balance.collected_amount;
+        let status = if outstanding.minor_units() > 0 {
+            CollectionStatus::Partial
+        } else if outstanding.minor_units() < 0 {
+            CollectionStatus::Over
+        } else {
+            CollectionStatus::Full
+        };
+        Self {
+            order_id: balance.order_id,
+            amount: balance.amount,
+            expected: balance.expected_amount,
+            collected: balance.collected_amount,
+            outstanding,
+            status,
+        }
+    }
 }
 
-fn reconciliate_payment_collected(
-    client: &mut Client,
-    payload: PaymentCollectedPayload,
-) -> Result<(), postgres::Error> {
-    let rows = client
-        .query(
-            r"SELECT r.transaction_id, r.order_id, r.payment_id 
-        FROM relations r, bank_transactions bt
-        WHERE r.transaction_id=$1
-        AND bt.transaction_id=$1
-        AND r.order_id IS NOT NULL
-        AND r.payment_id=$2",
-            &[&payload.transaction_id, &payload.payment_id],
-        )
-        .unwrap();
-    do_reconcile(client, rows)
+/// The part of an order's collected amount allocated to one of its guarantees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuaranteeCollection {
+    pub order_id: String,
+    pub guarantee_type: String,
+    pub price: Money,
+    pub collected: Money,
 }
 
-fn do_reconcile(t: &mut Client, rows: Vec<Row>) -> Result<(), postgres::Error> {
-    rows.into_iter()
-        .map(|x| (x.get(0), x.get(1), x.get(2)))
-        .for_each(|(t_id, o_id, p_id): (String, String, String)| {
-            let _ = t.query(
-                r"UPDATE bank_transactions
-        SET ordered_amount = ordered_amount + (
-            SELECT po.amount 
-            FROM product_orders po
-            WHERE po.order_id=$1
-        ) 
-        WHERE transaction_id=$2",
-                &[&o_id, &t_id],
-            );
-            let _ = t.query(
-                r"UPDATE product_orders
-        SET collected_amount = collected_amount + (
-            SELECT pc.amount 
-            FROM payment_collections pc 
-            WHERE pc.payment_id=$1
-        )
-        WHERE order_id=$2",
-                &[&p_id, &o_id],
-            );
-        });
-    Ok(())
+impl GuaranteeCollection {
+    /// Splits the collected amount of the order across its guarantees in proportion
+    /// to their prices, see [`Money::allocate`].
+    pub fn allocate(balance: &OrderBalance, guarantees: &[StoredGuarantee]) -> Vec<Self> {
+        let prices = guarantees
+            .iter()
+            .map(|g| g.price.minor_units())
+            .collect::<Vec<_>>();
+        guarantees
+            .iter()
+            .zip(balance.collected_amount.allocate(&prices))
+            .map(|(g, collected)| Self {
+                order_id: balance.order_id.clone(),
+                guarantee_type: g.guarantee_type.clone(),
+                price: g.price,
+                collected,
+            })
+            .collect()
+    }
+}
+
+/// The prices and collected amounts of one guarantee type, over every order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuaranteeTotal {
+    pub guarantee_type: String,
+    pub price: Money,
+    pub collected: Money,
 }
 
-fn save_bank_transaction_issued(
-    client: &mut Client,
-    payload: BankTransactionIssuedPayload,
-) -> Result<(), postgres::Error> {
-    client
-        .execute(
-            r"INSERT INTO bank_transactions (transaction_id, amount,occurred_on) VALUES($1,$2,$3)",
-            &[
-                &payload.transaction_id,
-                &payload.amount,
-                &payload.occurred_on.to_string(),
-            ],
+/// Adds up the allocations by guarantee type and currency, sorted that way.
+pub fn guarantee_totals(collections: &[GuaranteeCollection]) -> Vec<GuaranteeTotal> {
+    let mut totals = BTreeMap::<(&str, Currency), GuaranteeTotal>::new();
+    for c in collections {
+        let total = totals
+            .entry((&c.guarantee_type, c.price.currency()))
+            .or_insert_with(|| GuaranteeTotal {
+                guarantee_type: c.guarantee_type.clone(),
+                price: Money::zero(c.price.currency()),
+                collected: Money::zero(c.price.currency()),
+            });
+        total.price = total.price + c.price;
+        total.collected = total.collected + c.collected;
+    }
+    totals.into_values().collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconciliationStatus {
+    Matched,
+    /// the amounts differ, but by no more than the configured tolerance
+    NearMatch,
+    /// everything expected has arrived but the amounts differ
+    AmountMismatch,
+    /// an order no payment has been authorized for, or a bank transaction settling
+    /// payments that were never authorized for a known order
+    MissingPayment,
+    /// an authorized payment, or an order's payment, that has not been collected
+    MissingCollection,
+    /// a collection whose bank transaction has not been received
+    MissingBankTransaction,
+    /// nothing expected refers to it: a payment of an unknown order, a collection
+    /// never authorized or a bank transaction settling no payment
+    Orphan,
+    /// some amounts are in another currency and there is no rate to convert them
+    CurrencyMismatch,
+    /// the guarantee prices of an order do not add up to its amount
+    GuaranteeMismatch,
+}
+
+impl ReconciliationStatus {
+    /// Whether the amounts matched, exactly or within tolerance.
+    pub fn is_reconciled(&self) -> bool {
+        matches!(
+            self,
+            ReconciliationStatus::Matched | ReconciliationStatus::NearMatch
         )
-        .map(|_| ())
+    }
 }
 
-fn save_product_ordered(
-    client: &mut Client,
-    payload: ProductOrderedPayload,
-) -> Result<(), postgres::Error> {
-    client.execute(r"
-     INSERT INTO product_orders (order_id, amount,occurred_on, event_type, installment_type, insurance_code) 
-     VALUES($1,$2,$3,$4,$5,$6)
-     ", &[
-        &payload.order_id,
-        &payload.amount,
-        &payload.occurred_on.to_string(),
-        &payload.event_type.to_string(),
-        &payload.installment_type.to_string(),
-        &payload.insurance_code,
-     ])
-    .map(|_| ())
+impl std::fmt::Display for ReconciliationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReconciliationStatus::Matched => f.write_str("matched"),
+            ReconciliationStatus::NearMatch => f.write_str("near_match"),
+            ReconciliationStatus::AmountMismatch => f.write_str("amount_mismatch"),
+            ReconciliationStatus::MissingPayment => f.write_str("missing_payment"),
+            ReconciliationStatus::MissingCollection => f.write_str("missing_collection"),
+            ReconciliationStatus::MissingBankTransaction => f.write_str("missing_bank_transaction"),
+            ReconciliationStatus::Orphan => f.write_str("orphan"),
+            ReconciliationStatus::CurrencyMismatch => f.write_str("currency_mismatch"),
+            ReconciliationStatus::GuaranteeMismatch => f.write_str("guarantee_mismatch"),
+        }
+    }
 }
 
-fn save_payment_collected(
-    client: &mut Client,
-    payload: PaymentCollectedPayload,
-) -> Result<(), postgres::Error> {
-    let mut t = client.transaction().unwrap();
-    t.execute(
-        r"
-    INSERT INTO payment_collections (payment_id, transaction_id,amount,occurred_on) 
-    VALUES($1,$2,$3,$4)
-    ",
-        &[
-            &payload.payment_id,
-            &payload.transaction_id,
-            &payload.amount,
-            &payload.occurred_on.to_string(),
+/// What `reconcile` does for an event linking a payment and a bank transaction:
+/// nothing arrives, both are retried and each waits for the other.
+fn settlement(
+    payment_id: &str,
+    transaction_id: &str,
+) -> (Option<Key>, Vec<Key>, Vec<PendingMatch>) {
+    let payment = (BusinessKey::Payment, payment_id.to_owned());
+    let transaction = (BusinessKey::Transaction, transaction_id.to_owned());
+    (
+        None,
+        vec![payment.clone(), transaction.clone()],
+        vec![
+            PendingMatch {
+                awaited: payment.clone(),
+                key: transaction.clone(),
+            },
+            PendingMatch {
+                awaited: transaction,
+                key: payment,
+            },
         ],
     )
-    .map(|_| ())?;
+}
 
-    let r1 = t
-        .query(
-            "SELECT * FROM relations WHERE transaction_id=$1 AND payment_id IS NULL",
-            &[&payload.transaction_id],
-        )
-        .unwrap();
-
-    if r1.len() > 0 {
-        // ho almeno un transaction id corrispondente con payment id nullo
-        t.execute(
-            r"UPDATE relations SET payment_id=$1 WHERE transaction_id=$2",
-            &[&payload.payment_id, &payload.transaction_id],
-        )?;
+/// `Matched` when the amounts are equal, `NearMatch` when they differ within the
+/// tolerance, rounded to the minor unit, and `None` otherwise.
+fn compare(expected: Money, actual: Money, tolerance: Tolerance) -> Option<ReconciliationStatus> {
+    let difference = (actual - expected)
+        .checked_abs()
+        .map_or(i64::MAX, |difference| difference.minor_units());
+    let allowed = Money::from_major(tolerance.absolute, expected.currency())
+        .minor_units()
+        .max((tolerance.percentage / 100.0 * expected.minor_units().abs() as f64).round() as i64);
+    if difference == 0 {
+        Some(ReconciliationStatus::Matched)
+    } else if difference <= allowed {
+        Some(ReconciliationStatus::NearMatch)
     } else {
-        let r2 = t
-            .query(
-                "SELECT * FROM relations WHERE payment_id=$1 AND transaction_id IS NULL",
-                &[&payload.payment_id],
-            )
-            .unwrap();
-
-        if r2.len() > 0 {
-            // ho almeno un payment id corrispondente con transaction id nullo
-            t.execute(
-                r"UPDATE relations SET transaction_id=$1 WHERE payment_id=$2",
-                &[&payload.transaction_id, &payload.payment_id],
-            )?;
-        } else {
-            t.execute(
-                r"
-                INSERT INTO relations (transaction_id, payment_id) VALUES ($1,$2)",
-                &[&payload.transaction_id, &payload.payment_id],
-            )
-            .map(|_| ())?;
+        None
+    }
+}
+
+/// Amounts added up in one currency, converting the others when there is a rate.
+struct Converted {
+    total: Money,
+    rates: Vec<ExchangeRate>,
+    /// some amounts could not be converted and were left out
+    refused: bool,
+}
+
+impl Converted {
+    fn zero(currency: Currency) -> Self {
+        Self {
+            total: Money::zero(currency),
+            rates: vec![],
+            refused: false,
         }
     }
-    t.commit()
+
+    fn sum<I>(currency: Currency, amounts: I, exchange_rates: &ExchangeRates) -> Self
+    where
+        I: IntoIterator<Item = Money>,
+    {
+        let mut converted = Self::zero(currency);
+        for amount in amounts {
+            if amount.currency() == currency {
+                converted.total = converted.total + amount;
+            } else if let Some(rate) = exchange_rates.get(amount.currency(), currency) {
+                converted.total = converted.total + amount.convert(&rate);
+                if !converted.rates.contains(&rate) {
+                    converted.rates.push(rate);
+                }
+            } else {
+                converted.refused = true;
+            }
+        }
+        converted
+    }
+
+    fn and(mut self, other: Converted) -> Self {
+        self.total = self.total + other.total;
+        for rate in other.rates {
+            if !self.rates.contains(&rate) {
+                self.rates.push(rate);
+            }
+        }
+        self.refused |= other.refused;
+        self
+    }
 }
 
-fn save_payment_authorized(
-    client: &mut Client,
-    payload: PaymentAuthorizedPayload,
-) -> Result<(), postgres::Error> {
-    let mut t = client.transaction().unwrap();
-    t.execute(
-        r"
-    INSERT INTO payment_authorizations (payment_id, order_id,amount,occurred_on) 
-    VALUES($1,$2,$3,$4)",
-        &[
-            &payload.payment_id,
-            &payload.order_id,
-            &payload.amount,
-            &payload.occurred_on.to_string(),
-        ],
-    )
-    .map(|_| ())?;
+/// What is still expected for the order once it is adjusted, see
+/// [`expected_installments`].
+fn expected_amount<'a, I>(
+    order: &StoredOrder,
+    adjustments: I,
+    exchange_rates: &ExchangeRates,
+) -> Converted
+where
+    I: IntoIterator<Item = &'a StoredOrder>,
+{
+    expected_installments(order, adjustments, exchange_rates).1
+}
 
-    let r1 = t
-        .query(
-            "SELECT * FROM relations WHERE order_id=$1 AND payment_id IS NULL",
-            &[&payload.order_id],
-        )
-        .unwrap();
+/// The installments still expected for the order once it is adjusted, and what
+/// they add up to. An interruption keeps the installments covered until it,
+/// prorating the running one; cancellations then credit their amount from the latest
+/// installments, down to nothing. An order with an unknown installment type is due
+/// at once.
+fn expected_installments<'a, I>(
+    order: &StoredOrder,
+    adjustments: I,
+    exchange_rates: &ExchangeRates,
+) -> (Vec<Installment>, Converted)
+where
+    I: IntoIterator<Item = &'a StoredOrder>,
+{
+    let mut interrupted_on = None;
+    let mut credits = vec![];
+    for adjustment in adjustments {
+        match adjustment.event_type.parse() {
+            Ok(EventType::Interruption) => {
+                let on = adjustment.occurred_on;
+                interrupted_on =
+                    Some(interrupted_on.map_or(on, |first: DateTime<Utc>| first.min(on)));
+            }
+            Ok(EventType::Cancellation) => credits.push(adjustment.amount),
+            _ => {}
+        }
+    }
+    let mut schedule = match order.installment_type.parse::<InstallmentType>() {
+        Ok(installment_type) => {
+            installments::schedule(order.amount, &installment_type, order.occurred_on)
+        }
+        Err(_) => vec![Installment {
+            due_on: order.occurred_on,
+            covers_until: order.occurred_on + Months::new(installments::TERM_MONTHS),
+            amount: order.amount,
+        }],
+    };
+    if let Some(on) = interrupted_on {
+        schedule = installments::interrupted(&schedule, on);
+    }
+    let credited = Converted::sum(order.amount.currency(), credits, exchange_rates);
+    installments::credit(&mut schedule, credited.total);
+    let total = Money::sum(
+        order.amount.currency(),
+        schedule.iter().map(|installment| installment.amount),
+    );
+    (schedule, Converted { total, ..credited })
+}
 
-    if r1.len() > 0 {
-        t.execute(
-            r"UPDATE relations SET payment_id=$1 WHERE order_id=$2",
-            &[&payload.payment_id, &payload.order_id],
-        )
-        .map(|_| ())?;
-    } else {
-        let r2 = t
-            .query(
-                "SELECT * FROM relations WHERE payment_id=$1 AND order_id IS NULL",
-                &[&payload.payment_id],
-            )
-            .unwrap();
+/// A collection, or a refund or chargeback as a collection of its negated amount.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Settlement<'a> {
+    /// `collection`, `refund` or `chargeback`
+    pub kind: &'a str,
+    pub payment_id: &'a str,
+    pub transaction_id: &'a str,
+    pub amount: Money,
+}
 
-        if r2.len() > 0 {
-            t.execute(
-                r"UPDATE relations SET order_id=$1 WHERE payment_id=$2",
-                &[&payload.order_id, &payload.payment_id],
-            )
-            .map(|_| ())?;
-        } else {
-            t.execute(
-                r"
-                INSERT INTO relations (order_id, payment_id) VALUES ($1,$2)",
-                &[&payload.order_id, &payload.payment_id],
+/// The collections followed by the refunds and chargebacks.
+fn settlements(facts: &Facts) -> impl Iterator<Item = Settlement<'_>> {
+    let collections = facts.collections.iter().map(|c| Settlement {
+        kind: COLLECTION,
+        payment_id: &c.payment_id,
+        transaction_id: &c.transaction_id,
+        amount: c.amount,
+    });
+    let reversals = facts.reversals.iter().map(|r| Settlement {
+        kind: &r.kind,
+        payment_id: &r.payment_id,
+        transaction_id: &r.transaction_id,
+        amount: -r.amount,
+    });
+    collections.chain(reversals)
+}
+
+/// Values by business key and ID.
+type ByKey<'a, T> = HashMap<BusinessKey, HashMap<&'a str, Vec<T>>>;
+
+fn lookup<'m, T>(map: &'m ByKey<'_, T>, key: BusinessKey, id: &str) -> &'m [T] {
+    map.get(&key)
+        .and_then(|ids| ids.get(id))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// The facts referring to each order, payment and bank transaction, so that they
+/// are looked up by `(BusinessKey, id)` rather than scanned for every key.
+pub(crate) struct FactsIndex<'a> {
+    pub orders: HashMap<&'a str, &'a StoredOrder>,
+    pub transactions: HashSet<&'a str>,
+    /// the cancellations and interruptions of each order
+    adjustments: HashMap<&'a str, Vec<&'a StoredOrder>>,
+    /// by order
+    guarantees: HashMap<&'a str, Vec<&'a StoredGuarantee>>,
+    /// by order and by payment
+    authorizations: ByKey<'a, &'a StoredAuthorization>,
+    /// by payment and by bank transaction
+    settlements: ByKey<'a, Settlement<'a>>,
+    /// by payment
+    reversals: HashMap<&'a str, Vec<&'a StoredReversal>>,
+    /// by order and by bank transaction
+    allocations: ByKey<'a, &'a Allocation>,
+}
+
+impl<'a> FactsIndex<'a> {
+    pub fn new(facts: &'a Facts) -> Self {
+        let mut index = Self {
+            orders: HashMap::new(),
+            transactions: facts
+                .transactions
+                .iter()
+                .map(|t| t.transaction_id.as_str())
+                .collect(),
+            adjustments: HashMap::new(),
+            guarantees: HashMap::new(),
+            authorizations: HashMap::new(),
+            settlements: HashMap::new(),
+            reversals: HashMap::new(),
+            allocations: HashMap::new(),
+        };
+        for o in &facts.orders {
+            index.orders.insert(&o.order_id, o);
+            if let Some(original_order_id) = &o.original_order_id {
+                index
+                    .adjustments
+                    .entry(original_order_id)
+                    .or_default()
+                    .push(o);
+            }
+        }
+        for g in &facts.guarantees {
+            index.guarantees.entry(&g.order_id).or_default().push(g);
+        }
+        for a in &facts.authorizations {
+            for (key, id) in [
+                (BusinessKey::Order, &a.order_id),
+                (BusinessKey::Payment, &a.payment_id),
+            ] {
+                index
+                    .authorizations
+                    .entry(key)
+                    .or_default()
+                    .entry(id)
+                    .or_default()
+                    .push(a);
+            }
+        }
+        for s in settlements(facts) {
+            for (key, id) in [
+                (BusinessKey::Payment, s.payment_id),
+                (BusinessKey::Transaction, s.transaction_id),
+            ] {
+                index
+                    .settlements
+                    .entry(key)
+                    .or_default()
+                    .entry(id)
+                    .or_default()
+                    .push(s);
+            }
+        }
+        for r in &facts.reversals {
+            index.reversals.entry(&r.payment_id).or_default().push(r);
+        }
+        for a in &facts.allocations {
+            for key in [BusinessKey::Order, BusinessKey::Transaction] {
+                index
+                    .allocations
+                    .entry(key)
+                    .or_default()
+                    .entry(a.id(key))
+                    .or_default()
+                    .push(a);
+            }
+        }
+        index
+    }
+
+    /// The cancellations and interruptions of the order.
+    pub fn adjustments(&self, order_id: &str) -> &[&'a StoredOrder] {
+        self.adjustments.get(order_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn guarantees(&self, order_id: &str) -> &[&'a StoredGuarantee] {
+        self.guarantees.get(order_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// The authorizations of an order or a payment.
+    pub fn authorizations(&self, key: BusinessKey, id: &str) -> &[&'a StoredAuthorization] {
+        lookup(&self.authorizations, key, id)
+    }
+
+    /// The collections, refunds and chargebacks of a payment or a bank transaction.
+    pub fn settlements(&self, key: BusinessKey, id: &str) -> &[Settlement<'a>] {
+        lookup(&self.settlements, key, id)
+    }
+
+    pub fn reversals(&self, payment_id: &str) -> &[&'a StoredReversal] {
+        self.reversals.get(payment_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// The allocations to an order or of a bank transaction.
+    pub fn allocations(&self, key: BusinessKey, id: &str) -> &[&'a Allocation] {
+        lookup(&self.allocations, key, id)
+    }
+}
+
+/// Splits every collection, refund and chargeback across the orders its payment is
+/// authorized for, in proportion to the authorized amounts (see
+/// [`Money::allocate`]). Those of a payment never authorized are left out.
+pub fn allocations(facts: &Facts) -> Vec<Allocation> {
+    let index = FactsIndex::new(facts);
+    settlements(facts)
+        .flat_map(|s| {
+            let authorized = index.authorizations(BusinessKey::Payment, s.payment_id);
+            let weights = authorized
+                .iter()
+                .map(|a| a.amount.minor_units())
+                .collect::<Vec<_>>();
+            authorized
+                .iter()
+                .zip(s.amount.allocate(&weights))
+                .map(|(a, amount)| Allocation {
+                    kind: s.kind.to_owned(),
+                    order_id: a.order_id.clone(),
+                    payment_id: s.payment_id.to_owned(),
+                    transaction_id: s.transaction_id.to_owned(),
+                    amount,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The expected installments of every order, by `order_id` and due date, with the
+/// payments settled for the order applied to them. Payments are applied in the order
+/// they were authorized, each with what is allocated to the order of what stored
+/// bank transactions settled of it, net of refunds and chargebacks. Amounts in
+/// another currency are converted with `exchange_rates`, or left out.
+pub fn paid_installments(facts: &Facts, exchange_rates: &ExchangeRates) -> Vec<PaidInstallment> {
+    let index = FactsIndex::new(facts);
+    facts
+        .orders
+        .iter()
+        .filter(|o| o.original_order_id.is_none())
+        .flat_map(|o| {
+            let currency = o.amount.currency();
+            let (schedule, _) = expected_installments(
+                o,
+                index.adjustments(&o.order_id).iter().copied(),
+                exchange_rates,
+            );
+            let mut settled = HashMap::<&str, Vec<Money>>::new();
+            for allocated in index.allocations(BusinessKey::Order, &o.order_id) {
+                if index
+                    .transactions
+                    .contains(allocated.transaction_id.as_str())
+                {
+                    settled
+                        .entry(&allocated.payment_id)
+                        .or_default()
+                        .push(allocated.amount);
+                }
+            }
+            let mut authorizations = index
+                .authorizations(BusinessKey::Order, &o.order_id)
+                .to_vec();
+            authorizations.sort_by(|a, b| {
+                (a.occurred_on, &a.payment_id).cmp(&(b.occurred_on, &b.payment_id))
+            });
+            let payments = authorizations.into_iter().map(|a| {
+                let settled = Converted::sum(
+                    currency,
+                    settled.remove(a.payment_id.as_str()).into_iter().flatten(),
+                    exchange_rates,
+                );
+                (a.payment_id.as_str(), settled.total)
+            });
+            installments::apply_payments(&o.order_id, &schedule, payments)
+        })
+        .collect()
+}
+
+/// The status of one order, payment or bank transaction, with the amount it should
+/// match and the amount matched so far, in the currency of the expected one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reconciliation {
+    pub key: BusinessKey,
+    pub id: String,
+    pub status: ReconciliationStatus,
+    pub expected: Money,
+    pub actual: Money,
+    /// the rates amounts in other currencies were converted at
+    pub rates: Vec<ExchangeRate>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReconciliationReport {
+    /// by `order_id`
+    pub orders: Vec<Reconciliation>,
+    /// by `payment_id`
+    pub payments: Vec<Reconciliation>,
+    /// by `transaction_id`
+    pub transactions: Vec<Reconciliation>,
+    /// where each entry is in its list, by key and ID
+    index: HashMap<BusinessKey, HashMap<String, usize>>,
+}
+
+impl ReconciliationReport {
+    /// Payments are compared with the tolerance of the order they are authorized
+    /// for, bank transactions with the default one. Amounts in another currency are
+    /// converted with `exchange_rates`, or reported as a currency mismatch.
+    ///
+    /// Refunds and chargebacks count as collections of their negated amount, and
+    /// lower what is expected of the payment they reverse. Orders and bank
+    /// transactions are credited with the allocations linking them, so that a
+    /// payment shared by several orders is not counted in full for each.
+    pub fn from_facts(
+        facts: &Facts,
+        matching: &MatchingConfig,
+        exchange_rates: &ExchangeRates,
+    ) -> Self {
+        let index = FactsIndex::new(facts);
+        let orders = &index.orders;
+        let transactions = &index.transactions;
+        let settled = |payment_id: &str, currency: Currency| {
+            Converted::sum(
+                currency,
+                index
+                    .settlements(BusinessKey::Payment, payment_id)
+                    .iter()
+                    .filter(|s| transactions.contains(s.transaction_id))
+                    .map(|s| s.amount),
+                exchange_rates,
             )
-            .map(|_| ())?;
+        };
+        // the payment is authorized for a stored order
+        let ordered = |payment_id: &str| {
+            index
+                .authorizations(BusinessKey::Payment, payment_id)
+                .iter()
+                .any(|a| orders.contains_key(a.order_id.as_str()))
+        };
+        let order_tolerance = |order_id: &str| {
+            orders
+                .get(order_id)
+                .map(|o| matching.tolerance(&o.event_type, &o.insurance_code))
+                .unwrap_or(matching.default)
+        };
+
+        let order_reports = facts
+            .orders
+            .iter()
+            .filter_map(|o| {
+                if let Some(original_order_id) = &o.original_order_id {
+                    // folded into the order it adjusts, once that one is stored
+                    return (!orders.contains_key(original_order_id.as_str())).then(|| {
+                        Reconciliation {
+                            key: BusinessKey::Order,
+                            id: o.order_id.clone(),
+                            status: ReconciliationStatus::Orphan,
+                            expected: o.amount,
+                            actual: Money::zero(o.amount.currency()),
+                            rates: vec![],
+                        }
+                    });
+                }
+                let payments = index
+                    .authorizations(BusinessKey::Order, &o.order_id)
+                    .iter()
+                    .map(|a| a.payment_id.as_str())
+                    .collect::<Vec<_>>();
+                let currency = o.amount.currency();
+                let expected = expected_amount(
+                    o,
+                    index.adjustments(&o.order_id).iter().copied(),
+                    exchange_rates,
+                );
+                let actual = Converted::sum(
+                    currency,
+                    index
+                        .allocations(BusinessKey::Order, &o.order_id)
+                        .iter()
+                        .filter(|a| transactions.contains(a.transaction_id.as_str()))
+                        .map(|a| a.amount),
+                    exchange_rates,
+                );
+                let guarantees = index.guarantees(&o.order_id);
+                let priced = Money::sum(currency, guarantees.iter().map(|g| g.price));
+                let status = if expected.refused || actual.refused {
+                    ReconciliationStatus::CurrencyMismatch
+                } else if !guarantees.is_empty() && priced != o.amount {
+                    ReconciliationStatus::GuaranteeMismatch
+                } else if let Some(status) =
+                    compare(expected.total, actual.total, order_tolerance(&o.order_id))
+                {
+                    status
+                } else if payments.is_empty() {
+                    ReconciliationStatus::MissingPayment
+                } else if payments
+                    .iter()
+                    .any(|p| index.settlements(BusinessKey::Payment, p).is_empty())
+                {
+                    ReconciliationStatus::MissingCollection
+                } else if payments
+                    .iter()
+                    .flat_map(|p| index.settlements(BusinessKey::Payment, p))
+                    .any(|s| !transactions.contains(s.transaction_id))
+                {
+                    ReconciliationStatus::MissingBankTransaction
+                } else {
+                    ReconciliationStatus::AmountMismatch
+                };
+                let expected_total = expected.total;
+                // keeps the actual total, with the rates of both
+                let converted = actual.and(Converted {
+                    total: Money::zero(currency),
+                    ..expected
+                });
+                Some(Reconciliation {
+                    key: BusinessKey::Order,
+                    id: o.order_id.clone(),
+                    status,
+                    expected: expected_total,
+                    actual: converted.total,
+                    rates: converted.rates,
+                })
+            })
+            .collect();
+
+        let payment_ids = facts
+            .authorizations
+            .iter()
+            .map(|a| a.payment_id.as_str())
+            .chain(settlements(facts).map(|s| s.payment_id))
+            .collect::<BTreeSet<_>>();
+        let payment_reports = payment_ids
+            .into_iter()
+            .map(|payment_id| {
+                let payment_authorizations = index.authorizations(BusinessKey::Payment, payment_id);
+                let payment_settlements = index.settlements(BusinessKey::Payment, payment_id);
+                let currency = payment_authorizations
+                    .iter()
+                    .map(|a| a.amount.currency())
+                    .chain(payment_settlements.iter().map(|s| s.amount.currency()))
+                    .next()
+                    .unwrap_or_default();
+                let reversed = index.reversals(payment_id).iter().map(|r| -r.amount);
+                let expected = Converted::sum(
+                    currency,
+                    payment_authorizations
+                        .iter()
+                        .map(|a| a.amount)
+                        .chain(reversed),
+                    exchange_rates,
+                );
+                let actual = settled(payment_id, currency);
+                let tolerance = payment_authorizations
+                    .iter()
+                    .find(|a| orders.contains_key(a.order_id.as_str()))
+                    .map(|a| order_tolerance(&a.order_id))
+                    .unwrap_or(matching.default);
+                let status = if !ordered(payment_id) {
+                    ReconciliationStatus::Orphan
+                } else if payment_settlements.is_empty() {
+                    ReconciliationStatus::MissingCollection
+                } else if payment_settlements
+                    .iter()
+                    .any(|s| !transactions.contains(s.transaction_id))
+                {
+                    ReconciliationStatus::MissingBankTransaction
+                } else if expected.refused || actual.refused {
+                    ReconciliationStatus::CurrencyMismatch
+                } else if let Some(status) = compare(expected.total, actual.total, tolerance) {
+                    status
+                } else {
+                    ReconciliationStatus::AmountMismatch
+                };
+                Reconciliation {
+                    key: BusinessKey::Payment,
+                    id: payment_id.to_owned(),
+                    status,
+                    expected: expected.total,
+                    actual: actual.total,
+                    rates: expected.and(actual).rates,
+                }
+            })
+            .collect();
+
+        let transaction_reports = facts
+            .transactions
+            .iter()
+            .map(|t| {
+                let settled_here = index.settlements(BusinessKey::Transaction, &t.transaction_id);
+                let allocated_here = index.allocations(BusinessKey::Transaction, &t.transaction_id);
+                let actual = Converted::sum(
+                    t.amount.currency(),
+                    allocated_here
+                        .iter()
+                        .filter(|a| orders.contains_key(a.order_id.as_str()))
+                        .map(|a| a.amount),
+                    exchange_rates,
+                );
+                let status = if settled_here.is_empty() {
+                    ReconciliationStatus::Orphan
+                } else if actual.refused {
+                    ReconciliationStatus::CurrencyMismatch
+                } else if let Some(status) = compare(t.amount, actual.total, matching.default) {
+                    status
+                } else if settled_here.iter().any(|s| !ordered(s.payment_id))
+                    || allocated_here
+                        .iter()
+                        .any(|a| !orders.contains_key(a.order_id.as_str()))
+                {
+                    ReconciliationStatus::MissingPayment
+                } else {
+                    ReconciliationStatus::AmountMismatch
+                };
+                Reconciliation {
+                    key: BusinessKey::Transaction,
+                    id: t.transaction_id.clone(),
+                    status,
+                    expected: t.amount,
+                    actual: actual.total,
+                    rates: actual.rates,
+                }
+            })
+            .collect();
+
+        Self::new(order_reports, payment_reports, transaction_reports)
+    }
+
+    fn new(
+        orders: Vec<Reconciliation>,
+        payments: Vec<Reconciliation>,
+        transactions: Vec<Reconciliation>,
+    ) -> Self {
+        let mut index = HashMap::<BusinessKey, HashMap<String, usize>>::new();
+        for reconciliations in [&orders, &payments, &transactions] {
+            for (i, r) in reconciliations.iter().enumerate() {
+                index.entry(r.key).or_default().insert(r.id.clone(), i);
+            }
+        }
+        Self {
+            orders,
+            payments,
+            transactions,
+            index,
         }
     }
-    t.commit()
+
+    /// The reconciliation of one order, payment or bank transaction.
+    pub fn get(&self, key: BusinessKey, id: &str) -> Option<&Reconciliation> {
+        let reconciliations = match key {
+            BusinessKey::Order => &self.orders,
+            BusinessKey::Payment => &self.payments,
+            BusinessKey::Transaction => &self.transactions,
+        };
+        self.index
+            .get(&key)
+            .and_then(|ids| ids.get(id))
+            .and_then(|i| reconciliations.get(*i))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Reconciliation> {
+        self.orders
+            .iter()
+            .chain(&self.payments)
+            .chain(&self.transactions)
+    }
+
+    /// Everything that did not match, exactly or within tolerance.
+    pub fn unreconciled(&self) -> impl Iterator<Item = &Reconciliation> {
+        self.iter().filter(|r| !r.status.is_reconciled())
+    }
 }
@@ -0,0 +1,820 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+
+use crate::events::{
+    BankTransactionIssuedPayload, ChargebackReceivedPayload, Event, PaymentAuthorizedPayload,
+    PaymentCollectedPayload, PaymentRefundedPayload, ProductOrderedPayload,
+};
+use crate::money::{Currency, Money};
+use crate::storage::migrations::MigrationStatus;
+use crate::storage::{
+    Allocation, BusinessKey, EventLogQuery, Facts, LoggedEvent, OrderBalance, PendingMatch,
+    Session, StorageError, Store, StoredAuthorization, StoredCollection, StoredGuarantee,
+    StoredOrder, StoredReversal, StoredTransaction, Total, TransactionBalance, CHARGEBACK, REFUND,
+};
+
+/// Keeps the tables of the SQL backends in plain collections, following the same
+/// semantics, so that reconciliation can be tested without a database.
+#[derive(Default)]
+pub struct InMemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryState {
+    pub bank_transactions: BTreeMap<String, BankTransactionRow>,
+    pub payment_authorizations: BTreeMap<(String, String), PaymentRow>,
+    pub payment_collections: BTreeMap<(String, String), PaymentRow>,
+    /// keyed by (kind, transaction_id, payment_id)
+    pub payment_reversals: BTreeMap<(String, String, String), PaymentRow>,
+    pub product_orders: BTreeMap<String, ProductOrderRow>,
+    /// the price, keyed by (order_id, guarantee_type)
+    pub order_guarantees: BTreeMap<(String, String), Money>,
+    /// the allocations of each payment, by `payment_id`
+    pub relations: BTreeMap<String, Vec<Allocation>>,
+    pub totals: HashMap<Total, Vec<(Money, DateTime<Utc>)>>,
+    pub processed_events: BTreeMap<String, ProcessedEventRow>,
+    /// the entry with sequence `n` is at index `n - 1`
+    pub event_log: Vec<LoggedEvent>,
+    /// the `event_id`s of `event_log`, which is unique on them
+    event_ids: HashSet<String>,
+    pub checkpoints: BTreeMap<String, CheckpointRow>,
+    pub pending_matches: BTreeSet<PendingMatch>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BankTransactionRow {
+    pub amount: Money,
+    pub ordered_amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentRow {
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProductOrderRow {
+    pub amount: Money,
+    pub expected_amount: Money,
+    pub collected_amount: Money,
+    pub occurred_on: DateTime<Utc>,
+    pub insurance_code: String,
+    pub installment_type: String,
+    pub event_type: String,
+    pub original_order_id: Option<String>,
+}
+
+impl ProductOrderRow {
+    fn stored(&self, order_id: &str) -> StoredOrder {
+        StoredOrder {
+            order_id: order_id.to_owned(),
+            amount: self.amount,
+            expected_amount: self.expected_amount,
+            original_order_id: self.original_order_id.clone(),
+            event_type: self.event_type.clone(),
+            installment_type: self.installment_type.clone(),
+            insurance_code: self.insurance_code.clone(),
+            occurred_on: self.occurred_on,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessedEventRow {
+    pub event_type: String,
+    pub processed_on: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckpointRow {
+    pub sequence: i64,
+    pub last_error: Option<String>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MemoryState {
+    /// The sum of the projected rows in `currency`.
+    pub fn total(&self, total: Total, currency: Currency) -> Money {
+        Money::sum(
+            currency,
+            self.totals
+                .get(&total)
+                .into_iter()
+                .flatten()
+                .map(|(amount, _)| *amount)
+                .filter(|amount| amount.currency() == currency),
+        )
+    }
+
+    /// Orders whose `collected_amount` differs from their `amount`.
+    pub fn unreconciled_orders(&self) -> impl Iterator<Item = (&String, &ProductOrderRow)> {
+        self.product_orders
+            .iter()
+            .filter(|(_, o)| o.collected_amount != o.amount)
+    }
+
+    pub fn unreconciled_transactions(
+        &self,
+    ) -> impl Iterator<Item = (&String, &BankTransactionRow)> {
+        self.bank_transactions
+            .iter()
+            .filter(|(_, t)| t.ordered_amount != t.amount)
+    }
+
+    fn duplicate_key(table: &str) -> StorageError {
+        StorageError::QueryError(format!("duplicate key value in {table}"))
+    }
+}
+
+impl Store for InMemoryStore {
+    fn session(&self) -> Result<Box<dyn Session + '_>, StorageError> {
+        Ok(Box::new(InMemorySession {
+            state: self.state(),
+            undo_log: vec![],
+        }))
+    }
+
+    /// There is no schema to migrate in memory.
+    fn migrate(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        Ok(vec![])
+    }
+
+    fn migration_status(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        Ok(vec![])
+    }
+}
+
+type Undo = Box<dyn FnOnce(&mut MemoryState)>;
+
+/// Writes to the state in place, holding it locked, and records how to undo each
+/// write. Dropping the session without committing it undoes them, newest first.
+pub struct InMemorySession<'a> {
+    state: MutexGuard<'a, MemoryState>,
+    undo_log: Vec<Undo>,
+}
+
+impl InMemorySession<'_> {
+    fn on_rollback(&mut self, undo: impl FnOnce(&mut MemoryState) + 'static) {
+        self.undo_log.push(Box::new(undo));
+    }
+
+    /// Sets or, with `None`, removes the row of a table, returning the previous one.
+    fn put<K, V>(
+        &mut self,
+        table: fn(&mut MemoryState) -> &mut BTreeMap<K, V>,
+        key: K,
+        row: Option<V>,
+    ) -> Option<V>
+    where
+        K: Ord + Clone + 'static,
+        V: Clone + 'static,
+    {
+        let previous = match row {
+            Some(row) => table(&mut self.state).insert(key.clone(), row),
+            None => table(&mut self.state).remove(&key),
+        };
+        let restored = previous.clone();
+        self.on_rollback(move |state| {
+            match restored {
+                Some(row) => table(state).insert(key, row),
+                None => table(state).remove(&key),
+            };
+        });
+        previous
+    }
+
+    /// Updates a row in place, if the table has it.
+    fn update<K, V>(
+        &mut self,
+        table: fn(&mut MemoryState) -> &mut BTreeMap<K, V>,
+        key: &K,
+        f: impl FnOnce(&mut V),
+    ) where
+        K: Ord + Clone + 'static,
+        V: Clone + 'static,
+    {
+        if let Some(mut row) = table(&mut self.state).get(key).cloned() {
+            f(&mut row);
+            self.put(table, key.clone(), Some(row));
+        }
+    }
+
+    /// Inserts a new row, failing like a primary key if the table has it already.
+    fn insert<K, V>(
+        &mut self,
+        name: &str,
+        table: fn(&mut MemoryState) -> &mut BTreeMap<K, V>,
+        key: K,
+        row: V,
+    ) -> Result<(), StorageError>
+    where
+        K: Ord + Clone + 'static,
+        V: Clone + 'static,
+    {
+        if table(&mut self.state).contains_key(&key) {
+            return Err(MemoryState::duplicate_key(name));
+        }
+        self.put(table, key, Some(row));
+        Ok(())
+    }
+
+    fn save_reversal(
+        &mut self,
+        kind: &str,
+        payment_id: &str,
+        transaction_id: &str,
+        amount: Money,
+        occurred_on: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "payment_reversals",
+            |s| &mut s.payment_reversals,
+            (
+                kind.to_owned(),
+                transaction_id.to_owned(),
+                payment_id.to_owned(),
+            ),
+            PaymentRow {
+                amount,
+                occurred_on,
+            },
+        )
+    }
+}
+
+impl Drop for InMemorySession<'_> {
+    fn drop(&mut self) {
+        while let Some(undo) = self.undo_log.pop() {
+            undo(&mut self.state);
+        }
+    }
+}
+
+impl Session for InMemorySession<'_> {
+    fn commit(mut self: Box<Self>) -> Result<(), StorageError> {
+        self.undo_log.clear();
+        Ok(())
+    }
+
+    fn is_processed(&mut self, event_id: &str) -> Result<bool, StorageError> {
+        Ok(self.state.processed_events.contains_key(event_id))
+    }
+
+    fn mark_processed(
+        &mut self,
+        event_id: &str,
+        event_type: &str,
+        processed_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "processed_events",
+            |s| &mut s.processed_events,
+            event_id.to_owned(),
+            ProcessedEventRow {
+                event_type: event_type.to_owned(),
+                processed_on: *processed_on,
+            },
+        )
+    }
+
+    fn append_event(
+        &mut self,
+        event: &Event,
+        received_at: &DateTime<Utc>,
+    ) -> Result<i64, StorageError> {
+        if !self.state.event_ids.insert(event.event_id().to_owned()) {
+            return Err(MemoryState::duplicate_key("event_log"));
+        }
+        let sequence = self.state.event_log.len() as i64 + 1;
+        self.state.event_log.push(LoggedEvent {
+            sequence,
+            event: event.clone(),
+            received_at: *received_at,
+        });
+        let event_id = event.event_id().to_owned();
+        self.on_rollback(move |state| {
+            state.event_log.pop();
+            state.event_ids.remove(&event_id);
+        });
+        Ok(sequence)
+    }
+
+    fn read_events(&mut self, query: EventLogQuery) -> Result<Vec<LoggedEvent>, StorageError> {
+        let log = &self.state.event_log;
+        Ok(match query {
+            EventLogQuery::Range { from, to } => {
+                let index = |sequence: i64| (sequence.max(1) as usize - 1).min(log.len());
+                log[index(from)..index(to).max(index(from))].to_vec()
+            }
+            EventLogQuery::Key(key, id) => log
+                .iter()
+                .filter(|e| key.of(&e.event) == Some(id))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    fn log_head(&mut self) -> Result<i64, StorageError> {
+        Ok(self.state.event_log.last().map_or(0, |e| e.sequence))
+    }
+
+    fn checkpoint(&mut self, projector: &str) -> Result<Option<i64>, StorageError> {
+        Ok(self.state.checkpoints.get(projector).map(|c| c.sequence))
+    }
+
+    fn save_checkpoint(&mut self, projector: &str, sequence: i64) -> Result<(), StorageError> {
+        self.put(
+            |s| &mut s.checkpoints,
+            projector.to_owned(),
+            Some(CheckpointRow {
+                sequence,
+                last_error: None,
+            }),
+        );
+        Ok(())
+    }
+
+    fn save_projector_error(&mut self, projector: &str, error: &str) -> Result<(), StorageError> {
+        let checkpoint = CheckpointRow {
+            last_error: Some(error.to_owned()),
+            ..self
+                .state
+                .checkpoints
+                .get(projector)
+                .cloned()
+                .unwrap_or_default()
+        };
+        self.put(
+            |s| &mut s.checkpoints,
+            projector.to_owned(),
+            Some(checkpoint),
+        );
+        Ok(())
+    }
+
+    fn projector_error(&mut self, projector: &str) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .state
+            .checkpoints
+            .get(projector)
+            .and_then(|c| c.last_error.clone()))
+    }
+
+    fn save_bank_transaction_issued(
+        &mut self,
+        payload: &BankTransactionIssuedPayload,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "bank_transactions",
+            |s| &mut s.bank_transactions,
+            payload.transaction_id.clone(),
+            BankTransactionRow {
+                amount: payload.amount,
+                ordered_amount: Money::zero(payload.amount.currency()),
+                occurred_on: payload.occurred_on,
+            },
+        )
+    }
+
+    fn save_payment_authorized(
+        &mut self,
+        payload: &PaymentAuthorizedPayload,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "payment_authorizations",
+            |s| &mut s.payment_authorizations,
+            (payload.order_id.clone(), payload.payment_id.clone()),
+            PaymentRow {
+                amount: payload.amount,
+                occurred_on: payload.occurred_on,
+            },
+        )
+    }
+
+    fn save_payment_collected(
+        &mut self,
+        payload: &PaymentCollectedPayload,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "payment_collections",
+            |s| &mut s.payment_collections,
+            (payload.transaction_id.clone(), payload.payment_id.clone()),
+            PaymentRow {
+                amount: payload.amount,
+                occurred_on: payload.occurred_on,
+            },
+        )
+    }
+
+    fn save_product_ordered(
+        &mut self,
+        payload: &ProductOrderedPayload,
+    ) -> Result<(), StorageError> {
+        self.insert(
+            "product_orders",
+            |s| &mut s.product_orders,
+            payload.order_id.clone(),
+            ProductOrderRow {
+                amount: payload.amount,
+                expected_amount: payload.amount,
+                collected_amount: Money::zero(payload.amount.currency()),
+                occurred_on: payload.occurred_on,
+                insurance_code: payload.insurance_code.clone(),
+                installment_type: payload.installment_type.to_string(),
+                event_type: payload.event_type.to_string(),
+                original_order_id: payload.original_order_id.clone(),
+            },
+        )?;
+        for guarantee in &payload.guarantees {
+            self.insert(
+                "order_guarantees",
+                |s| &mut s.order_guarantees,
+                (payload.order_id.clone(), guarantee.guarantee_type.clone()),
+                guarantee.price,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn save_payment_refunded(
+        &mut self,
+        payload: &PaymentRefundedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            REFUND,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn save_chargeback_received(
+        &mut self,
+        payload: &ChargebackReceivedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            CHARGEBACK,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn payment_facts(&mut self, payment_id: &str) -> Result<Facts, StorageError> {
+        let state = &self.state;
+        Ok(Facts {
+            authorizations: state
+                .payment_authorizations
+                .iter()
+                .filter(|((_, p_id), _)| p_id == payment_id)
+                .map(|((order_id, payment_id), a)| StoredAuthorization {
+                    order_id: order_id.clone(),
+                    payment_id: payment_id.clone(),
+                    amount: a.amount,
+                    occurred_on: a.occurred_on,
+                })
+                .collect(),
+            collections: state
+                .payment_collections
+                .iter()
+                .filter(|((_, p_id), _)| p_id == payment_id)
+                .map(|((transaction_id, payment_id), c)| StoredCollection {
+                    payment_id: payment_id.clone(),
+                    transaction_id: transaction_id.clone(),
+                    amount: c.amount,
+                    occurred_on: c.occurred_on,
+                })
+                .collect(),
+            reversals: state
+                .payment_reversals
+                .iter()
+                .filter(|((_, _, p_id), _)| p_id == payment_id)
+                .map(|((kind, transaction_id, payment_id), r)| StoredReversal {
+                    kind: kind.clone(),
+                    payment_id: payment_id.clone(),
+                    transaction_id: transaction_id.clone(),
+                    amount: r.amount,
+                    occurred_on: r.occurred_on,
+                })
+                .collect(),
+            ..Facts::default()
+        })
+    }
+
+    fn save_allocations(
+        &mut self,
+        payment_id: &str,
+        allocations: &[Allocation],
+    ) -> Result<(), StorageError> {
+        let allocations = (!allocations.is_empty()).then(|| allocations.to_vec());
+        self.put(|s| &mut s.relations, payment_id.to_owned(), allocations);
+        Ok(())
+    }
+
+    fn related(
+        &mut self,
+        from: BusinessKey,
+        id: &str,
+        to: BusinessKey,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut related = self
+            .state
+            .relations
+            .values()
+            .flatten()
+            .filter(|allocation| allocation.id(from) == id)
+            .map(|allocation| allocation.id(to).to_owned())
+            .collect::<Vec<_>>();
+        related.sort();
+        related.dedup();
+        Ok(related)
+    }
+
+    fn is_stored(&mut self, key: BusinessKey, id: &str) -> Result<bool, StorageError> {
+        let state = &self.state;
+        Ok(match key {
+            BusinessKey::Order => state.product_orders.contains_key(id),
+            BusinessKey::Payment => state
+                .payment_authorizations
+                .keys()
+                .any(|(_, payment_id)| payment_id == id),
+            BusinessKey::Transaction => state.bank_transactions.contains_key(id),
+        })
+    }
+
+    fn add_pending_match(&mut self, pending: &PendingMatch) -> Result<(), StorageError> {
+        if self.state.pending_matches.insert(pending.clone()) {
+            let pending = pending.clone();
+            self.on_rollback(move |state| {
+                state.pending_matches.remove(&pending);
+            });
+        }
+        Ok(())
+    }
+
+    fn take_pending_matches(
+        &mut self,
+        awaited: BusinessKey,
+        id: &str,
+    ) -> Result<Vec<PendingMatch>, StorageError> {
+        let taken = self
+            .state
+            .pending_matches
+            .iter()
+            .filter(|pending| pending.awaited.0 == awaited && pending.awaited.1 == id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for pending in &taken {
+            self.state.pending_matches.remove(pending);
+        }
+        let restored = taken.clone();
+        self.on_rollback(move |state| state.pending_matches.extend(restored));
+        Ok(taken)
+    }
+
+    fn pending_matches(&mut self) -> Result<Vec<PendingMatch>, StorageError> {
+        Ok(self.state.pending_matches.iter().cloned().collect())
+    }
+
+    fn order_lifecycle(&mut self, order_id: &str) -> Result<Vec<StoredOrder>, StorageError> {
+        Ok(self
+            .state
+            .product_orders
+            .iter()
+            .filter(|(id, o)| *id == order_id || o.original_order_id.as_deref() == Some(order_id))
+            .map(|(id, o)| o.stored(id))
+            .collect())
+    }
+
+    fn update_expected_amount(
+        &mut self,
+        order_id: &str,
+        expected: Money,
+    ) -> Result<(), StorageError> {
+        self.update(
+            |s| &mut s.product_orders,
+            &order_id.to_owned(),
+            |o| o.expected_amount = expected,
+        );
+        Ok(())
+    }
+
+    fn update_collected_amount(&mut self, order_id: &str) -> Result<(), StorageError> {
+        let state = &self.state;
+        let Some(currency) = state
+            .product_orders
+            .get(order_id)
+            .map(|o| o.amount.currency())
+        else {
+            return Ok(());
+        };
+        let collected = state
+            .relations
+            .values()
+            .flatten()
+            .filter(|a| {
+                a.order_id == order_id
+                    && a.amount.currency() == currency
+                    && state.bank_transactions.contains_key(&a.transaction_id)
+            })
+            .map(|a| a.amount.minor_units())
+            .sum();
+        self.update(
+            |s| &mut s.product_orders,
+            &order_id.to_owned(),
+            |o| o.collected_amount = Money::new(collected, currency),
+        );
+        Ok(())
+    }
+
+    fn update_ordered_amount(&mut self, transaction_id: &str) -> Result<(), StorageError> {
+        let state = &self.state;
+        let Some(currency) = state
+            .bank_transactions
+            .get(transaction_id)
+            .map(|t| t.amount.currency())
+        else {
+            return Ok(());
+        };
+        let ordered = state
+            .relations
+            .values()
+            .flatten()
+            .filter(|a| {
+                a.transaction_id == transaction_id
+                    && a.amount.currency() == currency
+                    && state.product_orders.contains_key(&a.order_id)
+            })
+            .map(|a| a.amount.minor_units())
+            .sum();
+        self.update(
+            |s| &mut s.bank_transactions,
+            &transaction_id.to_owned(),
+            |t| t.ordered_amount = Money::new(ordered, currency),
+        );
+        Ok(())
+    }
+
+    fn order_balances(&mut self) -> Result<Vec<OrderBalance>, StorageError> {
+        Ok(self
+            .state
+            .product_orders
+            .iter()
+            .filter(|(_, o)| o.original_order_id.is_none())
+            .map(|(order_id, o)| OrderBalance {
+                order_id: order_id.clone(),
+                amount: o.amount,
+                expected_amount: o.expected_amount,
+                collected_amount: o.collected_amount,
+            })
+            .collect())
+    }
+
+    fn transaction_balances(&mut self) -> Result<Vec<TransactionBalance>, StorageError> {
+        Ok(self
+            .state
+            .bank_transactions
+            .iter()
+            .map(|(transaction_id, t)| TransactionBalance {
+                transaction_id: transaction_id.clone(),
+                amount: t.amount,
+                ordered_amount: t.ordered_amount,
+            })
+            .collect())
+    }
+
+    fn guarantees(&mut self) -> Result<Vec<StoredGuarantee>, StorageError> {
+        Ok(self
+            .state
+            .order_guarantees
+            .iter()
+            .map(|((order_id, guarantee_type), price)| StoredGuarantee {
+                order_id: order_id.clone(),
+                guarantee_type: guarantee_type.clone(),
+                price: *price,
+            })
+            .collect())
+    }
+
+    fn facts(&mut self) -> Result<Facts, StorageError> {
+        let guarantees = self.guarantees()?;
+        let state = &self.state;
+        let mut collections = state
+            .payment_collections
+            .iter()
+            .map(|((transaction_id, payment_id), c)| StoredCollection {
+                payment_id: payment_id.clone(),
+                transaction_id: transaction_id.clone(),
+                amount: c.amount,
+                occurred_on: c.occurred_on,
+            })
+            .collect::<Vec<_>>();
+        collections.sort_by(|a, b| {
+            (&a.payment_id, &a.transaction_id).cmp(&(&b.payment_id, &b.transaction_id))
+        });
+        let mut reversals = state
+            .payment_reversals
+            .iter()
+            .map(|((kind, transaction_id, payment_id), r)| StoredReversal {
+                kind: kind.clone(),
+                payment_id: payment_id.clone(),
+                transaction_id: transaction_id.clone(),
+                amount: r.amount,
+                occurred_on: r.occurred_on,
+            })
+            .collect::<Vec<_>>();
+        reversals.sort_by(|a, b| {
+            (&a.payment_id, &a.transaction_id, &a.kind).cmp(&(
+                &b.payment_id,
+                &b.transaction_id,
+                &b.kind,
+            ))
+        });
+        let mut allocations = state
+            .relations
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        allocations.sort_by(|a, b| {
+            (&a.order_id, &a.payment_id, &a.transaction_id, &a.kind).cmp(&(
+                &b.order_id,
+                &b.payment_id,
+                &b.transaction_id,
+                &b.kind,
+            ))
+        });
+        Ok(Facts {
+            orders: state
+                .product_orders
+                .iter()
+                .map(|(order_id, o)| o.stored(order_id))
+                .collect(),
+            guarantees,
+            authorizations: state
+                .payment_authorizations
+                .iter()
+                .map(|((order_id, payment_id), a)| StoredAuthorization {
+                    order_id: order_id.clone(),
+                    payment_id: payment_id.clone(),
+                    amount: a.amount,
+                    occurred_on: a.occurred_on,
+                })
+                .collect(),
+            collections,
+            reversals,
+            transactions: state
+                .bank_transactions
+                .iter()
+                .map(|(transaction_id, t)| StoredTransaction {
+                    transaction_id: transaction_id.clone(),
+                    amount: t.amount,
+                    occurred_on: t.occurred_on,
+                })
+                .collect(),
+            allocations,
+        })
+    }
+
+    fn insert_total(
+        &mut self,
+        total: Total,
+        amount: Money,
+        occurred_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.state
+            .totals
+            .entry(total)
+            .or_default()
+            .push((amount, *occurred_on));
+        self.on_rollback(move |state| {
+            state.totals.entry(total).or_default().pop();
+        });
+        Ok(())
+    }
+
+    fn clear_total(&mut self, total: Total) -> Result<(), StorageError> {
+        if let Some(rows) = self.state.totals.remove(&total) {
+            self.on_rollback(move |state| {
+                state.totals.insert(total, rows);
+            });
+        }
+        Ok(())
+    }
+}
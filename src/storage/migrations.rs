@@ -0,0 +1,217 @@
+use chrono::{SecondsFormat, Utc};
+
+use crate::storage::StorageError;
+
+/// A schema change, applied once per database in `version` order.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    /// `None` while the migration is pending
+    pub applied_on: Option<String>,
+}
+
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../../migrations/postgres/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "processed_events",
+        sql: include_str!("../../migrations/postgres/0002_processed_events.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "event_log",
+        sql: include_str!("../../migrations/postgres/0003_event_log.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "projector_checkpoints",
+        sql: include_str!("../../migrations/postgres/0004_projector_checkpoints.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "projector_errors",
+        sql: include_str!("../../migrations/postgres/0005_projector_errors.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "payment_allocation",
+        sql: include_str!("../../migrations/postgres/0006_payment_allocation.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "money",
+        sql: include_str!("../../migrations/postgres/0007_money.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "pending_matches",
+        sql: include_str!("../../migrations/postgres/0008_pending_matches.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "order_lifecycle",
+        sql: include_str!("../../migrations/postgres/0009_order_lifecycle.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "payment_reversals",
+        sql: include_str!("../../migrations/postgres/0010_payment_reversals.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "order_guarantees",
+        sql: include_str!("../../migrations/postgres/0011_order_guarantees.sql"),
+    },
+];
+
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../../migrations/sqlite/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "processed_events",
+        sql: include_str!("../../migrations/sqlite/0002_processed_events.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "event_log",
+        sql: include_str!("../../migrations/sqlite/0003_event_log.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "projector_checkpoints",
+        sql: include_str!("../../migrations/sqlite/0004_projector_checkpoints.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "projector_errors",
+        sql: include_str!("../../migrations/sqlite/0005_projector_errors.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "payment_allocation",
+        sql: include_str!("../../migrations/sqlite/0006_payment_allocation.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "money",
+        sql: include_str!("../../migrations/sqlite/0007_money.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "pending_matches",
+        sql: include_str!("../../migrations/sqlite/0008_pending_matches.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "order_lifecycle",
+        sql: include_str!("../../migrations/sqlite/0009_order_lifecycle.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "payment_reversals",
+        sql: include_str!("../../migrations/sqlite/0010_payment_reversals.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "order_guarantees",
+        sql: include_str!("../../migrations/sqlite/0011_order_guarantees.sql"),
+    },
+];
+
+const CREATE_MIGRATIONS_TABLE: &str = r"
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version bigint PRIMARY KEY,
+        name text NOT NULL,
+        applied_on text NOT NULL
+    )";
+
+/// The few statements the runner needs from a SQL connection.
+pub(crate) trait MigrationConnection {
+    fn batch_execute(&mut self, sql: &str) -> Result<(), StorageError>;
+    fn applied_migrations(&mut self) -> Result<Vec<(i64, String)>, StorageError>;
+    fn record_migration(
+        &mut self,
+        migration: &Migration,
+        applied_on: &str,
+    ) -> Result<(), StorageError>;
+}
+
+pub(crate) fn status(
+    connection: &mut dyn MigrationConnection,
+    migrations: &[Migration],
+) -> Result<Vec<MigrationStatus>, StorageError> {
+    connection.batch_execute(CREATE_MIGRATIONS_TABLE)?;
+    let applied = connection.applied_migrations()?;
+    Ok(migrations
+        .iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            name: m.name.to_owned(),
+            applied_on: applied
+                .iter()
+                .find(|(version, _)| *version == m.version)
+                .map(|(_, applied_on)| applied_on.clone()),
+        })
+        .collect())
+}
+
+/// Applies every pending migration in a single transaction and returns them.
+pub(crate) fn migrate(
+    connection: &mut dyn MigrationConnection,
+    migrations: &[Migration],
+) -> Result<Vec<MigrationStatus>, StorageError> {
+    let pending = status(connection, migrations)?
+        .into_iter()
+        .filter(|s| s.applied_on.is_none())
+        .map(|s| s.version)
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let applied_on = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    connection.batch_execute("BEGIN")?;
+    let result = migrations
+        .iter()
+        .filter(|m| pending.contains(&m.version))
+        .map(|m| {
+            connection
+                .batch_execute(m.sql)
+                .and_then(|_| connection.record_migration(m, &applied_on))
+                .map_err(|e| {
+                    StorageError::QueryError(format!("migration {} {}: {e}", m.version, m.name))
+                })
+                .map(|_| MigrationStatus {
+                    version: m.version,
+                    name: m.name.to_owned(),
+                    applied_on: Some(applied_on.clone()),
+                })
+        })
+        .collect::<Result<Vec<_>, _>>();
+
+    match result {
+        Ok(applied) => {
+            connection.batch_execute("COMMIT")?;
+            Ok(applied)
+        }
+        Err(e) => {
+            connection.batch_execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::{Backend, DatabaseConfig};
+use crate::events::{
+    BankTransactionIssuedPayload, ChargebackReceivedPayload, Event, PaymentAuthorizedPayload,
+    PaymentCollectedPayload, PaymentRefundedPayload, ProductOrderedPayload,
+};
+use crate::money::{Currency, Money};
+use crate::storage::migrations::MigrationStatus;
+
+pub mod memory;
+pub mod migrations;
+pub mod postgres;
+pub mod sqlite;
+
+#[derive(Debug)]
+pub enum StorageError {
+    ConnectionError(String),
+    QueryError(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::ConnectionError(s) => f.write_fmt(format_args!("Connection Error: {s}")),
+            StorageError::QueryError(s) => f.write_fmt(format_args!("Query Error: {s}")),
+        }
+    }
+}
+
+/// An order with the amount collected for it so far, in the order's currency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderBalance {
+    pub order_id: String,
+    pub amount: Money,
+    /// the amount once cancellations and interruptions are applied
+    pub expected_amount: Money,
+    pub collected_amount: Money,
+}
+
+/// A bank transaction with the amount it settled for orders so far, in its currency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransactionBalance {
+    pub transaction_id: String,
+    pub amount: Money,
+    pub ordered_amount: Money,
+}
+
+/// A coverage of an order, priced in the order's currency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredGuarantee {
+    pub order_id: String,
+    pub guarantee_type: String,
+    pub price: Money,
+}
+
+/// The stored events reconciliation statuses are computed from, each list sorted by
+/// its key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Facts {
+    pub orders: Vec<StoredOrder>,
+    /// by `order_id` and `guarantee_type`
+    pub guarantees: Vec<StoredGuarantee>,
+    pub authorizations: Vec<StoredAuthorization>,
+    pub collections: Vec<StoredCollection>,
+    pub reversals: Vec<StoredReversal>,
+    pub transactions: Vec<StoredTransaction>,
+    /// by `order_id`, `payment_id` and `transaction_id`
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredOrder {
+    pub order_id: String,
+    pub amount: Money,
+    pub expected_amount: Money,
+    pub original_order_id: Option<String>,
+    pub event_type: String,
+    pub installment_type: String,
+    pub insurance_code: String,
+    pub occurred_on: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredAuthorization {
+    pub order_id: String,
+    pub payment_id: String,
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredCollection {
+    pub payment_id: String,
+    pub transaction_id: String,
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+/// A refund or chargeback of a collected payment, `amount` being what it gave back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredReversal {
+    /// `refund` or `chargeback`
+    pub kind: String,
+    pub payment_id: String,
+    pub transaction_id: String,
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+pub const COLLECTION: &str = "collection";
+pub const REFUND: &str = "refund";
+pub const CHARGEBACK: &str = "chargeback";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredTransaction {
+    pub transaction_id: String,
+    pub amount: Money,
+    pub occurred_on: DateTime<Utc>,
+}
+
+/// The part of a collection, or of a refund or chargeback as a negative amount,
+/// credited to one of the orders its payment is authorized for: a row of the
+/// `relations` table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Allocation {
+    /// `collection`, `refund` or `chargeback`
+    pub kind: String,
+    pub order_id: String,
+    pub payment_id: String,
+    pub transaction_id: String,
+    pub amount: Money,
+}
+
+impl Allocation {
+    /// The order, payment or bank transaction the allocation links.
+    pub fn id(&self, key: BusinessKey) -> &str {
+        match key {
+            BusinessKey::Order => &self.order_id,
+            BusinessKey::Payment => &self.payment_id,
+            BusinessKey::Transaction => &self.transaction_id,
+        }
+    }
+}
+
+/// An entry of the append-only `event_log` table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedEvent {
+    pub sequence: i64,
+    pub event: Event,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Which entries of the event log to read, always returned in `sequence` order.
+#[derive(Clone, Copy, Debug)]
+pub enum EventLogQuery<'a> {
+    /// entries with `from <= sequence < to`
+    Range { from: i64, to: i64 },
+    /// entries referring to the given order, payment or bank transaction
+    Key(BusinessKey, &'a str),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BusinessKey {
+    Order,
+    Payment,
+    Transaction,
+}
+
+impl BusinessKey {
+    pub fn column(&self) -> &'static str {
+        match self {
+            BusinessKey::Order => "order_id",
+            BusinessKey::Payment => "payment_id",
+            BusinessKey::Transaction => "transaction_id",
+        }
+    }
+
+    pub fn from_column(column: &str) -> Option<Self> {
+        [
+            BusinessKey::Order,
+            BusinessKey::Payment,
+            BusinessKey::Transaction,
+        ]
+        .into_iter()
+        .find(|key| key.column() == column)
+    }
+
+    pub fn of<'e>(&self, event: &'e Event) -> Option<&'e str> {
+        match self {
+            BusinessKey::Order => event.order_id(),
+            BusinessKey::Payment => event.payment_id(),
+            BusinessKey::Transaction => event.transaction_id(),
+        }
+    }
+}
+
+/// An event that referenced a key not stored yet: once the `awaited` key arrives,
+/// matching is retried for `key`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PendingMatch {
+    pub awaited: (BusinessKey, String),
+    pub key: (BusinessKey, String),
+}
+
+/// Decodes a `pending_matches` row of the SQL backends.
+fn pending_match(
+    awaited_column: &str,
+    awaited_id: String,
+    key_column: &str,
+    key_id: String,
+) -> Result<PendingMatch, StorageError> {
+    let key = |column: &str| {
+        BusinessKey::from_column(column).ok_or_else(|| {
+            StorageError::QueryError(format!("pending_matches: unknown key `{column}`"))
+        })
+    };
+    Ok(PendingMatch {
+        awaited: (key(awaited_column)?, awaited_id),
+        key: (key(key_column)?, key_id),
+    })
+}
+
+/// The table a key is stored in once its order, authorization or bank transaction
+/// arrived.
+fn key_table(key: BusinessKey) -> &'static str {
+    match key {
+        BusinessKey::Order => "product_orders",
+        BusinessKey::Payment => "payment_authorizations",
+        BusinessKey::Transaction => "bank_transactions",
+    }
+}
+
+/// Amounts are stored as minor units next to their currency code.
+fn stored_money(table: &str, minor_units: i64, currency: &str) -> Result<Money, StorageError> {
+    let currency = currency
+        .parse::<Currency>()
+        .map_err(|e| StorageError::QueryError(format!("{table}.currency: {e}")))?;
+    Ok(Money::new(minor_units, currency))
+}
+
+fn stored_timestamp(table: &str, value: &str) -> Result<DateTime<Utc>, StorageError> {
+    crate::events::parse_timestamp(value)
+        .map_err(|e| StorageError::QueryError(format!("{table}.occurred_on: {e}")))
+}
+
+/// Decodes an `event_log` row of the SQL backends.
+fn logged_event(
+    sequence: i64,
+    payload: &str,
+    received_at: &str,
+) -> Result<LoggedEvent, StorageError> {
+    let decoding_error = |e: String| StorageError::QueryError(format!("event_log {sequence}: {e}"));
+    Ok(LoggedEvent {
+        sequence,
+        event: Event::from_json(payload).map_err(|e| decoding_error(e.to_string()))?,
+        received_at: crate::events::parse_timestamp(received_at).map_err(decoding_error)?,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Total {
+    Ordered,
+    Authorized,
+    Collected,
+}
+
+impl Total {
+    pub fn table(&self) -> &'static str {
+        match self {
+            Total::Ordered => "total_ordered",
+            Total::Authorized => "total_authorized",
+            Total::Collected => "total_collected",
+        }
+    }
+}
+
+/// A storage backend. The event handler opens one session per accepted event, so
+/// that all of its writes are committed together or not at all.
+pub trait Store {
+    fn session(&self) -> Result<Box<dyn Session + '_>, StorageError>;
+
+    /// Applies the pending schema migrations and returns the ones applied.
+    fn migrate(&self) -> Result<Vec<MigrationStatus>, StorageError>;
+
+    fn migration_status(&self) -> Result<Vec<MigrationStatus>, StorageError>;
+}
+
+/// Opens the backend selected in the configuration.
+pub fn open(config: &DatabaseConfig) -> Result<Box<dyn Store>, StorageError> {
+    Ok(match config.backend {
+        Backend::Postgres => Box::new(postgres::PostgresStore::new(crate::pool::connect(config)?)),
+        Backend::Sqlite => Box::new(sqlite::SqliteStore::open(&config.url)?),
+        Backend::Memory => Box::new(memory::InMemoryStore::new()),
+    })
+}
+
+impl<S: Store + ?Sized> Store for std::sync::Arc<S> {
+    fn session(&self) -> Result<Box<dyn Session + '_>, StorageError> {
+        self.as_ref().session()
+    }
+
+    fn migrate(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        self.as_ref().migrate()
+    }
+
+    fn migration_status(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        self.as_ref().migration_status()
+    }
+}
+
+/// A unit of work. Dropping a session without calling `commit` discards its writes.
+pub trait Session {
+    fn commit(self: Box<Self>) -> Result<(), StorageError>;
+
+    /// Whether the event has already been recorded in the processed-events ledger.
+    fn is_processed(&mut self, event_id: &str) -> Result<bool, StorageError>;
+
+    /// Records the event in the ledger; fails if it is already there.
+    fn mark_processed(
+        &mut self,
+        event_id: &str,
+        event_type: &str,
+        processed_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError>;
+
+    /// Appends the event to the event log and returns its sequence number.
+    fn append_event(
+        &mut self,
+        event: &Event,
+        received_at: &DateTime<Utc>,
+    ) -> Result<i64, StorageError>;
+
+    fn read_events(&mut self, query: EventLogQuery) -> Result<Vec<LoggedEvent>, StorageError>;
+
+    /// Sequence of the last logged event, 0 while the log is empty.
+    fn log_head(&mut self) -> Result<i64, StorageError>;
+
+    /// Last sequence applied by the projector, `None` if it never ran. SQL backends
+    /// lock the checkpoint until the session ends, so that a projector is never run
+    /// by two sessions at once.
+    fn checkpoint(&mut self, projector: &str) -> Result<Option<i64>, StorageError>;
+
+    /// Moves the projector's checkpoint and clears its last error.
+    fn save_checkpoint(&mut self, projector: &str, sequence: i64) -> Result<(), StorageError>;
+
+    /// Keeps the error that stopped the projector, leaving its checkpoint as it is.
+    fn save_projector_error(&mut self, projector: &str, error: &str) -> Result<(), StorageError>;
+
+    fn projector_error(&mut self, projector: &str) -> Result<Option<String>, StorageError>;
+
+    fn save_bank_transaction_issued(
+        &mut self,
+        payload: &BankTransactionIssuedPayload,
+    ) -> Result<(), StorageError>;
+
+    fn save_payment_authorized(
+        &mut self,
+        payload: &PaymentAuthorizedPayload,
+    ) -> Result<(), StorageError>;
+
+    fn save_payment_collected(
+        &mut self,
+        payload: &PaymentCollectedPayload,
+    ) -> Result<(), StorageError>;
+
+    /// Stores the order with its guarantees.
+    fn save_product_ordered(&mut self, payload: &ProductOrderedPayload)
+        -> Result<(), StorageError>;
+
+    fn save_payment_refunded(
+        &mut self,
+        payload: &PaymentRefundedPayload,
+    ) -> Result<(), StorageError>;
+
+    fn save_chargeback_received(
+        &mut self,
+        payload: &ChargebackReceivedPayload,
+    ) -> Result<(), StorageError>;
+
+    /// The authorizations, collections, refunds and chargebacks of the payment, the
+    /// other lists left empty.
+    fn payment_facts(&mut self, payment_id: &str) -> Result<Facts, StorageError>;
+
+    /// Replaces the allocations of the payment's collections, refunds and
+    /// chargebacks.
+    fn save_allocations(
+        &mut self,
+        payment_id: &str,
+        allocations: &[Allocation],
+    ) -> Result<(), StorageError>;
+
+    /// The `to` keys an allocation links to `id`, e.g. the bank transactions that
+    /// settled payments of an order.
+    fn related(
+        &mut self,
+        from: BusinessKey,
+        id: &str,
+        to: BusinessKey,
+    ) -> Result<Vec<String>, StorageError>;
+
+    /// Whether the order, the payment's authorization or the bank transaction is
+    /// stored.
+    fn is_stored(&mut self, key: BusinessKey, id: &str) -> Result<bool, StorageError>;
+
+    /// Queues the match, unless it is already waiting.
+    fn add_pending_match(&mut self, pending: &PendingMatch) -> Result<(), StorageError>;
+
+    /// Removes and returns the matches waiting for the key.
+    fn take_pending_matches(
+        &mut self,
+        awaited: BusinessKey,
+        id: &str,
+    ) -> Result<Vec<PendingMatch>, StorageError>;
+
+    /// Every queued match, sorted.
+    fn pending_matches(&mut self) -> Result<Vec<PendingMatch>, StorageError>;
+
+    /// The order and the cancellations and interruptions referring to it, by
+    /// `order_id`.
+    fn order_lifecycle(&mut self, order_id: &str) -> Result<Vec<StoredOrder>, StorageError>;
+
+    /// Sets what is still expected for the order once it is adjusted.
+    fn update_expected_amount(
+        &mut self,
+        order_id: &str,
+        expected: Money,
+    ) -> Result<(), StorageError>;
+
+    /// Sets the order's `collected_amount` to what is allocated to it of the
+    /// collections a stored bank transaction settled, less the refunds and
+    /// chargebacks paid out. Only allocations in the order's currency count,
+    /// converting the others is left to the reconciliation report.
+    fn update_collected_amount(&mut self, order_id: &str) -> Result<(), StorageError>;
+
+    /// Sets the transaction's `ordered_amount` to what it settled in its currency
+    /// for stored orders, less the refunds and chargebacks it paid out.
+    fn update_ordered_amount(&mut self, transaction_id: &str) -> Result<(), StorageError>;
+
+    /// Every stored order, by `order_id`, leaving out the cancellations and
+    /// interruptions of another order.
+    fn order_balances(&mut self) -> Result<Vec<OrderBalance>, StorageError>;
+
+    /// Every stored bank transaction, by `transaction_id`.
+    fn transaction_balances(&mut self) -> Result<Vec<TransactionBalance>, StorageError>;
+
+    /// Every stored guarantee, by `order_id` and `guarantee_type`.
+    fn guarantees(&mut self) -> Result<Vec<StoredGuarantee>, StorageError>;
+
+    /// Every stored order, authorization, collection, refund, chargeback, bank
+    /// transaction and allocation.
+    fn facts(&mut self) -> Result<Facts, StorageError>;
+
+    fn insert_total(
+        &mut self,
+        total: Total,
+        amount: Money,
+        occurred_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError>;
+
+    fn clear_total(&mut self, total: Total) -> Result<(), StorageError>;
+}
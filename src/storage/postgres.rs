@@ -0,0 +1,744 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use postgres::NoTls;
+use r2d2_postgres::{
+    r2d2::{self, PooledConnection},
+    PostgresConnectionManager,
+};
+
+use crate::events::{
+    BankTransactionIssuedPayload, ChargebackReceivedPayload, Event, PaymentAuthorizedPayload,
+    PaymentCollectedPayload, PaymentRefundedPayload, ProductOrderedPayload,
+};
+use crate::money::Money;
+use crate::storage::migrations::{self, Migration, MigrationConnection, MigrationStatus};
+use crate::storage::{
+    key_table, logged_event, pending_match, stored_money, stored_timestamp, Allocation,
+    BusinessKey, EventLogQuery, Facts, LoggedEvent, OrderBalance, PendingMatch, Session,
+    StorageError, Store, StoredAuthorization, StoredCollection, StoredGuarantee, StoredOrder,
+    StoredReversal, StoredTransaction, Total, TransactionBalance, CHARGEBACK, REFUND,
+};
+
+pub type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+type Client = PooledConnection<PostgresConnectionManager<NoTls>>;
+
+impl From<postgres::Error> for StorageError {
+    fn from(e: postgres::Error) -> Self {
+        StorageError::QueryError(e.to_string())
+    }
+}
+
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+impl PostgresStore {
+    fn client(&self) -> Result<Client, StorageError> {
+        self.pool
+            .get()
+            .map_err(|e| StorageError::ConnectionError(e.to_string()))
+    }
+}
+
+impl Store for PostgresStore {
+    fn session(&self) -> Result<Box<dyn Session + '_>, StorageError> {
+        let mut client = self.client()?;
+        client.batch_execute("BEGIN")?;
+        Ok(Box::new(PostgresSession {
+            client,
+            committed: false,
+        }))
+    }
+
+    fn migrate(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        migrations::migrate(&mut *self.client()?, migrations::POSTGRES)
+    }
+
+    fn migration_status(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        migrations::status(&mut *self.client()?, migrations::POSTGRES)
+    }
+}
+
+impl MigrationConnection for postgres::Client {
+    fn batch_execute(&mut self, sql: &str) -> Result<(), StorageError> {
+        Ok(postgres::Client::batch_execute(self, sql)?)
+    }
+
+    fn applied_migrations(&mut self) -> Result<Vec<(i64, String)>, StorageError> {
+        Ok(self
+            .query("SELECT version, applied_on FROM schema_migrations", &[])?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn record_migration(
+        &mut self,
+        migration: &Migration,
+        applied_on: &str,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            "INSERT INTO schema_migrations (version, name, applied_on) VALUES ($1,$2,$3)",
+            &[&migration.version, &migration.name, &applied_on],
+        )?;
+        Ok(())
+    }
+}
+
+/// Runs inside a transaction opened with the session: nothing is visible to other
+/// connections until `commit`, and dropping the session rolls everything back.
+pub struct PostgresSession {
+    client: Client,
+    committed: bool,
+}
+
+impl Drop for PostgresSession {
+    fn drop(&mut self) {
+        if !self.committed {
+            // a broken connection is discarded by the pool anyway
+            let _ = self.client.batch_execute("ROLLBACK");
+        }
+    }
+}
+
+impl PostgresSession {
+    fn save_reversal(
+        &mut self,
+        kind: &str,
+        payment_id: &str,
+        transaction_id: &str,
+        amount: Money,
+        occurred_on: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.client.execute(
+            r"
+        INSERT INTO payment_reversals (kind, payment_id, transaction_id, amount, currency, occurred_on)
+        VALUES($1,$2,$3,$4,$5,$6)
+        ",
+            &[
+                &kind,
+                &payment_id,
+                &transaction_id,
+                &amount.minor_units(),
+                &amount.currency().code(),
+                &occurred_on.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Adds the authorizations, collections, refunds and chargebacks matching the
+    /// `WHERE` clause to the facts.
+    fn payment_rows(
+        &mut self,
+        facts: &mut Facts,
+        filter: &str,
+        params: &[&(dyn postgres::types::ToSql + Sync)],
+    ) -> Result<(), StorageError> {
+        for row in self.client.query(
+            &format!(
+                r"SELECT order_id, payment_id, amount, currency, occurred_on
+            FROM payment_authorizations {filter} ORDER BY order_id, payment_id"
+            ),
+            params,
+        )? {
+            facts.authorizations.push(StoredAuthorization {
+                order_id: row.get(0),
+                payment_id: row.get(1),
+                amount: stored_money("payment_authorizations", row.get(2), row.get(3))?,
+                occurred_on: stored_timestamp("payment_authorizations", row.get(4))?,
+            });
+        }
+        for row in self.client.query(
+            &format!(
+                r"SELECT payment_id, transaction_id, amount, currency, occurred_on
+            FROM payment_collections {filter} ORDER BY payment_id, transaction_id"
+            ),
+            params,
+        )? {
+            facts.collections.push(StoredCollection {
+                payment_id: row.get(0),
+                transaction_id: row.get(1),
+                amount: stored_money("payment_collections", row.get(2), row.get(3))?,
+                occurred_on: stored_timestamp("payment_collections", row.get(4))?,
+            });
+        }
+        for row in self.client.query(
+            &format!(
+                r"SELECT kind, payment_id, transaction_id, amount, currency, occurred_on
+            FROM payment_reversals {filter} ORDER BY payment_id, transaction_id, kind"
+            ),
+            params,
+        )? {
+            facts.reversals.push(StoredReversal {
+                kind: row.get(0),
+                payment_id: row.get(1),
+                transaction_id: row.get(2),
+                amount: stored_money("payment_reversals", row.get(3), row.get(4))?,
+                occurred_on: stored_timestamp("payment_reversals", row.get(5))?,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Session for PostgresSession {
+    fn commit(mut self: Box<Self>) -> Result<(), StorageError> {
+        self.client.batch_execute("COMMIT")?;
+        self.committed = true;
+        Ok(())
+    }
+
+    fn is_processed(&mut self, event_id: &str) -> Result<bool, StorageError> {
+        Ok(!self
+            .client
+            .query(
+                "SELECT 1 FROM processed_events WHERE event_id=$1",
+                &[&event_id],
+            )?
+            .is_empty())
+    }
+
+    fn mark_processed(
+        &mut self,
+        event_id: &str,
+        event_type: &str,
+        processed_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.client.execute(
+            r"INSERT INTO processed_events (event_id, event_type, processed_on) VALUES($1,$2,$3)",
+            &[&event_id, &event_type, &processed_on.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn append_event(
+        &mut self,
+        event: &Event,
+        received_at: &DateTime<Utc>,
+    ) -> Result<i64, StorageError> {
+        let payload = event
+            .to_json()
+            .map_err(|e| StorageError::QueryError(e.to_string()))?;
+        let row = self.client.query_one(
+            r"
+        INSERT INTO event_log (event_id, event_type, order_id, payment_id, transaction_id, payload, received_at)
+        VALUES($1,$2,$3,$4,$5,$6,$7)
+        RETURNING sequence",
+            &[
+                &event.event_id(),
+                &event.event_type(),
+                &event.order_id(),
+                &event.payment_id(),
+                &event.transaction_id(),
+                &payload,
+                &received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ],
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn read_events(&mut self, query: EventLogQuery) -> Result<Vec<LoggedEvent>, StorageError> {
+        let select = "SELECT sequence, payload, received_at FROM event_log";
+        let rows = match query {
+            EventLogQuery::Range { from, to } => self.client.query(
+                &format!("{select} WHERE sequence >= $1 AND sequence < $2 ORDER BY sequence"),
+                &[&from, &to],
+            )?,
+            EventLogQuery::Key(key, id) => self.client.query(
+                &format!("{select} WHERE {}=$1 ORDER BY sequence", key.column()),
+                &[&id],
+            )?,
+        };
+        rows.into_iter()
+            .map(|row| logged_event(row.get(0), row.get(1), row.get(2)))
+            .collect()
+    }
+
+    fn log_head(&mut self) -> Result<i64, StorageError> {
+        Ok(self
+            .client
+            .query_one("SELECT COALESCE(MAX(sequence), 0) FROM event_log", &[])?
+            .get(0))
+    }
+
+    fn checkpoint(&mut self, projector: &str) -> Result<Option<i64>, StorageError> {
+        Ok(self
+            .client
+            .query(
+                "SELECT sequence FROM projector_checkpoints WHERE projector=$1 FOR UPDATE",
+                &[&projector],
+            )?
+            .first()
+            .map(|row| row.get(0)))
+    }
+
+    fn save_checkpoint(&mut self, projector: &str, sequence: i64) -> Result<(), StorageError> {
+        self.client.execute(
+            r"
+        INSERT INTO projector_checkpoints (projector, sequence) VALUES($1,$2)
+        ON CONFLICT (projector) DO UPDATE SET sequence=excluded.sequence, last_error=NULL",
+            &[&projector, &sequence],
+        )?;
+        Ok(())
+    }
+
+    fn save_projector_error(&mut self, projector: &str, error: &str) -> Result<(), StorageError> {
+        self.client.execute(
+            r"
+        INSERT INTO projector_checkpoints (projector, sequence, last_error) VALUES($1,0,$2)
+        ON CONFLICT (projector) DO UPDATE SET last_error=excluded.last_error",
+            &[&projector, &error],
+        )?;
+        Ok(())
+    }
+
+    fn projector_error(&mut self, projector: &str) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .client
+            .query(
+                "SELECT last_error FROM projector_checkpoints WHERE projector=$1",
+                &[&projector],
+            )?
+            .first()
+            .and_then(|row| row.get(0)))
+    }
+
+    fn save_bank_transaction_issued(
+        &mut self,
+        payload: &BankTransactionIssuedPayload,
+    ) -> Result<(), StorageError> {
+        self.client
+            .execute(
+                r"
+        INSERT INTO bank_transactions (transaction_id, amount, currency, occurred_on)
+        VALUES($1,$2,$3,$4)",
+                &[
+                    &payload.transaction_id,
+                    &payload.amount.minor_units(),
+                    &payload.amount.currency().code(),
+                    &payload.occurred_on.to_string(),
+                ],
+            )
+            .map(|_| ())?;
+        Ok(())
+    }
+
+    fn save_payment_authorized(
+        &mut self,
+        payload: &PaymentAuthorizedPayload,
+    ) -> Result<(), StorageError> {
+        self.client
+            .execute(
+                r"
+        INSERT INTO payment_authorizations (payment_id, order_id, amount, currency, occurred_on)
+        VALUES($1,$2,$3,$4,$5)",
+                &[
+                    &payload.payment_id,
+                    &payload.order_id,
+                    &payload.amount.minor_units(),
+                    &payload.amount.currency().code(),
+                    &payload.occurred_on.to_string(),
+                ],
+            )
+            .map(|_| ())?;
+
+        Ok(())
+    }
+
+    fn save_payment_collected(
+        &mut self,
+        payload: &PaymentCollectedPayload,
+    ) -> Result<(), StorageError> {
+        self.client
+            .execute(
+                r"
+        INSERT INTO payment_collections (payment_id, transaction_id, amount, currency, occurred_on)
+        VALUES($1,$2,$3,$4,$5)
+        ",
+                &[
+                    &payload.payment_id,
+                    &payload.transaction_id,
+                    &payload.amount.minor_units(),
+                    &payload.amount.currency().code(),
+                    &payload.occurred_on.to_string(),
+                ],
+            )
+            .map(|_| ())?;
+
+        Ok(())
+    }
+
+    fn save_product_ordered(
+        &mut self,
+        payload: &ProductOrderedPayload,
+    ) -> Result<(), StorageError> {
+        self.client.execute(r"
+         INSERT INTO product_orders (order_id, amount, expected_amount, currency, occurred_on, event_type, installment_type, insurance_code, original_order_id)
+         VALUES($1,$2,$2,$3,$4,$5,$6,$7,$8)
+         ", &[
+            &payload.order_id,
+            &payload.amount.minor_units(),
+            &payload.amount.currency().code(),
+            &payload.occurred_on.to_string(),
+            &payload.event_type.to_string(),
+            &payload.installment_type.to_string(),
+            &payload.insurance_code,
+            &payload.original_order_id,
+         ])
+        .map(|_| ())?;
+        for guarantee in &payload.guarantees {
+            self.client.execute(
+                r"
+            INSERT INTO order_guarantees (order_id, guarantee_type, price, currency)
+            VALUES($1,$2,$3,$4)
+            ",
+                &[
+                    &payload.order_id,
+                    &guarantee.guarantee_type,
+                    &guarantee.price.minor_units(),
+                    &guarantee.price.currency().code(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn save_payment_refunded(
+        &mut self,
+        payload: &PaymentRefundedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            REFUND,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn save_chargeback_received(
+        &mut self,
+        payload: &ChargebackReceivedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            CHARGEBACK,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn payment_facts(&mut self, payment_id: &str) -> Result<Facts, StorageError> {
+        let mut facts = Facts::default();
+        self.payment_rows(&mut facts, "WHERE payment_id=$1", &[&payment_id])?;
+        Ok(facts)
+    }
+
+    fn save_allocations(
+        &mut self,
+        payment_id: &str,
+        allocations: &[Allocation],
+    ) -> Result<(), StorageError> {
+        self.client
+            .execute("DELETE FROM relations WHERE payment_id=$1", &[&payment_id])?;
+        for allocation in allocations {
+            self.client.execute(
+                r"
+            INSERT INTO relations (kind, order_id, payment_id, transaction_id, amount, currency)
+            VALUES($1,$2,$3,$4,$5,$6)",
+                &[
+                    &allocation.kind,
+                    &allocation.order_id,
+                    &allocation.payment_id,
+                    &allocation.transaction_id,
+                    &allocation.amount.minor_units(),
+                    &allocation.amount.currency().code(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn related(
+        &mut self,
+        from: BusinessKey,
+        id: &str,
+        to: BusinessKey,
+    ) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .client
+            .query(
+                &format!(
+                    "SELECT DISTINCT {} FROM relations WHERE {}=$1",
+                    to.column(),
+                    from.column()
+                ),
+                &[&id],
+            )?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn is_stored(&mut self, key: BusinessKey, id: &str) -> Result<bool, StorageError> {
+        Ok(!self
+            .client
+            .query(
+                &format!(
+                    "SELECT 1 FROM {} WHERE {}=$1 LIMIT 1",
+                    key_table(key),
+                    key.column()
+                ),
+                &[&id],
+            )?
+            .is_empty())
+    }
+
+    fn add_pending_match(&mut self, pending: &PendingMatch) -> Result<(), StorageError> {
+        self.client.execute(
+            r"
+        INSERT INTO pending_matches (awaited_column, awaited_id, key_column, key_id)
+        VALUES($1,$2,$3,$4) ON CONFLICT DO NOTHING",
+            &[
+                &pending.awaited.0.column(),
+                &pending.awaited.1,
+                &pending.key.0.column(),
+                &pending.key.1,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn take_pending_matches(
+        &mut self,
+        awaited: BusinessKey,
+        id: &str,
+    ) -> Result<Vec<PendingMatch>, StorageError> {
+        self.client
+            .query(
+                r"DELETE FROM pending_matches WHERE awaited_column=$1 AND awaited_id=$2
+            RETURNING awaited_column, awaited_id, key_column, key_id",
+                &[&awaited.column(), &id],
+            )?
+            .into_iter()
+            .map(|row| pending_match(row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect()
+    }
+
+    fn pending_matches(&mut self) -> Result<Vec<PendingMatch>, StorageError> {
+        self.client
+            .query(
+                r"SELECT awaited_column, awaited_id, key_column, key_id FROM pending_matches
+            ORDER BY awaited_column, awaited_id, key_column, key_id",
+                &[],
+            )?
+            .into_iter()
+            .map(|row| pending_match(row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect()
+    }
+
+    fn order_lifecycle(&mut self, order_id: &str) -> Result<Vec<StoredOrder>, StorageError> {
+        self.client
+            .query(
+                &format!(
+                    r"SELECT {STORED_ORDER} FROM product_orders
+                    WHERE order_id=$1 OR original_order_id=$1 ORDER BY order_id"
+                ),
+                &[&order_id],
+            )?
+            .iter()
+            .map(stored_order)
+            .collect()
+    }
+
+    fn update_expected_amount(
+        &mut self,
+        order_id: &str,
+        expected: Money,
+    ) -> Result<(), StorageError> {
+        self.client.execute(
+            "UPDATE product_orders SET expected_amount=$2 WHERE order_id=$1",
+            &[&order_id, &expected.minor_units()],
+        )?;
+        Ok(())
+    }
+
+    fn update_collected_amount(&mut self, order_id: &str) -> Result<(), StorageError> {
+        self.client.execute(
+            r"UPDATE product_orders SET collected_amount = (
+            SELECT COALESCE(SUM(r.amount), 0)
+            FROM relations r
+            JOIN bank_transactions bt ON bt.transaction_id = r.transaction_id
+            WHERE r.order_id = product_orders.order_id
+            AND r.currency = product_orders.currency
+        )
+        WHERE order_id=$1",
+            &[&order_id],
+        )?;
+        Ok(())
+    }
+
+    fn update_ordered_amount(&mut self, transaction_id: &str) -> Result<(), StorageError> {
+        self.client.execute(
+            r"UPDATE bank_transactions SET ordered_amount = (
+            SELECT COALESCE(SUM(r.amount), 0)
+            FROM relations r
+            JOIN product_orders po ON po.order_id = r.order_id
+            WHERE r.transaction_id = bank_transactions.transaction_id
+            AND r.currency = bank_transactions.currency
+        )
+        WHERE transaction_id=$1",
+            &[&transaction_id],
+        )?;
+        Ok(())
+    }
+
+    fn order_balances(&mut self) -> Result<Vec<OrderBalance>, StorageError> {
+        self.client
+            .query(
+                r"SELECT order_id, amount, collected_amount, currency, expected_amount
+                FROM product_orders WHERE original_order_id IS NULL ORDER BY order_id",
+                &[],
+            )?
+            .into_iter()
+            .map(|row| {
+                Ok(OrderBalance {
+                    order_id: row.get(0),
+                    amount: stored_money("product_orders", row.get(1), row.get(3))?,
+                    expected_amount: stored_money("product_orders", row.get(4), row.get(3))?,
+                    collected_amount: stored_money("product_orders", row.get(2), row.get(3))?,
+                })
+            })
+            .collect()
+    }
+
+    fn transaction_balances(&mut self) -> Result<Vec<TransactionBalance>, StorageError> {
+        self.client
+            .query(
+                r"SELECT transaction_id, amount, ordered_amount, currency
+                FROM bank_transactions ORDER BY transaction_id",
+                &[],
+            )?
+            .into_iter()
+            .map(|row| {
+                Ok(TransactionBalance {
+                    transaction_id: row.get(0),
+                    amount: stored_money("bank_transactions", row.get(1), row.get(3))?,
+                    ordered_amount: stored_money("bank_transactions", row.get(2), row.get(3))?,
+                })
+            })
+            .collect()
+    }
+
+    fn guarantees(&mut self) -> Result<Vec<StoredGuarantee>, StorageError> {
+        self.client
+            .query(
+                r"SELECT order_id, guarantee_type, price, currency
+                FROM order_guarantees ORDER BY order_id, guarantee_type",
+                &[],
+            )?
+            .into_iter()
+            .map(|row| {
+                Ok(StoredGuarantee {
+                    order_id: row.get(0),
+                    guarantee_type: row.get(1),
+                    price: stored_money("order_guarantees", row.get(2), row.get(3))?,
+                })
+            })
+            .collect()
+    }
+
+    fn facts(&mut self) -> Result<Facts, StorageError> {
+        let mut facts = Facts {
+            orders: self
+                .client
+                .query(
+                    &format!("SELECT {STORED_ORDER} FROM product_orders ORDER BY order_id"),
+                    &[],
+                )?
+                .iter()
+                .map(stored_order)
+                .collect::<Result<_, _>>()?,
+            guarantees: self.guarantees()?,
+            ..Facts::default()
+        };
+        self.payment_rows(&mut facts, "", &[])?;
+        for row in self.client.query(
+            r"SELECT transaction_id, amount, currency, occurred_on
+            FROM bank_transactions ORDER BY transaction_id",
+            &[],
+        )? {
+            facts.transactions.push(StoredTransaction {
+                transaction_id: row.get(0),
+                amount: stored_money("bank_transactions", row.get(1), row.get(2))?,
+                occurred_on: stored_timestamp("bank_transactions", row.get(3))?,
+            });
+        }
+        for row in self.client.query(
+            r"SELECT kind, order_id, payment_id, transaction_id, amount, currency
+            FROM relations ORDER BY order_id, payment_id, transaction_id, kind",
+            &[],
+        )? {
+            facts.allocations.push(Allocation {
+                kind: row.get(0),
+                order_id: row.get(1),
+                payment_id: row.get(2),
+                transaction_id: row.get(3),
+                amount: stored_money("relations", row.get(4), row.get(5))?,
+            });
+        }
+        Ok(facts)
+    }
+
+    fn insert_total(
+        &mut self,
+        total: Total,
+        amount: Money,
+        occurred_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.client
+            .execute(
+                &format!(
+                    r"INSERT INTO {} (amount, currency, occurred_on) VALUES($1,$2,$3)",
+                    total.table()
+                ),
+                &[
+                    &amount.minor_units(),
+                    &amount.currency().code(),
+                    &occurred_on.to_string(),
+                ],
+            )
+            .map(|_| ())?;
+        Ok(())
+    }
+
+    fn clear_total(&mut self, total: Total) -> Result<(), StorageError> {
+        self.client
+            .execute(&format!("DELETE FROM {}", total.table()), &[])?;
+        Ok(())
+    }
+}
+
+/// The `product_orders` columns [`stored_order`] decodes.
+const STORED_ORDER: &str = "order_id, amount, expected_amount, currency, event_type, \
+    installment_type, insurance_code, occurred_on, original_order_id";
+
+fn stored_order(row: &postgres::Row) -> Result<StoredOrder, StorageError> {
+    Ok(StoredOrder {
+        order_id: row.get(0),
+        amount: stored_money("product_orders", row.get(1), row.get(3))?,
+        expected_amount: stored_money("product_orders", row.get(2), row.get(3))?,
+        event_type: row.get(4),
+        installment_type: row.get(5),
+        insurance_code: row.get(6),
+        occurred_on: stored_timestamp("product_orders", row.get(7))?,
+        original_order_id: row.get(8),
+    })
+}
@@ -0,0 +1,814 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlite::{Connection, State, Value};
+
+use crate::events::{
+    BankTransactionIssuedPayload, ChargebackReceivedPayload, Event, PaymentAuthorizedPayload,
+    PaymentCollectedPayload, PaymentRefundedPayload, ProductOrderedPayload,
+};
+use crate::money::Money;
+use crate::storage::migrations::{self, Migration, MigrationConnection, MigrationStatus};
+use crate::storage::{
+    key_table, logged_event, pending_match, stored_money, stored_timestamp, Allocation,
+    BusinessKey, EventLogQuery, Facts, LoggedEvent, OrderBalance, PendingMatch, Session,
+    StorageError, Store, StoredAuthorization, StoredCollection, StoredGuarantee, StoredOrder,
+    StoredReversal, StoredTransaction, Total, TransactionBalance, CHARGEBACK, REFUND,
+};
+
+impl From<sqlite::Error> for StorageError {
+    fn from(e: sqlite::Error) -> Self {
+        StorageError::QueryError(e.to_string())
+    }
+}
+
+/// SQLite flavour of the Postgres schema, for local runs and tests. Use `:memory:`
+/// as path for a throwaway database.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let connection =
+            sqlite::open(path).map_err(|e| StorageError::ConnectionError(e.to_string()))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Drops every table and re-applies the migrations.
+    #[cfg(test)]
+    pub fn reset_db(&self) -> Result<(), StorageError> {
+        {
+            let connection = self.connection();
+            let mut tables = vec![];
+            connection.iterate(
+                "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%'",
+                |row| {
+                    tables.extend(row.iter().filter_map(|(_, name)| name.map(str::to_owned)));
+                    true
+                },
+            )?;
+            for table in tables {
+                connection.execute(format!("DROP TABLE {table}"))?;
+            }
+        }
+        self.migrate().map(|_| ())
+    }
+}
+
+impl Store for SqliteStore {
+    fn session(&self) -> Result<Box<dyn Session + '_>, StorageError> {
+        let connection = self.connection();
+        connection.execute("BEGIN")?;
+        Ok(Box::new(SqliteSession {
+            connection,
+            committed: false,
+        }))
+    }
+
+    fn migrate(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        migrations::migrate(&mut *self.connection(), migrations::SQLITE)
+    }
+
+    fn migration_status(&self) -> Result<Vec<MigrationStatus>, StorageError> {
+        migrations::status(&mut *self.connection(), migrations::SQLITE)
+    }
+}
+
+impl MigrationConnection for Connection {
+    fn batch_execute(&mut self, sql: &str) -> Result<(), StorageError> {
+        Ok(self.execute(sql)?)
+    }
+
+    fn applied_migrations(&mut self) -> Result<Vec<(i64, String)>, StorageError> {
+        let mut statement = self.prepare("SELECT version, applied_on FROM schema_migrations")?;
+        let mut applied = vec![];
+        while statement.next()? == State::Row {
+            applied.push((statement.read(0)?, statement.read(1)?));
+        }
+        Ok(applied)
+    }
+
+    fn record_migration(
+        &mut self,
+        migration: &Migration,
+        applied_on: &str,
+    ) -> Result<(), StorageError> {
+        let mut statement = self.prepare(
+            "INSERT INTO schema_migrations (version, name, applied_on) VALUES (?1,?2,?3)",
+        )?;
+        statement.bind(
+            &[
+                Value::from(migration.version),
+                migration.name.into(),
+                applied_on.into(),
+            ][..],
+        )?;
+        while statement.next()? != State::Done {}
+        Ok(())
+    }
+}
+
+/// Runs inside a transaction opened with the session: dropping the session without
+/// `commit` rolls everything back.
+pub struct SqliteSession<'a> {
+    connection: MutexGuard<'a, Connection>,
+    committed: bool,
+}
+
+impl Drop for SqliteSession<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = self.connection.execute("ROLLBACK");
+        }
+    }
+}
+
+impl SqliteSession<'_> {
+    fn execute(&self, query: &str, params: &[Value]) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind(params)?;
+        while statement.next()? != State::Done {}
+        Ok(())
+    }
+
+    fn query(&self, query: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, StorageError> {
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind(params)?;
+        let mut rows = vec![];
+        while statement.next()? == State::Row {
+            rows.push(
+                (0..statement.column_count())
+                    .map(|i| statement.read::<Value, _>(i))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+        Ok(rows)
+    }
+
+    fn save_reversal(
+        &self,
+        kind: &str,
+        payment_id: &str,
+        transaction_id: &str,
+        amount: Money,
+        occurred_on: DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO payment_reversals (kind, payment_id, transaction_id, amount, currency, occurred_on)
+        VALUES(?1,?2,?3,?4,?5,?6)",
+            &[
+                kind.into(),
+                payment_id.into(),
+                transaction_id.into(),
+                amount.minor_units().into(),
+                amount.currency().code().into(),
+                occurred_on.to_string().into(),
+            ],
+        )
+    }
+
+    /// Adds the authorizations, collections, refunds and chargebacks matching the
+    /// `WHERE` clause to the facts.
+    fn payment_rows(
+        &self,
+        facts: &mut Facts,
+        filter: &str,
+        params: &[Value],
+    ) -> Result<(), StorageError> {
+        for row in self.query(
+            &format!(
+                r"SELECT order_id, payment_id, amount, currency, occurred_on
+            FROM payment_authorizations {filter} ORDER BY order_id, payment_id"
+            ),
+            params,
+        )? {
+            facts.authorizations.push(StoredAuthorization {
+                order_id: text(&row[0])?,
+                payment_id: text(&row[1])?,
+                amount: stored_money("payment_authorizations", integer(&row[2])?, &text(&row[3])?)?,
+                occurred_on: stored_timestamp("payment_authorizations", &text(&row[4])?)?,
+            });
+        }
+        for row in self.query(
+            &format!(
+                r"SELECT payment_id, transaction_id, amount, currency, occurred_on
+            FROM payment_collections {filter} ORDER BY payment_id, transaction_id"
+            ),
+            params,
+        )? {
+            facts.collections.push(StoredCollection {
+                payment_id: text(&row[0])?,
+                transaction_id: text(&row[1])?,
+                amount: stored_money("payment_collections", integer(&row[2])?, &text(&row[3])?)?,
+                occurred_on: stored_timestamp("payment_collections", &text(&row[4])?)?,
+            });
+        }
+        for row in self.query(
+            &format!(
+                r"SELECT kind, payment_id, transaction_id, amount, currency, occurred_on
+            FROM payment_reversals {filter} ORDER BY payment_id, transaction_id, kind"
+            ),
+            params,
+        )? {
+            facts.reversals.push(StoredReversal {
+                kind: text(&row[0])?,
+                payment_id: text(&row[1])?,
+                transaction_id: text(&row[2])?,
+                amount: stored_money("payment_reversals", integer(&row[3])?, &text(&row[4])?)?,
+                occurred_on: stored_timestamp("payment_reversals", &text(&row[5])?)?,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn text(value: &Value) -> Result<String, StorageError> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        other => Err(StorageError::QueryError(format!(
+            "expected text, found {:?}",
+            other.kind()
+        ))),
+    }
+}
+
+/// The `product_orders` columns [`stored_order`] decodes.
+const STORED_ORDER: &str = "order_id, amount, expected_amount, currency, event_type, \
+    installment_type, insurance_code, occurred_on, original_order_id";
+
+fn stored_order(row: &[Value]) -> Result<StoredOrder, StorageError> {
+    let currency = text(&row[3])?;
+    Ok(StoredOrder {
+        order_id: text(&row[0])?,
+        amount: stored_money("product_orders", integer(&row[1])?, &currency)?,
+        expected_amount: stored_money("product_orders", integer(&row[2])?, &currency)?,
+        event_type: text(&row[4])?,
+        installment_type: text(&row[5])?,
+        insurance_code: text(&row[6])?,
+        occurred_on: stored_timestamp("product_orders", &text(&row[7])?)?,
+        original_order_id: match &row[8] {
+            Value::Null => None,
+            value => Some(text(value)?),
+        },
+    })
+}
+
+fn pending_match_row(row: &[Value]) -> Result<PendingMatch, StorageError> {
+    pending_match(
+        &text(&row[0])?,
+        text(&row[1])?,
+        &text(&row[2])?,
+        text(&row[3])?,
+    )
+}
+
+fn integer(value: &Value) -> Result<i64, StorageError> {
+    match value {
+        Value::Integer(i) => Ok(*i),
+        other => Err(StorageError::QueryError(format!(
+            "expected integer, found {:?}",
+            other.kind()
+        ))),
+    }
+}
+
+impl Session for SqliteSession<'_> {
+    fn commit(mut self: Box<Self>) -> Result<(), StorageError> {
+        self.connection.execute("COMMIT")?;
+        self.committed = true;
+        Ok(())
+    }
+
+    fn is_processed(&mut self, event_id: &str) -> Result<bool, StorageError> {
+        Ok(!self
+            .query(
+                "SELECT 1 FROM processed_events WHERE event_id=?1",
+                &[event_id.into()],
+            )?
+            .is_empty())
+    }
+
+    fn mark_processed(
+        &mut self,
+        event_id: &str,
+        event_type: &str,
+        processed_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            r"INSERT INTO processed_events (event_id, event_type, processed_on) VALUES(?1,?2,?3)",
+            &[
+                event_id.into(),
+                event_type.into(),
+                processed_on.to_string().into(),
+            ],
+        )
+    }
+
+    fn append_event(
+        &mut self,
+        event: &Event,
+        received_at: &DateTime<Utc>,
+    ) -> Result<i64, StorageError> {
+        let payload = event
+            .to_json()
+            .map_err(|e| StorageError::QueryError(e.to_string()))?;
+        self.execute(
+            r"
+        INSERT INTO event_log (event_id, event_type, order_id, payment_id, transaction_id, payload, received_at)
+        VALUES(?1,?2,?3,?4,?5,?6,?7)",
+            &[
+                event.event_id().into(),
+                event.event_type().into(),
+                event.order_id().map_or(Value::Null, Value::from),
+                event.payment_id().map_or(Value::Null, Value::from),
+                event.transaction_id().map_or(Value::Null, Value::from),
+                payload.into(),
+                received_at
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            ],
+        )?;
+        match self.query("SELECT last_insert_rowid()", &[])?.as_slice() {
+            [row] => integer(&row[0]),
+            _ => Err(StorageError::QueryError(
+                "no sequence for the appended event".to_owned(),
+            )),
+        }
+    }
+
+    fn read_events(&mut self, query: EventLogQuery) -> Result<Vec<LoggedEvent>, StorageError> {
+        let select = "SELECT sequence, payload, received_at FROM event_log";
+        let rows = match query {
+            EventLogQuery::Range { from, to } => self.query(
+                &format!("{select} WHERE sequence >= ?1 AND sequence < ?2 ORDER BY sequence"),
+                &[from.into(), to.into()],
+            )?,
+            EventLogQuery::Key(key, id) => self.query(
+                &format!("{select} WHERE {}=?1 ORDER BY sequence", key.column()),
+                &[id.into()],
+            )?,
+        };
+        rows.iter()
+            .map(|row| logged_event(integer(&row[0])?, &text(&row[1])?, &text(&row[2])?))
+            .collect()
+    }
+
+    fn log_head(&mut self) -> Result<i64, StorageError> {
+        match self
+            .query("SELECT COALESCE(MAX(sequence), 0) FROM event_log", &[])?
+            .as_slice()
+        {
+            [row] => integer(&row[0]),
+            _ => Err(StorageError::QueryError(
+                "no head for the event log".to_owned(),
+            )),
+        }
+    }
+
+    fn checkpoint(&mut self, projector: &str) -> Result<Option<i64>, StorageError> {
+        self.query(
+            "SELECT sequence FROM projector_checkpoints WHERE projector=?1",
+            &[projector.into()],
+        )?
+        .first()
+        .map(|row| integer(&row[0]))
+        .transpose()
+    }
+
+    fn save_checkpoint(&mut self, projector: &str, sequence: i64) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO projector_checkpoints (projector, sequence) VALUES(?1,?2)
+        ON CONFLICT (projector) DO UPDATE SET sequence=excluded.sequence, last_error=NULL",
+            &[projector.into(), sequence.into()],
+        )
+    }
+
+    fn save_projector_error(&mut self, projector: &str, error: &str) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO projector_checkpoints (projector, sequence, last_error) VALUES(?1,0,?2)
+        ON CONFLICT (projector) DO UPDATE SET last_error=excluded.last_error",
+            &[projector.into(), error.into()],
+        )
+    }
+
+    fn projector_error(&mut self, projector: &str) -> Result<Option<String>, StorageError> {
+        match self
+            .query(
+                "SELECT last_error FROM projector_checkpoints WHERE projector=?1",
+                &[projector.into()],
+            )?
+            .first()
+            .map(|row| &row[0])
+        {
+            None | Some(Value::Null) => Ok(None),
+            Some(error) => text(error).map(Some),
+        }
+    }
+
+    fn save_bank_transaction_issued(
+        &mut self,
+        payload: &BankTransactionIssuedPayload,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO bank_transactions (transaction_id, amount, currency, occurred_on)
+        VALUES(?1,?2,?3,?4)",
+            &[
+                payload.transaction_id.as_str().into(),
+                payload.amount.minor_units().into(),
+                payload.amount.currency().code().into(),
+                payload.occurred_on.to_string().into(),
+            ],
+        )
+    }
+
+    fn save_payment_authorized(
+        &mut self,
+        payload: &PaymentAuthorizedPayload,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO payment_authorizations (payment_id, order_id, amount, currency, occurred_on)
+        VALUES(?1,?2,?3,?4,?5)",
+            &[
+                payload.payment_id.as_str().into(),
+                payload.order_id.as_str().into(),
+                payload.amount.minor_units().into(),
+                payload.amount.currency().code().into(),
+                payload.occurred_on.to_string().into(),
+            ],
+        )
+    }
+
+    fn save_payment_collected(
+        &mut self,
+        payload: &PaymentCollectedPayload,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO payment_collections (payment_id, transaction_id, amount, currency, occurred_on)
+        VALUES(?1,?2,?3,?4,?5)",
+            &[
+                payload.payment_id.as_str().into(),
+                payload.transaction_id.as_str().into(),
+                payload.amount.minor_units().into(),
+                payload.amount.currency().code().into(),
+                payload.occurred_on.to_string().into(),
+            ],
+        )
+    }
+
+    fn save_product_ordered(
+        &mut self,
+        payload: &ProductOrderedPayload,
+    ) -> Result<(), StorageError> {
+        self.execute(r"
+         INSERT INTO product_orders (order_id, amount, expected_amount, currency, occurred_on, event_type, installment_type, insurance_code, original_order_id)
+         VALUES(?1,?2,?2,?3,?4,?5,?6,?7,?8)
+         ", &[
+            payload.order_id.as_str().into(),
+            payload.amount.minor_units().into(),
+            payload.amount.currency().code().into(),
+            payload.occurred_on.to_string().into(),
+            payload.event_type.to_string().into(),
+            payload.installment_type.to_string().into(),
+            payload.insurance_code.as_str().into(),
+            payload
+                .original_order_id
+                .as_deref()
+                .map_or(Value::Null, Value::from),
+         ])?;
+        for guarantee in &payload.guarantees {
+            self.execute(
+                r"
+            INSERT INTO order_guarantees (order_id, guarantee_type, price, currency)
+            VALUES(?1,?2,?3,?4)",
+                &[
+                    payload.order_id.as_str().into(),
+                    guarantee.guarantee_type.as_str().into(),
+                    guarantee.price.minor_units().into(),
+                    guarantee.price.currency().code().into(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn save_payment_refunded(
+        &mut self,
+        payload: &PaymentRefundedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            REFUND,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn save_chargeback_received(
+        &mut self,
+        payload: &ChargebackReceivedPayload,
+    ) -> Result<(), StorageError> {
+        self.save_reversal(
+            CHARGEBACK,
+            &payload.payment_id,
+            &payload.transaction_id,
+            payload.amount,
+            payload.occurred_on,
+        )
+    }
+
+    fn payment_facts(&mut self, payment_id: &str) -> Result<Facts, StorageError> {
+        let mut facts = Facts::default();
+        self.payment_rows(&mut facts, "WHERE payment_id=?1", &[payment_id.into()])?;
+        Ok(facts)
+    }
+
+    fn save_allocations(
+        &mut self,
+        payment_id: &str,
+        allocations: &[Allocation],
+    ) -> Result<(), StorageError> {
+        self.execute(
+            "DELETE FROM relations WHERE payment_id=?1",
+            &[payment_id.into()],
+        )?;
+        for allocation in allocations {
+            self.execute(
+                r"
+            INSERT INTO relations (kind, order_id, payment_id, transaction_id, amount, currency)
+            VALUES(?1,?2,?3,?4,?5,?6)",
+                &[
+                    allocation.kind.as_str().into(),
+                    allocation.order_id.as_str().into(),
+                    allocation.payment_id.as_str().into(),
+                    allocation.transaction_id.as_str().into(),
+                    allocation.amount.minor_units().into(),
+                    allocation.amount.currency().code().into(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn related(
+        &mut self,
+        from: BusinessKey,
+        id: &str,
+        to: BusinessKey,
+    ) -> Result<Vec<String>, StorageError> {
+        self.query(
+            &format!(
+                "SELECT DISTINCT {} FROM relations WHERE {}=?1",
+                to.column(),
+                from.column()
+            ),
+            &[id.into()],
+        )?
+        .iter()
+        .map(|row| text(&row[0]))
+        .collect()
+    }
+
+    fn is_stored(&mut self, key: BusinessKey, id: &str) -> Result<bool, StorageError> {
+        Ok(!self
+            .query(
+                &format!(
+                    "SELECT 1 FROM {} WHERE {}=?1 LIMIT 1",
+                    key_table(key),
+                    key.column()
+                ),
+                &[id.into()],
+            )?
+            .is_empty())
+    }
+
+    fn add_pending_match(&mut self, pending: &PendingMatch) -> Result<(), StorageError> {
+        self.execute(
+            r"
+        INSERT INTO pending_matches (awaited_column, awaited_id, key_column, key_id)
+        VALUES(?1,?2,?3,?4) ON CONFLICT DO NOTHING",
+            &[
+                pending.awaited.0.column().into(),
+                pending.awaited.1.as_str().into(),
+                pending.key.0.column().into(),
+                pending.key.1.as_str().into(),
+            ],
+        )
+    }
+
+    fn take_pending_matches(
+        &mut self,
+        awaited: BusinessKey,
+        id: &str,
+    ) -> Result<Vec<PendingMatch>, StorageError> {
+        let pending = self
+            .query(
+                r"SELECT awaited_column, awaited_id, key_column, key_id FROM pending_matches
+            WHERE awaited_column=?1 AND awaited_id=?2 ORDER BY key_column, key_id",
+                &[awaited.column().into(), id.into()],
+            )?
+            .iter()
+            .map(|row| pending_match_row(row))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.execute(
+            "DELETE FROM pending_matches WHERE awaited_column=?1 AND awaited_id=?2",
+            &[awaited.column().into(), id.into()],
+        )?;
+        Ok(pending)
+    }
+
+    fn pending_matches(&mut self) -> Result<Vec<PendingMatch>, StorageError> {
+        self.query(
+            r"SELECT awaited_column, awaited_id, key_column, key_id FROM pending_matches
+            ORDER BY awaited_column, awaited_id, key_column, key_id",
+            &[],
+        )?
+        .iter()
+        .map(|row| pending_match_row(row))
+        .collect()
+    }
+
+    fn order_lifecycle(&mut self, order_id: &str) -> Result<Vec<StoredOrder>, StorageError> {
+        self.query(
+            &format!(
+                r"SELECT {STORED_ORDER} FROM product_orders
+                WHERE order_id=?1 OR original_order_id=?1 ORDER BY order_id"
+            ),
+            &[order_id.into()],
+        )?
+        .iter()
+        .map(|row| stored_order(row))
+        .collect()
+    }
+
+    fn update_expected_amount(
+        &mut self,
+        order_id: &str,
+        expected: Money,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            "UPDATE product_orders SET expected_amount=?2 WHERE order_id=?1",
+            &[order_id.into(), expected.minor_units().into()],
+        )
+    }
+
+    fn update_collected_amount(&mut self, order_id: &str) -> Result<(), StorageError> {
+        self.execute(
+            r"UPDATE product_orders SET collected_amount = (
+            SELECT COALESCE(SUM(r.amount), 0)
+            FROM relations r
+            JOIN bank_transactions bt ON bt.transaction_id = r.transaction_id
+            WHERE r.order_id = product_orders.order_id
+            AND r.currency = product_orders.currency
+        )
+        WHERE order_id=?1",
+            &[order_id.into()],
+        )
+    }
+
+    fn update_ordered_amount(&mut self, transaction_id: &str) -> Result<(), StorageError> {
+        self.execute(
+            r"UPDATE bank_transactions SET ordered_amount = (
+            SELECT COALESCE(SUM(r.amount), 0)
+            FROM relations r
+            JOIN product_orders po ON po.order_id = r.order_id
+            WHERE r.transaction_id = bank_transactions.transaction_id
+            AND r.currency = bank_transactions.currency
+        )
+        WHERE transaction_id=?1",
+            &[transaction_id.into()],
+        )
+    }
+
+    fn order_balances(&mut self) -> Result<Vec<OrderBalance>, StorageError> {
+        self.query(
+            r"SELECT order_id, amount, collected_amount, currency, expected_amount
+            FROM product_orders WHERE original_order_id IS NULL ORDER BY order_id",
+            &[],
+        )?
+        .iter()
+        .map(|row| {
+            let currency = text(&row[3])?;
+            Ok(OrderBalance {
+                order_id: text(&row[0])?,
+                amount: stored_money("product_orders", integer(&row[1])?, &currency)?,
+                expected_amount: stored_money("product_orders", integer(&row[4])?, &currency)?,
+                collected_amount: stored_money("product_orders", integer(&row[2])?, &currency)?,
+            })
+        })
+        .collect()
+    }
+
+    fn transaction_balances(&mut self) -> Result<Vec<TransactionBalance>, StorageError> {
+        self.query(
+            r"SELECT transaction_id, amount, ordered_amount, currency
+            FROM bank_transactions ORDER BY transaction_id",
+            &[],
+        )?
+        .iter()
+        .map(|row| {
+            let currency = text(&row[3])?;
+            Ok(TransactionBalance {
+                transaction_id: text(&row[0])?,
+                amount: stored_money("bank_transactions", integer(&row[1])?, &currency)?,
+                ordered_amount: stored_money("bank_transactions", integer(&row[2])?, &currency)?,
+            })
+        })
+        .collect()
+    }
+
+    fn guarantees(&mut self) -> Result<Vec<StoredGuarantee>, StorageError> {
+        self.query(
+            r"SELECT order_id, guarantee_type, price, currency
+            FROM order_guarantees ORDER BY order_id, guarantee_type",
+            &[],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(StoredGuarantee {
+                order_id: text(&row[0])?,
+                guarantee_type: text(&row[1])?,
+                price: stored_money("order_guarantees", integer(&row[2])?, &text(&row[3])?)?,
+            })
+        })
+        .collect()
+    }
+
+    fn facts(&mut self) -> Result<Facts, StorageError> {
+        let mut facts = Facts {
+            orders: self
+                .query(
+                    &format!("SELECT {STORED_ORDER} FROM product_orders ORDER BY order_id"),
+                    &[],
+                )?
+                .iter()
+                .map(|row| stored_order(row))
+                .collect::<Result<_, _>>()?,
+            guarantees: self.guarantees()?,
+            ..Facts::default()
+        };
+        self.payment_rows(&mut facts, "", &[])?;
+        for row in self.query(
+            r"SELECT transaction_id, amount, currency, occurred_on
+            FROM bank_transactions ORDER BY transaction_id",
+            &[],
+        )? {
+            facts.transactions.push(StoredTransaction {
+                transaction_id: text(&row[0])?,
+                amount: stored_money("bank_transactions", integer(&row[1])?, &text(&row[2])?)?,
+                occurred_on: stored_timestamp("bank_transactions", &text(&row[3])?)?,
+            });
+        }
+        for row in self.query(
+            r"SELECT kind, order_id, payment_id, transaction_id, amount, currency
+            FROM relations ORDER BY order_id, payment_id, transaction_id, kind",
+            &[],
+        )? {
+            facts.allocations.push(Allocation {
+                kind: text(&row[0])?,
+                order_id: text(&row[1])?,
+                payment_id: text(&row[2])?,
+                transaction_id: text(&row[3])?,
+                amount: stored_money("relations", integer(&row[4])?, &text(&row[5])?)?,
+            });
+        }
+        Ok(facts)
+    }
+
+    fn insert_total(
+        &mut self,
+        total: Total,
+        amount: Money,
+        occurred_on: &DateTime<Utc>,
+    ) -> Result<(), StorageError> {
+        self.execute(
+            &format!(
+                r"INSERT INTO {} (amount, currency, occurred_on) VALUES(?1,?2,?3)",
+                total.table()
+            ),
+            &[
+                amount.minor_units().into(),
+                amount.currency().code().into(),
+                occurred_on.to_string().into(),
+            ],
+        )
+    }
+
+    fn clear_total(&mut self, total: Total) -> Result<(), StorageError> {
+        self.execute(&format!("DELETE FROM {}", total.table()), &[])
+    }
+}